@@ -0,0 +1,18 @@
+import { execute } from "./lib.ts";
+
+export async function GET(req: Req): Promise<Res> {
+  return { status: 200, headers: {}, body: await execute("get") };
+}
+
+export const POST = async (req: Req): Promise<Res> => ({ status: 201, headers: {}, body: null }),
+  helper = 1;
+
+async function put() {
+  return { status: 204, headers: {}, body: null };
+}
+
+export { put as PUT };
+export default GET;
+
+type Req = { url: string };
+type Res = { status: number; headers: Record<string, string>; body: string | null };
@@ -0,0 +1 @@
+export const main = import.meta.main;
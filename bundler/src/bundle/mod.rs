@@ -38,6 +38,12 @@ pub struct Options {
     pub module_type: ModuleType,
 }
 
+/// Names exported by the module at `path`, without following `export *`
+pub fn module_exports(path: &str) -> Result<Vec<String>> {
+    let source = std::fs::read_to_string(path)?;
+    transpiler::TypeScript::exports(path, &source)
+}
+
 pub fn run_bundle(entry: &str, options: &Options) -> Result<String> {
     // Create SWC globals and an LRC sourcemap.
     let globals = Globals::default();
@@ -65,7 +71,10 @@ pub fn run_bundle(entry: &str, options: &Options) -> Result<String> {
         Box::new(Hook),
     );
 
-    // Create bundle entries.
+    // Create bundle entries, a bare entry like `main.ts` is relative to cwd
+    // and resolved upfront so that `import.meta` of the entry works as well.
+    let entry = resolve_import(None, entry, None)
+        .or_else(|_| resolve_import(None, &format!("./{entry}"), None))?;
     let mut entries = HashMap::default();
     entries.insert("main".to_string(), FileName::Real(entry.into()));
 
@@ -2,6 +2,9 @@ use anyhow::{bail, Result};
 use swc_common::errors::{ColorConfig, Handler};
 use swc_common::sync::Lrc;
 use swc_common::{FileName, Globals, Mark, SourceMap, GLOBALS};
+use swc_ecma_ast::{
+    Decl, ExportDecl, ExportSpecifier, ModuleDecl, ModuleExportName, ModuleItem, NamedExport, Pat,
+};
 use swc_ecma_codegen::text_writer::JsWriter;
 use swc_ecma_codegen::Emitter;
 use swc_ecma_parser::lexer::Lexer;
@@ -76,4 +79,66 @@ impl TypeScript {
 
         Ok(String::from_utf8_lossy(&buffer).to_string())
     }
+
+    /// Names exported by a module, `export *` is not followed
+    pub fn exports(filename: &str, source: &str) -> Result<Vec<String>> {
+        let cm: Lrc<SourceMap> = Default::default();
+        let handler = Handler::with_tty_emitter(ColorConfig::Auto, true, false, Some(cm.clone()));
+        let fm = cm.new_source_file(FileName::Custom(filename.into()), source.into());
+        let lexer = Lexer::new(
+            Syntax::Typescript(TsConfig {
+                tsx: true,
+                decorators: true,
+                no_early_errors: true,
+                ..Default::default()
+            }),
+            Default::default(),
+            StringInput::from(&*fm),
+            None,
+        );
+        let module = match Parser::new_from(lexer).parse_module() {
+            Ok(module) => module,
+            Err(e) => {
+                e.into_diagnostic(&handler).emit();
+                bail!("failed to parse {filename}");
+            }
+        };
+
+        let mut names = Vec::new();
+        for item in module.body {
+            let ModuleItem::ModuleDecl(decl) = item else {
+                continue;
+            };
+            match decl {
+                ModuleDecl::ExportDecl(ExportDecl { decl, .. }) => match decl {
+                    Decl::Fn(f) => names.push(f.ident.sym.to_string()),
+                    Decl::Class(c) => names.push(c.ident.sym.to_string()),
+                    Decl::Var(v) => names.extend(v.decls.iter().filter_map(|d| match &d.name {
+                        Pat::Ident(id) => Some(id.id.sym.to_string()),
+                        _ => None,
+                    })),
+                    _ => {}
+                },
+                ModuleDecl::ExportNamed(NamedExport { specifiers, .. }) => {
+                    for specifier in specifiers {
+                        if let ExportSpecifier::Named(named) = specifier {
+                            names.push(export_name(named.exported.as_ref().unwrap_or(&named.orig)));
+                        }
+                    }
+                }
+                ModuleDecl::ExportDefaultDecl(_) | ModuleDecl::ExportDefaultExpr(_) => {
+                    names.push("default".to_string());
+                }
+                _ => {}
+            }
+        }
+        Ok(names)
+    }
+}
+
+fn export_name(name: &ModuleExportName) -> String {
+    match name {
+        ModuleExportName::Ident(ident) => ident.sym.to_string(),
+        ModuleExportName::Str(s) => s.value.to_string(),
+    }
 }
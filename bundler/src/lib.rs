@@ -2,7 +2,8 @@ mod bundle;
 
 use anyhow::Result;
 
-pub use bundle::{run_bundle, Options};
+pub use bundle::{module_exports, run_bundle, Options};
+pub use swc_bundler::ModuleType;
 
 pub type ModulePath = String;
 pub type ModuleSource = String;
@@ -23,4 +24,27 @@ mod tests {
         assert_eq!(ret, "(function(){async function execute(name){console.log(\"Executing lib\");return`Hello ${name}!`;}async function main(){console.log(\"Executing main\");console.log(await execute(\"world\"));}return{default:main};})();");
         Ok(())
     }
+
+    #[test]
+    fn bundle_es_should_work() -> Result<()> {
+        let options = Options {
+            module_type: ModuleType::Es,
+            ..Default::default()
+        };
+        let ret = run_bundle("assets/main.ts", &options)?;
+        assert!(!ret.starts_with("(function(){"));
+        assert!(ret.contains("export{main as default}"), "{ret}");
+
+        // `import.meta` of the entry is kept for the runtime to fill in
+        let ret = run_bundle("assets/meta.ts", &options)?;
+        assert!(ret.contains("main:import.meta.main"), "{ret}");
+        Ok(())
+    }
+
+    #[test]
+    fn module_exports_should_work() -> Result<()> {
+        let names = module_exports("assets/exports.ts")?;
+        assert_eq!(names, ["GET", "POST", "helper", "PUT", "default"]);
+        Ok(())
+    }
 }
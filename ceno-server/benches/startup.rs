@@ -0,0 +1,39 @@
+use ceno_server::{compile_bytecode, JsCode, JsWorker, Services};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+/// Generate an IIFE bundle with `count` handlers, roughly 200 bytes each
+fn large_bundle(count: usize) -> String {
+    let mut code = String::from("(function(){\n");
+    for i in 0..count {
+        code.push_str(&format!(
+            r#"async function handler{i}(req) {{
+    const items = [{i}, {i} * 2, {i} * 3].map((v) => ({{ id: v, name: `item-${{v}}` }}));
+    return {{ status: 200, headers: {{}}, body: JSON.stringify({{ url: req.url, items }}) }};
+}}
+"#
+        ));
+    }
+    code.push_str("return {");
+    for i in 0..count {
+        code.push_str(&format!("handler{i},"));
+    }
+    code.push_str("};\n})();");
+    code
+}
+
+fn startup(c: &mut Criterion) {
+    let source = large_bundle(5000);
+    let bytecode = compile_bytecode(&source.as_str().into()).unwrap();
+    let code = JsCode::new(source.clone()).with_bytecode(bytecode);
+
+    let mut group = c.benchmark_group("worker startup");
+    group.sample_size(20);
+    group.bench_function("source", |b| b.iter(|| JsWorker::try_new(&source).unwrap()));
+    group.bench_function("bytecode", |b| {
+        b.iter(|| JsWorker::try_load(&code, &Services::default()).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, startup);
+criterion_main!(benches);
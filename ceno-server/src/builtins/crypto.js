@@ -0,0 +1,182 @@
+// Web Crypto subset, heavy lifting is done by the native functions in `crypto.rs`
+(function (native, util) {
+  const HASHES = ["SHA-256", "SHA-384", "SHA-512"];
+  const INTEGER_ARRAYS = [
+    Int8Array, Uint8Array, Uint8ClampedArray, Int16Array, Uint16Array,
+    Int32Array, Uint32Array, BigInt64Array, BigUint64Array,
+  ];
+  // DER prefix of an Ed25519 SubjectPublicKeyInfo, followed by the 32 bytes key
+  const ED25519_SPKI_PREFIX = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+  // raw key material is kept out of reach of user code
+  const secrets = new WeakMap();
+
+  function normalizeName(algorithm) {
+    const name = typeof algorithm === "string" ? algorithm : algorithm && algorithm.name;
+    if (typeof name !== "string") {
+      throw new TypeError("Algorithm: name is missing");
+    }
+    return name.toUpperCase();
+  }
+
+  function normalizeHash(algorithm) {
+    const name = normalizeName(algorithm);
+    if (!HASHES.includes(name)) {
+      throw new DOMException(`Unrecognized hash algorithm: ${name}`, "NotSupportedError");
+    }
+    return name;
+  }
+
+  function checkUsages(usages, allowed) {
+    if (!Array.isArray(usages) || usages.some((u) => !allowed.includes(u))) {
+      throw new DOMException(`Unsupported key usages: ${usages}`, "SyntaxError");
+    }
+  }
+
+  class CryptoKey {
+    constructor() {
+      throw new TypeError("Illegal constructor");
+    }
+  }
+
+  function createKey(type, extractable, algorithm, usages, raw) {
+    const key = Object.create(CryptoKey.prototype);
+    Object.defineProperties(key, {
+      type: { value: type, enumerable: true },
+      extractable: { value: Boolean(extractable), enumerable: true },
+      algorithm: { value: Object.freeze(algorithm), enumerable: true },
+      usages: { value: Object.freeze([...usages]), enumerable: true },
+    });
+    secrets.set(key, raw);
+    return key;
+  }
+
+  function keyMaterial(key, name, usage) {
+    const raw = secrets.get(key);
+    if (raw === undefined) {
+      throw new TypeError("key is not a CryptoKey");
+    }
+    if (key.algorithm.name.toUpperCase() !== name || !key.usages.includes(usage)) {
+      throw new DOMException(`key does not support ${usage} with ${name}`, "InvalidAccessError");
+    }
+    return raw;
+  }
+
+  function importHmacKey(format, keyData, algorithm, extractable, usages) {
+    const hash = normalizeHash(algorithm.hash);
+    checkUsages(usages, ["sign", "verify"]);
+
+    let raw;
+    if (format === "raw") {
+      raw = util.toArrayBuffer(keyData);
+    } else if (format === "jwk") {
+      if (keyData.kty !== "oct" || typeof keyData.k !== "string") {
+        throw new DOMException("invalid HMAC JWK", "DataError");
+      }
+      raw = native.base64UrlDecode(keyData.k);
+    } else {
+      throw new DOMException(`Unsupported key format: ${format}`, "NotSupportedError");
+    }
+    if (raw.byteLength === 0) {
+      throw new DOMException("HMAC key must not be empty", "DataError");
+    }
+
+    const alg = { name: "HMAC", hash: { name: hash }, length: raw.byteLength * 8 };
+    return createKey("secret", extractable, alg, usages, raw);
+  }
+
+  function importEd25519Key(format, keyData, extractable, usages) {
+    checkUsages(usages, ["verify"]);
+
+    let raw;
+    if (format === "raw") {
+      raw = util.toArrayBuffer(keyData);
+    } else if (format === "spki") {
+      const der = new Uint8Array(util.toArrayBuffer(keyData));
+      if (der.length !== 44 || ED25519_SPKI_PREFIX.some((b, i) => der[i] !== b)) {
+        throw new DOMException("invalid Ed25519 SubjectPublicKeyInfo", "DataError");
+      }
+      raw = der.slice(12).buffer;
+    } else if (format === "jwk") {
+      if (keyData.d !== undefined) {
+        throw new DOMException("Ed25519 private keys are not supported", "NotSupportedError");
+      }
+      if (keyData.kty !== "OKP" || keyData.crv !== "Ed25519" || typeof keyData.x !== "string") {
+        throw new DOMException("invalid Ed25519 JWK", "DataError");
+      }
+      raw = native.base64UrlDecode(keyData.x);
+    } else {
+      throw new DOMException(`Unsupported key format: ${format}`, "NotSupportedError");
+    }
+    if (raw.byteLength !== 32) {
+      throw new DOMException("Ed25519 public key must be 32 bytes", "DataError");
+    }
+
+    return createKey("public", extractable, { name: "Ed25519" }, usages, raw);
+  }
+
+  const subtle = {
+    async digest(algorithm, data) {
+      return native.digest(normalizeHash(algorithm), util.toArrayBuffer(data));
+    },
+
+    async importKey(format, keyData, algorithm, extractable, keyUsages) {
+      switch (normalizeName(algorithm)) {
+        case "HMAC":
+          return importHmacKey(format, keyData, algorithm, extractable, keyUsages);
+        case "ED25519":
+          return importEd25519Key(format, keyData, extractable, keyUsages);
+        default:
+          throw new DOMException(`Unrecognized algorithm: ${normalizeName(algorithm)}`, "NotSupportedError");
+      }
+    },
+
+    async sign(algorithm, key, data) {
+      const name = normalizeName(algorithm);
+      if (name !== "HMAC") {
+        throw new DOMException(`Unsupported sign algorithm: ${name}`, "NotSupportedError");
+      }
+      const raw = keyMaterial(key, name, "sign");
+      return native.hmacSign(key.algorithm.hash.name, raw, util.toArrayBuffer(data));
+    },
+
+    async verify(algorithm, key, signature, data) {
+      const name = normalizeName(algorithm);
+      switch (name) {
+        case "HMAC": {
+          const raw = keyMaterial(key, name, "verify");
+          return native.hmacVerify(key.algorithm.hash.name, raw, util.toArrayBuffer(signature), util.toArrayBuffer(data));
+        }
+        case "ED25519": {
+          const raw = keyMaterial(key, name, "verify");
+          return native.ed25519Verify(raw, util.toArrayBuffer(signature), util.toArrayBuffer(data));
+        }
+        default:
+          throw new DOMException(`Unsupported verify algorithm: ${name}`, "NotSupportedError");
+      }
+    },
+  };
+
+  const crypto = {
+    subtle: Object.freeze(subtle),
+
+    getRandomValues(array) {
+      if (!INTEGER_ARRAYS.some((ty) => array instanceof ty)) {
+        throw new DOMException("Argument is not an integer-type TypedArray", "TypeMismatchError");
+      }
+      if (array.byteLength > 65536) {
+        throw new DOMException(`byteLength of ${array.byteLength} exceeds 65536`, "QuotaExceededError");
+      }
+      const random = new Uint8Array(native.randomBytes(array.byteLength));
+      new Uint8Array(array.buffer, array.byteOffset, array.byteLength).set(random);
+      return array;
+    },
+
+    randomUUID() {
+      return native.randomUUID();
+    },
+  };
+
+  globalThis.CryptoKey = CryptoKey;
+  globalThis.crypto = Object.freeze(crypto);
+})
@@ -0,0 +1,248 @@
+use base64::{
+    alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine as _,
+};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rquickjs::{ArrayBuffer, Ctx, Exception, Function, Object, Result};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use uuid::Uuid;
+
+/// `getRandomValues` refuses to fill more than 64KiB at once
+const MAX_RANDOM_BYTES: usize = 65536;
+
+/// base64url without padding as used by JWK, but accepting padded input as well
+const BASE64_URL: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+#[derive(Debug, Clone, Copy)]
+enum Hash {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+/// Install `crypto` and `CryptoKey` into the global object.
+///
+/// The native functions only deal with raw bytes, the `crypto.js` shim is
+/// responsible for argument normalization and the Web Crypto key model.
+pub(crate) fn init<'js>(ctx: &Ctx<'js>, util: &Object<'js>) -> Result<()> {
+    let native = Object::new(ctx.clone())?;
+    native.set("randomBytes", Function::new(ctx.clone(), random_bytes)?)?;
+    native.set("randomUUID", Function::new(ctx.clone(), random_uuid)?)?;
+    native.set("digest", Function::new(ctx.clone(), digest)?)?;
+    native.set("hmacSign", Function::new(ctx.clone(), hmac_sign)?)?;
+    native.set("hmacVerify", Function::new(ctx.clone(), hmac_verify)?)?;
+    native.set("ed25519Verify", Function::new(ctx.clone(), ed25519_verify)?)?;
+    native.set(
+        "base64UrlDecode",
+        Function::new(ctx.clone(), base64_url_decode)?,
+    )?;
+
+    let setup: Function = ctx.eval(include_str!("crypto.js"))?;
+    setup.call((native, util.clone()))
+}
+
+impl Hash {
+    fn parse(ctx: &Ctx<'_>, name: &str) -> Result<Self> {
+        match name {
+            "SHA-256" => Ok(Hash::Sha256),
+            "SHA-384" => Ok(Hash::Sha384),
+            "SHA-512" => Ok(Hash::Sha512),
+            v => Err(Exception::throw_type(
+                ctx,
+                &format!("Unrecognized hash algorithm: {v}"),
+            )),
+        }
+    }
+}
+
+fn bytes<'a>(ctx: &Ctx<'_>, buf: &'a ArrayBuffer<'_>) -> Result<&'a [u8]> {
+    buf.as_bytes()
+        .ok_or_else(|| Exception::throw_type(ctx, "ArrayBuffer is detached"))
+}
+
+fn random_bytes(ctx: Ctx<'_>, len: usize) -> Result<ArrayBuffer<'_>> {
+    if len > MAX_RANDOM_BYTES {
+        return Err(Exception::throw_range(
+            &ctx,
+            &format!("requested {len} random bytes, the maximum is {MAX_RANDOM_BYTES}"),
+        ));
+    }
+    let mut buf = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut buf);
+    ArrayBuffer::new(ctx, buf)
+}
+
+fn random_uuid() -> String {
+    Uuid::new_v4().to_string()
+}
+
+fn digest<'js>(ctx: Ctx<'js>, hash: String, data: ArrayBuffer<'js>) -> Result<ArrayBuffer<'js>> {
+    let data = bytes(&ctx, &data)?;
+    let ret = match Hash::parse(&ctx, &hash)? {
+        Hash::Sha256 => Sha256::digest(data).to_vec(),
+        Hash::Sha384 => Sha384::digest(data).to_vec(),
+        Hash::Sha512 => Sha512::digest(data).to_vec(),
+    };
+    ArrayBuffer::new(ctx, ret)
+}
+
+fn new_mac<M: Mac + hmac::digest::KeyInit>(key: &[u8], data: &[u8]) -> M {
+    let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(data);
+    mac
+}
+
+fn hmac_sign<'js>(
+    ctx: Ctx<'js>,
+    hash: String,
+    key: ArrayBuffer<'js>,
+    data: ArrayBuffer<'js>,
+) -> Result<ArrayBuffer<'js>> {
+    let (key, data) = (bytes(&ctx, &key)?, bytes(&ctx, &data)?);
+    let ret = match Hash::parse(&ctx, &hash)? {
+        Hash::Sha256 => new_mac::<Hmac<Sha256>>(key, data)
+            .finalize()
+            .into_bytes()
+            .to_vec(),
+        Hash::Sha384 => new_mac::<Hmac<Sha384>>(key, data)
+            .finalize()
+            .into_bytes()
+            .to_vec(),
+        Hash::Sha512 => new_mac::<Hmac<Sha512>>(key, data)
+            .finalize()
+            .into_bytes()
+            .to_vec(),
+    };
+    ArrayBuffer::new(ctx, ret)
+}
+
+fn hmac_verify<'js>(
+    ctx: Ctx<'js>,
+    hash: String,
+    key: ArrayBuffer<'js>,
+    signature: ArrayBuffer<'js>,
+    data: ArrayBuffer<'js>,
+) -> Result<bool> {
+    let (key, data) = (bytes(&ctx, &key)?, bytes(&ctx, &data)?);
+    let signature = bytes(&ctx, &signature)?;
+    // `verify_slice` compares in constant time
+    let ret = match Hash::parse(&ctx, &hash)? {
+        Hash::Sha256 => new_mac::<Hmac<Sha256>>(key, data).verify_slice(signature),
+        Hash::Sha384 => new_mac::<Hmac<Sha384>>(key, data).verify_slice(signature),
+        Hash::Sha512 => new_mac::<Hmac<Sha512>>(key, data).verify_slice(signature),
+    };
+    Ok(ret.is_ok())
+}
+
+fn ed25519_verify<'js>(
+    ctx: Ctx<'js>,
+    key: ArrayBuffer<'js>,
+    signature: ArrayBuffer<'js>,
+    data: ArrayBuffer<'js>,
+) -> Result<bool> {
+    let (key, data) = (bytes(&ctx, &key)?, bytes(&ctx, &data)?);
+    let signature = bytes(&ctx, &signature)?;
+
+    let Ok(key) = <&[u8; 32]>::try_from(key) else {
+        return Ok(false);
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return Ok(false);
+    };
+    let Ok(key) = VerifyingKey::from_bytes(key) else {
+        return Ok(false);
+    };
+    Ok(key.verify(data, &signature).is_ok())
+}
+
+fn base64_url_decode(ctx: Ctx<'_>, data: String) -> Result<ArrayBuffer<'_>> {
+    let ret = BASE64_URL
+        .decode(data)
+        .map_err(|e| Exception::throw_type(&ctx, &format!("invalid base64url: {e}")))?;
+    ArrayBuffer::new(ctx, ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builtins::eval;
+
+    const HEX: &str = r#"
+        const hex = (buf) => Array.from(new Uint8Array(buf))
+            .map((b) => b.toString(16).padStart(2, "0"))
+            .join("");
+        const bytes = (s) => new Uint8Array(Array.from(s, (c) => c.charCodeAt(0)));
+    "#;
+
+    #[test]
+    fn digest_should_work() {
+        let code = format!(
+            r#"(async () => {{
+                {HEX}
+                return hex(await crypto.subtle.digest("SHA-256", bytes("abc")));
+            }})()"#
+        );
+        let ret: String = eval(&code);
+        assert_eq!(
+            ret,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn hmac_sign_and_verify_should_work() {
+        // RFC 4231 test case 2
+        let code = format!(
+            r#"(async () => {{
+                {HEX}
+                const key = await crypto.subtle.importKey(
+                    "raw", bytes("Jefe"), {{ name: "HMAC", hash: "SHA-256" }}, false, ["sign", "verify"]);
+                const data = bytes("what do ya want for nothing?");
+                const sig = await crypto.subtle.sign("HMAC", key, data);
+                const ok = await crypto.subtle.verify("HMAC", key, sig, data);
+                const bad = await crypto.subtle.verify("HMAC", key, sig, bytes("tampered"));
+                return [hex(sig), ok, bad].join(",");
+            }})()"#
+        );
+        let ret: String = eval(&code);
+        assert_eq!(
+            ret,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843,true,false"
+        );
+    }
+
+    #[test]
+    fn ed25519_verify_should_work() {
+        // RFC 8032 test 1, the signature of an empty message
+        let code = r#"(async () => {
+            const unhex = (s) => new Uint8Array(s.match(/../g).map((b) => parseInt(b, 16)));
+            const key = await crypto.subtle.importKey(
+                "raw",
+                unhex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"),
+                "Ed25519", false, ["verify"]);
+            const sig = unhex("e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b");
+            const ok = await crypto.subtle.verify("Ed25519", key, sig, new Uint8Array());
+            const bad = await crypto.subtle.verify("Ed25519", key, sig, new Uint8Array([1]));
+            return ok && !bad;
+        })()"#;
+        assert!(eval::<bool>(code));
+    }
+
+    #[test]
+    fn random_should_work() {
+        let code = r#"(async () => {
+            const uuid = crypto.randomUUID();
+            const buf = crypto.getRandomValues(new Uint32Array(16));
+            let quota = false;
+            try { crypto.getRandomValues(new Uint8Array(65537)); } catch (e) { quota = e.name === "QuotaExceededError"; }
+            return /^[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}$/.test(uuid)
+                && buf.some((v) => v !== 0) && quota;
+        })()"#;
+        assert!(eval::<bool>(code));
+    }
+}
@@ -0,0 +1,99 @@
+// Encoding standard and base64 helpers, see `encoding.rs`
+(function (native, util) {
+  const decoders = new WeakMap();
+
+  function utf8Length(codePoint) {
+    if (codePoint < 0x80) return 1;
+    if (codePoint < 0x800) return 2;
+    if (codePoint < 0x10000) return 3;
+    return 4;
+  }
+
+  class TextEncoder {
+    get encoding() {
+      return "utf-8";
+    }
+
+    encode(input = "") {
+      return new Uint8Array(native.encode(util.toUSVString(input)));
+    }
+
+    encodeInto(source, destination) {
+      if (!(destination instanceof Uint8Array)) {
+        throw new TypeError("destination is not a Uint8Array");
+      }
+      // replacing lone surrogates keeps the UTF-16 length, so `read` still
+      // counts code units of `source`
+      const input = util.toUSVString(source);
+      let read = 0;
+      let written = 0;
+      for (const ch of input) {
+        const len = utf8Length(ch.codePointAt(0));
+        if (written + len > destination.length) {
+          break;
+        }
+        read += ch.length;
+        written += len;
+      }
+      destination.set(new Uint8Array(native.encode(input.slice(0, read))));
+      return { read, written };
+    }
+  }
+
+  function decoder(self) {
+    const ret = decoders.get(self);
+    if (ret === undefined) {
+      throw new TypeError("Illegal invocation");
+    }
+    return ret;
+  }
+
+  class TextDecoder {
+    constructor(label = "utf-8", options = {}) {
+      const state = {
+        inner: new native.Decoder(String(label), Boolean(options.fatal), Boolean(options.ignoreBOM)),
+        fatal: Boolean(options.fatal),
+        ignoreBOM: Boolean(options.ignoreBOM),
+      };
+      decoders.set(this, state);
+    }
+
+    get encoding() {
+      return decoder(this).inner.encoding;
+    }
+
+    get fatal() {
+      return decoder(this).fatal;
+    }
+
+    get ignoreBOM() {
+      return decoder(this).ignoreBOM;
+    }
+
+    decode(input, options = {}) {
+      const bytes = input === undefined ? new ArrayBuffer(0) : util.toArrayBuffer(input);
+      return decoder(this).inner.decode(bytes, Boolean(options.stream));
+    }
+  }
+
+  function atob(data) {
+    const ret = native.atob(util.toUSVString(data));
+    if (ret === undefined || ret === null) {
+      throw new DOMException("The string to be decoded is not correctly encoded", "InvalidCharacterError");
+    }
+    return ret;
+  }
+
+  function btoa(data) {
+    const ret = native.btoa(util.toUSVString(data));
+    if (ret === undefined || ret === null) {
+      throw new DOMException("The string to be encoded contains characters outside of the Latin1 range", "InvalidCharacterError");
+    }
+    return ret;
+  }
+
+  globalThis.TextEncoder = TextEncoder;
+  globalThis.TextDecoder = TextDecoder;
+  globalThis.atob = atob;
+  globalThis.btoa = btoa;
+})
@@ -0,0 +1,278 @@
+use base64::{
+    alphabet,
+    engine::{general_purpose::STANDARD, DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine as _,
+};
+use encoding_rs::{DecoderResult, Encoding, REPLACEMENT};
+use rquickjs::{class::Trace, ArrayBuffer, Class, Ctx, Exception, Function, Object, Result};
+
+/// "forgiving-base64 decode" from the HTML spec, padding is stripped before decoding
+const FORGIVING_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new()
+        .with_decode_padding_mode(DecodePaddingMode::RequireNone)
+        .with_decode_allow_trailing_bits(true),
+);
+
+/// Streaming decoder backing a `TextDecoder` instance
+#[derive(Trace)]
+#[rquickjs::class]
+pub(crate) struct Decoder {
+    #[qjs(skip_trace)]
+    encoding: &'static Encoding,
+    /// `None` until the first chunk of a stream is decoded
+    #[qjs(skip_trace)]
+    inner: Option<encoding_rs::Decoder>,
+    fatal: bool,
+    ignore_bom: bool,
+}
+
+/// Install `TextEncoder`, `TextDecoder`, `atob` and `btoa` into the global object.
+pub(crate) fn init<'js>(ctx: &Ctx<'js>, util: &Object<'js>) -> Result<()> {
+    let native = Object::new(ctx.clone())?;
+    native.set("encode", Function::new(ctx.clone(), encode)?)?;
+    native.set("atob", Function::new(ctx.clone(), atob)?)?;
+    native.set("btoa", Function::new(ctx.clone(), btoa)?)?;
+    Class::<Decoder>::define(&native)?;
+
+    let setup: Function = ctx.eval(include_str!("encoding.js"))?;
+    setup.call((native, util.clone()))
+}
+
+#[rquickjs::methods]
+impl Decoder {
+    #[qjs(constructor)]
+    pub fn new(ctx: Ctx<'_>, label: String, fatal: bool, ignore_bom: bool) -> Result<Self> {
+        match Encoding::for_label(label.as_bytes()) {
+            Some(encoding) if encoding != REPLACEMENT => Ok(Self {
+                encoding,
+                inner: None,
+                fatal,
+                ignore_bom,
+            }),
+            _ => Err(Exception::throw_range(
+                &ctx,
+                &format!("The encoding label provided ('{label}') is invalid"),
+            )),
+        }
+    }
+
+    #[qjs(get)]
+    pub fn encoding(&self) -> String {
+        self.encoding.name().to_ascii_lowercase()
+    }
+
+    pub fn decode<'js>(
+        &mut self,
+        ctx: Ctx<'js>,
+        input: ArrayBuffer<'js>,
+        stream: bool,
+    ) -> Result<String> {
+        let input = input
+            .as_bytes()
+            .ok_or_else(|| Exception::throw_type(&ctx, "ArrayBuffer is detached"))?;
+        let (encoding, ignore_bom) = (self.encoding, self.ignore_bom);
+        let decoder = self.inner.get_or_insert_with(|| {
+            if ignore_bom {
+                encoding.new_decoder_without_bom_handling()
+            } else {
+                encoding.new_decoder_with_bom_removal()
+            }
+        });
+
+        let last = !stream;
+        let ret = if self.fatal {
+            let len = decoder
+                .max_utf8_buffer_length_without_replacement(input.len())
+                .unwrap_or(usize::MAX);
+            let mut output = String::with_capacity(len);
+            match decoder.decode_to_string_without_replacement(input, &mut output, last) {
+                (DecoderResult::InputEmpty, _) => Ok(output),
+                _ => Err(Exception::throw_type(
+                    &ctx,
+                    &format!(
+                        "The encoded data was not valid for encoding {}",
+                        encoding.name()
+                    ),
+                )),
+            }
+        } else {
+            let len = decoder
+                .max_utf8_buffer_length(input.len())
+                .unwrap_or(usize::MAX);
+            let mut output = String::with_capacity(len);
+            let _ = decoder.decode_to_string(input, &mut output, last);
+            Ok(output)
+        };
+
+        // a failed or finished stream starts over on the next call
+        if last || ret.is_err() {
+            self.inner = None;
+        }
+        ret
+    }
+}
+
+fn encode(ctx: Ctx<'_>, input: String) -> Result<ArrayBuffer<'_>> {
+    ArrayBuffer::new(ctx, input.into_bytes())
+}
+
+/// Decode base64 into a binary string, `None` means the input is invalid
+fn atob(data: String) -> Option<String> {
+    let mut data: Vec<u8> = data
+        .into_bytes()
+        .into_iter()
+        .filter(|b| !matches!(b, b'\t' | b'\n' | b'\x0c' | b'\r' | b' '))
+        .collect();
+    if data.len().is_multiple_of(4) {
+        for _ in 0..2 {
+            if data.last() == Some(&b'=') {
+                data.pop();
+            }
+        }
+    }
+    if data.len() % 4 == 1 {
+        return None;
+    }
+
+    let bytes = FORGIVING_BASE64.decode(data).ok()?;
+    Some(bytes.into_iter().map(char::from).collect())
+}
+
+/// Encode a binary string as base64, `None` means it has a code point above U+00FF
+fn btoa(data: String) -> Option<String> {
+    let bytes = data
+        .chars()
+        .map(|c| u8::try_from(c).ok())
+        .collect::<Option<Vec<_>>>()?;
+    Some(STANDARD.encode(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builtins::eval;
+
+    /// Run WPT-style `[input, expected]` cases through `f`, returning the failed ones
+    fn check_cases(cases: &str, f: &str) -> String {
+        eval(&format!(
+            r#"(async () => {{
+                const f = {f};
+                const failures = [];
+                for (const [input, expected] of {cases}) {{
+                    let actual;
+                    try {{ actual = f(input); }} catch (e) {{ actual = e.name; }}
+                    if (JSON.stringify(actual) !== JSON.stringify(expected)) {{
+                        failures.push(`${{JSON.stringify(input)}}: ${{JSON.stringify(actual)}}`);
+                    }}
+                }}
+                return failures.join("\n");
+            }})()"#
+        ))
+    }
+
+    #[test]
+    fn text_encoder_should_replace_lone_surrogates() {
+        // encoding/textencoder-utf16-surrogates.any.js
+        let cases = r#"[
+            ["𝄞", [0xF0, 0x9D, 0x84, 0x9E]],
+            ["\uD834", [0xEF, 0xBF, 0xBD]],
+            ["\uDD1E", [0xEF, 0xBF, 0xBD]],
+            ["\uFFFD", [0xEF, 0xBF, 0xBD]],
+            ["\uDD1E\uD834", [0xEF, 0xBF, 0xBD, 0xEF, 0xBF, 0xBD]],
+            ["", []],
+        ]"#;
+        let f = "(s) => Array.from(new TextEncoder().encode(s))";
+        assert_eq!(check_cases(cases, f), "");
+    }
+
+    #[test]
+    fn text_encoder_encode_into_should_work() {
+        // encoding/encodeInto.any.js
+        let cases = r#"[
+            [["Hi", 0], [0, 0]],
+            [["A", 10], [1, 1]],
+            [["\u{1D306}", 3], [0, 0]],
+            [["\u{1D306}A", 4], [2, 4]],
+            [["\uD834A\uDF06A¥Hi", 10], [5, 10]],
+            [["A\uDF06", 4], [2, 4]],
+            [["¥¥", 4], [2, 4]],
+        ]"#;
+        let f = r#"([s, len]) => {
+            const { read, written } = new TextEncoder().encodeInto(s, new Uint8Array(len));
+            return [read, written];
+        }"#;
+        assert_eq!(check_cases(cases, f), "");
+    }
+
+    #[test]
+    fn text_decoder_should_work() {
+        // encoding/api-basics.any.js, textdecoder-fatal.any.js, textdecoder-labels.any.js
+        let cases = r#"[
+            [["utf-8", {}, [0xEF, 0xBB, 0xBF, 0x41]], ["utf-8", "A"]],
+            [["utf-8", { ignoreBOM: true }, [0xEF, 0xBB, 0xBF, 0x41]], ["utf-8", "\uFEFFA"]],
+            [["utf8", {}, [0xE2, 0x82, 0xAC]], ["utf-8", "€"]],
+            [[" unicode-1-1-utf-8\n", {}, [0x41]], ["utf-8", "A"]],
+            [["latin1", {}, [0x80, 0xE9]], ["windows-1252", "€é"]],
+            [["utf-16le", {}, [0x41, 0x00, 0x3D, 0xD8, 0x00, 0xDE]], ["utf-16le", "A😀"]],
+            [["utf-8", {}, [0xFF, 0x41]], ["utf-8", "\uFFFDA"]],
+            [["utf-8", { fatal: true }, [0xFF, 0x41]], "TypeError"],
+            [["utf-8", { fatal: true }, [0xE2, 0x82]], "TypeError"],
+            [["iso-2022-kr", {}, []], "RangeError"],
+            [["unknown", {}, []], "RangeError"],
+        ]"#;
+        let f = r#"([label, options, bytes]) => {
+            const decoder = new TextDecoder(label, options);
+            return [decoder.encoding, decoder.decode(new Uint8Array(bytes))];
+        }"#;
+        assert_eq!(check_cases(cases, f), "");
+    }
+
+    #[test]
+    fn text_decoder_stream_should_work() {
+        let ret: String = eval(
+            r#"(async () => {
+                const decoder = new TextDecoder();
+                const chunks = [[0xE2], [0x82], [0xAC, 0xF0, 0x9F], [0x98, 0x80]];
+                let out = chunks.map((c) => decoder.decode(new Uint8Array(c), { stream: true })).join("");
+                out += decoder.decode();
+                // the decoder is reusable after the stream is flushed
+                return out + decoder.decode(new Uint8Array([0xEF, 0xBB, 0xBF, 0x21]));
+            })()"#,
+        );
+        assert_eq!(ret, "€😀!");
+    }
+
+    #[test]
+    fn atob_btoa_should_work() {
+        // html/webappapis/atob/base64.any.js
+        let cases = r#"[
+            ["", ""],
+            ["abcd", "i·\u001d"],
+            [" abcd===", "InvalidCharacterError"],
+            ["abcd=== ", "InvalidCharacterError"],
+            ["ab==", "i"],
+            ["ab=", "InvalidCharacterError"],
+            ["ab", "i"],
+            ["abc", "i·"],
+            ["a", "InvalidCharacterError"],
+            ["ab\t\n\f\r cd", "i·\u001d"],
+            ["ab cd", "InvalidCharacterError"],
+            ["ab=c", "InvalidCharacterError"],
+            ["YR", "a"],
+            ["//4=", "ÿþ"],
+            [null, "\u009eée"],
+            [undefined, "InvalidCharacterError"],
+        ]"#;
+        assert_eq!(check_cases(cases, "atob"), "");
+
+        let cases = r#"[
+            ["", ""],
+            ["ÿþ", "//4="],
+            ["abc", "YWJj"],
+            ["Ā", "InvalidCharacterError"],
+            ["😀", "InvalidCharacterError"],
+            [null, "bnVsbA=="],
+        ]"#;
+        assert_eq!(check_cases(cases, "btoa"), "");
+    }
+}
@@ -0,0 +1,36 @@
+mod crypto;
+mod encoding;
+mod url;
+
+use rquickjs::{Ctx, Function, Object};
+
+/// Install the web-compatible globals into a context.
+///
+/// Must be called before the user's bundle is evaluated so that module level
+/// code is able to use them.
+pub(crate) fn init(ctx: &Ctx<'_>) -> rquickjs::Result<()> {
+    let setup: Function = ctx.eval(include_str!("util.js"))?;
+    let util: Object = setup.call(())?;
+
+    crypto::init(ctx, &util)?;
+    encoding::init(ctx, &util)?;
+    url::init(ctx, &util)?;
+    Ok(())
+}
+
+/// Evaluate `code` with the builtins installed and wait for the promise it returns
+#[cfg(test)]
+pub(crate) fn eval<T>(code: &str) -> T
+where
+    T: for<'js> rquickjs::FromJs<'js>,
+{
+    use rquickjs::{CatchResultExt, Context, Promise, Runtime};
+
+    let rt = Runtime::new().unwrap();
+    let ctx = Context::full(&rt).unwrap();
+    ctx.with(|ctx| {
+        init(&ctx).unwrap();
+        let promise: Promise = ctx.eval(code).catch(&ctx).unwrap();
+        promise.finish().catch(&ctx).unwrap()
+    })
+}
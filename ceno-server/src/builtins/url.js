@@ -0,0 +1,222 @@
+// WHATWG URL and URLSearchParams, see `url.rs`
+(function (native, util) {
+  const COMPONENTS = ["protocol", "username", "password", "host", "hostname", "port", "pathname", "search", "hash"];
+
+  // URL -> { parts, params }, where `parts` holds every getter value
+  const urls = new WeakMap();
+  // URLSearchParams -> { list, url }, `url` is set for `URL.prototype.searchParams`
+  const queries = new WeakMap();
+
+  function internal(map, self) {
+    const ret = map.get(self);
+    if (ret === undefined) {
+      throw new TypeError("Illegal invocation");
+    }
+    return ret;
+  }
+
+  // propagate a change of the search params to the associated URL
+  function updateUrl(params) {
+    const { list, url } = queries.get(params);
+    if (url !== null) {
+      const state = urls.get(url);
+      state.parts = native.set(state.parts.href, "search", native.serializeQuery(list));
+    }
+  }
+
+  // propagate a change of the URL's query to its search params
+  function updateParams(state) {
+    if (state.params !== null) {
+      queries.get(state.params).list = native.parseQuery(state.parts.search.slice(1));
+    }
+  }
+
+  class URLSearchParams {
+    constructor(init = "") {
+      let list;
+      if (init !== null && typeof init === "object") {
+        if (typeof init[Symbol.iterator] === "function") {
+          list = Array.from(init, (pair) => {
+            const entry = Array.from(pair);
+            if (entry.length !== 2) {
+              throw new TypeError("Each query pair must be an iterable [name, value] tuple");
+            }
+            return [util.toUSVString(entry[0]), util.toUSVString(entry[1])];
+          });
+        } else {
+          list = Object.keys(init).map((name) => [util.toUSVString(name), util.toUSVString(init[name])]);
+        }
+      } else {
+        const query = util.toUSVString(init);
+        list = native.parseQuery(query.startsWith("?") ? query.slice(1) : query);
+      }
+      queries.set(this, { list, url: null });
+    }
+
+    get size() {
+      return internal(queries, this).list.length;
+    }
+
+    append(name, value) {
+      internal(queries, this).list.push([util.toUSVString(name), util.toUSVString(value)]);
+      updateUrl(this);
+    }
+
+    delete(name, value) {
+      const state = internal(queries, this);
+      name = util.toUSVString(name);
+      if (value === undefined) {
+        state.list = state.list.filter(([k]) => k !== name);
+      } else {
+        value = util.toUSVString(value);
+        state.list = state.list.filter(([k, v]) => k !== name || v !== value);
+      }
+      updateUrl(this);
+    }
+
+    get(name) {
+      name = util.toUSVString(name);
+      const entry = internal(queries, this).list.find(([k]) => k === name);
+      return entry === undefined ? null : entry[1];
+    }
+
+    getAll(name) {
+      name = util.toUSVString(name);
+      return internal(queries, this).list.filter(([k]) => k === name).map(([, v]) => v);
+    }
+
+    has(name, value) {
+      name = util.toUSVString(name);
+      const list = internal(queries, this).list;
+      if (value === undefined) {
+        return list.some(([k]) => k === name);
+      }
+      value = util.toUSVString(value);
+      return list.some(([k, v]) => k === name && v === value);
+    }
+
+    set(name, value) {
+      const state = internal(queries, this);
+      name = util.toUSVString(name);
+      value = util.toUSVString(value);
+      const index = state.list.findIndex(([k]) => k === name);
+      if (index === -1) {
+        state.list.push([name, value]);
+      } else {
+        state.list[index][1] = value;
+        state.list = state.list.filter(([k], i) => k !== name || i <= index);
+      }
+      updateUrl(this);
+    }
+
+    sort() {
+      // stable sort by code units as the spec requires
+      internal(queries, this).list.sort(([a], [b]) => (a < b ? -1 : a > b ? 1 : 0));
+      updateUrl(this);
+    }
+
+    forEach(callback, thisArg) {
+      for (const [name, value] of this) {
+        callback.call(thisArg, value, name, this);
+      }
+    }
+
+    *entries() {
+      const state = internal(queries, this);
+      // iterate the live list, mutations during iteration are visible
+      for (let i = 0; i < state.list.length; i++) {
+        yield [state.list[i][0], state.list[i][1]];
+      }
+    }
+
+    *keys() {
+      for (const [name] of this.entries()) {
+        yield name;
+      }
+    }
+
+    *values() {
+      for (const [, value] of this.entries()) {
+        yield value;
+      }
+    }
+
+    [Symbol.iterator]() {
+      return this.entries();
+    }
+
+    toString() {
+      return native.serializeQuery(internal(queries, this).list);
+    }
+  }
+
+  class URL {
+    constructor(url, base) {
+      const parts = native.parse(util.toUSVString(url), base === undefined ? undefined : util.toUSVString(base));
+      if (parts === null || parts === undefined) {
+        throw new TypeError(`Invalid URL: ${url}`);
+      }
+      urls.set(this, { parts, params: null });
+    }
+
+    static canParse(url, base) {
+      const parts = native.parse(util.toUSVString(url), base === undefined ? undefined : util.toUSVString(base));
+      return parts !== null && parts !== undefined;
+    }
+
+    get href() {
+      return internal(urls, this).parts.href;
+    }
+
+    set href(value) {
+      const state = internal(urls, this);
+      const parts = native.parse(util.toUSVString(value), undefined);
+      if (parts === null || parts === undefined) {
+        throw new TypeError(`Invalid URL: ${value}`);
+      }
+      state.parts = parts;
+      updateParams(state);
+    }
+
+    get origin() {
+      return internal(urls, this).parts.origin;
+    }
+
+    get searchParams() {
+      const state = internal(urls, this);
+      if (state.params === null) {
+        state.params = new URLSearchParams(state.parts.search);
+        queries.get(state.params).url = this;
+      }
+      return state.params;
+    }
+
+    toString() {
+      return this.href;
+    }
+
+    toJSON() {
+      return this.href;
+    }
+  }
+
+  for (const name of COMPONENTS) {
+    Object.defineProperty(URL.prototype, name, {
+      get() {
+        return internal(urls, this).parts[name];
+      },
+      set(value) {
+        const state = internal(urls, this);
+        state.parts = native.set(state.parts.href, name, util.toUSVString(value));
+        if (name === "search") {
+          updateParams(state);
+        }
+      },
+      enumerable: true,
+      configurable: true,
+    });
+  }
+
+  globalThis.URL = URL;
+  globalThis.URLSearchParams = URLSearchParams;
+})
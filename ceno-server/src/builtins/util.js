@@ -0,0 +1,31 @@
+// Helpers shared by the builtins, evaluated before any of them
+(function () {
+  const LONE_SURROGATE = /[\uD800-\uDBFF](?![\uDC00-\uDFFF])|(?<![\uD800-\uDBFF])[\uDC00-\uDFFF]/g;
+
+  if (typeof globalThis.DOMException === "undefined") {
+    globalThis.DOMException = class DOMException extends Error {
+      constructor(message = "", name = "Error") {
+        super(message);
+        this.name = name;
+      }
+    };
+  }
+
+  return {
+    // WebIDL USVString conversion, native functions reject lone surrogates
+    toUSVString(value) {
+      return String(value).replace(LONE_SURROGATE, "\uFFFD");
+    },
+
+    // copy a BufferSource into a fresh ArrayBuffer
+    toArrayBuffer(data) {
+      if (data instanceof ArrayBuffer) {
+        return data.slice(0);
+      }
+      if (ArrayBuffer.isView(data)) {
+        return data.buffer.slice(data.byteOffset, data.byteOffset + data.byteLength);
+      }
+      throw new TypeError("Argument is not an ArrayBuffer or ArrayBufferView");
+    },
+  };
+})
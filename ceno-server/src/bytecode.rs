@@ -0,0 +1,170 @@
+use crate::engine::{eval_module, JsCode, JsFormat, MODULE_NAME};
+use anyhow::{anyhow, bail, Result};
+use rquickjs::{qjs, CatchResultExt, Context, Ctx, Error, FromJs, Module, Object, Runtime, Value};
+use std::{ffi::CString, mem::MaybeUninit, slice};
+
+/// QuickJS bytecode can only be read by the build that wrote it, so every
+/// blob starts with a header naming the ceno version which produced it.
+const MAGIC: &str = concat!("ceno-bytecode/", env!("CARGO_PKG_VERSION"), "\n");
+
+const SCRIPT_NAME: &str = "main.js";
+
+/// Header of a blob, which also records whether it holds a script or a module
+fn header(format: JsFormat) -> String {
+    let kind = match format {
+        JsFormat::Iife => "script",
+        JsFormat::Es => "module",
+    };
+    format!("{MAGIC}{kind}\n")
+}
+
+/// Compile bundled code into QuickJS bytecode
+pub fn compile_bytecode(code: &JsCode) -> Result<Vec<u8>> {
+    let rt = Runtime::new()?;
+    let ctx = Context::full(&rt)?;
+
+    ctx.with(|ctx| {
+        let bytecode = match code.format {
+            JsFormat::Iife => compile(&ctx, &code.source),
+            JsFormat::Es => Module::declare(ctx.clone(), MODULE_NAME, code.source.as_str())
+                .and_then(|module| module.write(false)),
+        }
+        .catch(&ctx)
+        .map_err(|e| anyhow!("compile bytecode failed: {e}"))?;
+
+        let header = header(code.format);
+        let mut ret = Vec::with_capacity(header.len() + bytecode.len());
+        ret.extend_from_slice(header.as_bytes());
+        ret.extend_from_slice(&bytecode);
+        Ok(ret)
+    })
+}
+
+fn compile(ctx: &Ctx<'_>, source: &str) -> rquickjs::Result<Vec<u8>> {
+    let len = source.len();
+    // `JS_Eval` requires the input to be nul terminated
+    let source = CString::new(source)?;
+    let name = CString::new(SCRIPT_NAME)?;
+    let flags = (qjs::JS_EVAL_TYPE_GLOBAL | qjs::JS_EVAL_FLAG_COMPILE_ONLY) as i32;
+    let raw = ctx.as_raw().as_ptr();
+
+    unsafe {
+        let func = qjs::JS_Eval(raw, source.as_ptr(), len as _, name.as_ptr(), flags);
+        if qjs::JS_IsException(func) {
+            return Err(Error::Exception);
+        }
+        // freed on drop
+        let func = Value::from_raw(ctx.clone(), func);
+
+        let mut size = MaybeUninit::uninit();
+        let flags = qjs::JS_WRITE_OBJ_BYTECODE as i32;
+        let buf = qjs::JS_WriteObject(raw, size.as_mut_ptr(), func.as_raw(), flags);
+        if buf.is_null() {
+            return Err(Error::Exception);
+        }
+        let ret = slice::from_raw_parts(buf, size.assume_init() as _).to_vec();
+        qjs::js_free(raw, buf as _);
+        Ok(ret)
+    }
+}
+
+/// Run bytecode produced by [`compile_bytecode`] and return the handlers, which
+/// are the completion value of a script or the namespace of a module.
+///
+/// # Safety
+/// QuickJS doesn't validate bytecode while reading it, `bytecode` must come
+/// from [`compile_bytecode`] of the same ceno build. The header is checked,
+/// but can't protect against corrupted content.
+pub(crate) unsafe fn eval_bytecode<'js>(
+    ctx: &Ctx<'js>,
+    bytecode: &[u8],
+    format: JsFormat,
+) -> Result<Object<'js>> {
+    let Some(bytecode) = bytecode.strip_prefix(header(format).as_bytes()) else {
+        bail!(
+            "bytecode was not produced by ceno v{} for {:?}",
+            env!("CARGO_PKG_VERSION"),
+            format
+        );
+    };
+    match format {
+        JsFormat::Iife => unsafe { eval_script(ctx, bytecode) },
+        JsFormat::Es => {
+            let module = unsafe { Module::load(ctx.clone(), bytecode) }
+                .catch(ctx)
+                .map_err(|e| anyhow!("load bytecode failed: {e}"))?;
+            eval_module(ctx, module)
+        }
+    }
+}
+
+unsafe fn eval_script<'js, V: FromJs<'js>>(ctx: &Ctx<'js>, bytecode: &[u8]) -> Result<V> {
+    let raw = ctx.as_raw().as_ptr();
+    let flags = qjs::JS_READ_OBJ_BYTECODE as i32;
+
+    let ret = unsafe {
+        let func = qjs::JS_ReadObject(raw, bytecode.as_ptr(), bytecode.len() as _, flags);
+        if qjs::JS_IsException(func) {
+            Err(Error::Exception)
+        } else {
+            // takes the ownership of `func`
+            let ret = qjs::JS_EvalFunction(raw, func);
+            if qjs::JS_IsException(ret) {
+                Err(Error::Exception)
+            } else {
+                V::from_js(ctx, Value::from_raw(ctx.clone(), ret))
+            }
+        }
+    };
+
+    ret.catch(ctx)
+        .map_err(|e| anyhow!("eval bytecode failed: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytecode_should_round_trip() -> Result<()> {
+        let rt = Runtime::new()?;
+        let ctx = Context::full(&rt)?;
+        let cases = [
+            JsCode::new("(function(){ return { answer: 40 + 2 }; })();"),
+            JsCode::new("export const answer = await Promise.resolve(40 + 2);")
+                .with_format(JsFormat::Es),
+        ];
+        for code in cases {
+            let bytecode = compile_bytecode(&code)?;
+            assert!(bytecode.starts_with(MAGIC.as_bytes()));
+
+            let answer = ctx.with(|ctx| {
+                let obj = unsafe { eval_bytecode(&ctx, &bytecode, code.format)? };
+                Ok::<i32, anyhow::Error>(obj.get("answer")?)
+            })?;
+            assert_eq!(answer, 42);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bytecode_should_reject_foreign_header() {
+        let rt = Runtime::new().unwrap();
+        let ctx = Context::full(&rt).unwrap();
+        ctx.with(|ctx| {
+            let ret = unsafe { eval_bytecode(&ctx, b"\x02garbage", JsFormat::Iife) };
+            assert!(ret.is_err());
+            // a script must not be loaded as a module
+            let script = compile_bytecode(&"({})".into()).unwrap();
+            let ret = unsafe { eval_bytecode(&ctx, &script, JsFormat::Es) };
+            assert!(ret.is_err());
+        });
+    }
+
+    #[test]
+    fn compile_bytecode_should_report_syntax_error() {
+        assert!(compile_bytecode(&"(function(){".into()).is_err());
+        let code = JsCode::new("export {").with_format(JsFormat::Es);
+        assert!(compile_bytecode(&code).is_err());
+    }
+}
@@ -0,0 +1,773 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config::ResponseCacheConfig, AppError};
+use anyhow::{bail, Result};
+use axum::{
+    body::{Body, Bytes, HttpBody as _},
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+/// How the cache handled a request, as in RFC 9211
+const CACHE_STATUS: HeaderName = HeaderName::from_static("cache-status");
+
+/// File marking a directory as a generation of the cache, the only ones
+/// which are removed from `dir`
+const GENERATION_MARKER: &str = ".ceno-cache";
+
+/// Responses of GET handlers kept while they're fresh, by method, path,
+/// query and the values of the headers they vary by
+pub struct ResponseCache {
+    config: ResponseCacheConfig,
+    /// subdirectory of `config.dir` for this config and code
+    generation: String,
+    /// opened on first use, so building a router has no side effects
+    dir: OnceLock<Option<PathBuf>>,
+    /// the variants of each key
+    entries: DashMap<String, Vec<Arc<Entry>>>,
+    len: AtomicUsize,
+    /// bumped on every use, the entry used the longest ago is evicted first
+    clock: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    meta: EntryMeta,
+    /// `None` if kept on disk
+    body: Option<Bytes>,
+    used: AtomicU64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EntryMeta {
+    key: String,
+    /// the headers the response varies by, and their values in the request
+    vary: Vec<(String, Option<String>)>,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// seconds since the epoch
+    stored: u64,
+    /// seconds the response is fresh for
+    ttl: u64,
+    /// may answer requests with credentials
+    public: bool,
+}
+
+impl ResponseCache {
+    /// `generation` tells the code and config apart, the entries of other
+    /// generations are dropped from `config.dir`
+    pub fn new(config: ResponseCacheConfig, generation: String) -> Result<Self> {
+        if let Some(admin) = &config.admin {
+            if admin.token.is_empty() {
+                bail!("response_cache.admin needs a token");
+            }
+            if !admin.path.starts_with('/') {
+                bail!("response_cache.admin path {} must start with /", admin.path);
+            }
+        }
+        Ok(Self {
+            config,
+            generation,
+            dir: OnceLock::new(),
+            entries: DashMap::new(),
+            len: AtomicUsize::new(0),
+            clock: AtomicU64::new(0),
+        })
+    }
+
+    /// The cached response to a GET or HEAD request, if fresh. Requests
+    /// `guarded` by middleware, which may turn clients away, are only
+    /// answered with `public` responses.
+    pub fn get(&self, parts: &Parts, guarded: bool) -> Option<Response> {
+        self.get_at(parts, guarded, now())
+    }
+
+    fn get_at(&self, parts: &Parts, guarded: bool, now: u64) -> Option<Response> {
+        if !cacheable(parts) || bypassed(&parts.headers) {
+            return None;
+        }
+        let dir = self.dir();
+        let key = cache_key(parts);
+        let entry = self
+            .entries
+            .get(&key)?
+            .iter()
+            .find(|entry| entry.matches(&parts.headers))
+            .cloned()?;
+        let age = now.saturating_sub(entry.meta.stored);
+        if age >= entry.meta.ttl {
+            self.remove(&entry);
+            return None;
+        }
+        if private(parts, guarded) && !entry.meta.public {
+            return None;
+        }
+        let body = match (&entry.body, dir) {
+            (Some(body), _) => body.clone(),
+            (None, Some(dir)) => match fs::read(dir.join(entry.meta.file("body"))) {
+                Ok(body) => body.into(),
+                Err(e) => {
+                    warn!(key, "cached body unreadable, {e}");
+                    self.remove(&entry);
+                    return None;
+                }
+            },
+            (None, None) => return None,
+        };
+        entry.used.store(self.tick(), Ordering::Relaxed);
+
+        let mut res = Response::new(Body::from(body));
+        *res.status_mut() = StatusCode::from_u16(entry.meta.status).unwrap_or_default();
+        let headers = res.headers_mut();
+        for (name, value) in &entry.meta.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        headers.insert(header::AGE, age.into());
+        let status = format!("ceno; hit; ttl={}", entry.meta.ttl - age);
+        if let Ok(status) = HeaderValue::from_str(&status) {
+            headers.insert(CACHE_STATUS, status);
+        }
+        Some(res)
+    }
+
+    /// Keep `res` if its `Cache-Control` allows it, returning it with an
+    /// `ETag`. Responses to `guarded` requests are kept if `public` only.
+    pub async fn store(
+        &self,
+        parts: &Parts,
+        guarded: bool,
+        res: Response,
+    ) -> Result<Response, AppError> {
+        self.store_at(parts, guarded, res, now()).await
+    }
+
+    async fn store_at(
+        &self,
+        parts: &Parts,
+        guarded: bool,
+        res: Response,
+        now: u64,
+    ) -> Result<Response, AppError> {
+        if !cacheable(parts) {
+            return Ok(res);
+        }
+        let forwarded = match bypassed(&parts.headers) {
+            true => "ceno; fwd=request",
+            false => "ceno; fwd=miss",
+        };
+        let Some((ttl, public)) = self.keepable(parts, guarded, &res) else {
+            let mut res = res;
+            res.headers_mut()
+                .insert(CACHE_STATUS, HeaderValue::from_static(forwarded));
+            return Ok(res);
+        };
+
+        let (mut head, body) = res.into_parts();
+        let body = axum::body::to_bytes(body, self.config.max_body)
+            .await
+            .map_err(anyhow::Error::from)?;
+        if !head.headers.contains_key(header::ETAG) {
+            let etag = format!("\"{}\"", hex(&Sha256::digest(&body)[..16]));
+            if let Ok(etag) = HeaderValue::from_str(&etag) {
+                head.headers.insert(header::ETAG, etag);
+            }
+        }
+        let vary = vary_names(&head.headers)
+            .into_iter()
+            .map(|name| {
+                let value = header_value(&parts.headers, &name);
+                (name, value)
+            })
+            .collect();
+        let headers = head
+            .headers
+            .iter()
+            .filter(|(name, _)| **name != header::AGE && **name != CACHE_STATUS)
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let meta = EntryMeta {
+            key: cache_key(parts),
+            vary,
+            status: head.status.as_u16(),
+            headers,
+            stored: now,
+            ttl,
+            public,
+        };
+        let body_kept = match self.dir() {
+            Some(dir) => match meta.write(dir, &body) {
+                Ok(()) => None,
+                Err(e) => {
+                    warn!(key = meta.key, "cache kept in memory, {e:#}");
+                    Some(body.clone())
+                }
+            },
+            None => Some(body.clone()),
+        };
+        self.insert(meta, body_kept);
+
+        let status = format!("{forwarded}; stored");
+        if let Ok(status) = HeaderValue::from_str(&status) {
+            head.headers.insert(CACHE_STATUS, status);
+        }
+        Ok(Response::from_parts(head, Body::from(body)))
+    }
+
+    /// The freshness of `res` if it may be cached for the request
+    fn keepable(&self, parts: &Parts, guarded: bool, res: &Response) -> Option<(u64, bool)> {
+        let headers = res.headers();
+        if res.status() != StatusCode::OK
+            || headers.contains_key(header::SET_COOKIE)
+            || vary_names(headers).iter().any(|name| name == "*")
+            || directives(&parts.headers).any(|(name, _)| name == "no-store")
+        {
+            return None;
+        }
+        let (ttl, public) = freshness(headers)?;
+        if private(parts, guarded) && !public {
+            return None;
+        }
+        let size = res.body().size_hint().exact()?;
+        (size <= self.config.max_body as u64).then_some((ttl, public))
+    }
+
+    /// Answer the admin endpoint, if this request is for it
+    pub(crate) fn admin(&self, parts: &Parts) -> Result<Option<Response>, AppError> {
+        let Some(admin) = self
+            .config
+            .admin
+            .as_ref()
+            .filter(|admin| admin.path == parts.uri.path())
+        else {
+            return Ok(None);
+        };
+        if parts.method != Method::DELETE {
+            return Err(AppError::RouteMethodNotAllowed(
+                parts.method.clone(),
+                vec![Method::DELETE],
+            ));
+        }
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if !token.is_some_and(|token| same(token.trim().as_bytes(), admin.token.as_bytes())) {
+            return Err(AppError::Unauthorized("Bearer".to_string()));
+        }
+        let path = url::form_urlencoded::parse(parts.uri.query().unwrap_or_default().as_bytes())
+            .find(|(name, _)| name == "path")
+            .map(|(_, path)| path.into_owned());
+        let purged = self.purge(path.as_deref());
+        info!(?path, purged, "response cache purged");
+        Ok(Some(Json(json!({ "purged": purged })).into_response()))
+    }
+
+    /// Drop the entries of `path` and the paths under it, or all of them,
+    /// returning how many were dropped
+    pub fn purge(&self, path: Option<&str>) -> usize {
+        let under = |key: &str| {
+            let Some(path) = path else {
+                return true;
+            };
+            let entry_path = key
+                .split_once(' ')
+                .and_then(|(_, rest)| rest.split('?').next())
+                .unwrap_or_default();
+            let path = path.trim_end_matches('/');
+            entry_path == path
+                || entry_path
+                    .strip_prefix(path)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        };
+        self.dir();
+        let purged = self
+            .entries
+            .iter()
+            .filter(|variants| under(variants.key()))
+            .flat_map(|variants| variants.value().clone())
+            .collect::<Vec<_>>();
+        purged.iter().filter(|entry| self.remove(entry)).count()
+    }
+
+    /// The directory of this generation, loading the entries it holds
+    fn dir(&self) -> Option<&Path> {
+        self.dir
+            .get_or_init(|| {
+                let base = Path::new(self.config.dir.as_ref()?);
+                self.open(base)
+                    .inspect_err(|e| warn!("response cache kept in memory, {e:#}"))
+                    .ok()
+            })
+            .as_deref()
+    }
+
+    fn open(&self, base: &Path) -> Result<PathBuf> {
+        let dir = base.join(&self.generation);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(GENERATION_MARKER), "")?;
+        // entries of other code or config may be stale, while directories
+        // the cache didn't create are left alone
+        for entry in fs::read_dir(base)? {
+            let path = entry?.path();
+            if path != dir && path.join(GENERATION_MARKER).is_file() {
+                fs::remove_dir_all(path)?;
+            }
+        }
+        let now = now();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let meta = serde_json::from_slice::<EntryMeta>(&fs::read(&path)?)?;
+            if now < meta.stored + meta.ttl {
+                self.insert(meta, None);
+            } else {
+                meta.delete(&dir);
+            }
+        }
+        Ok(dir)
+    }
+
+    fn insert(&self, meta: EntryMeta, body: Option<Bytes>) {
+        let entry = Arc::new(Entry {
+            meta,
+            body,
+            used: AtomicU64::new(self.tick()),
+        });
+        let replaced = {
+            let mut variants = self.entries.entry(entry.meta.key.clone()).or_default();
+            let replaced = variants
+                .iter()
+                .position(|variant| variant.meta.vary == entry.meta.vary)
+                .map(|i| variants.swap_remove(i));
+            variants.push(entry);
+            replaced.is_some()
+        };
+        // a replaced entry had the same files, which now hold the new one
+        if !replaced {
+            self.len.fetch_add(1, Ordering::Relaxed);
+        }
+        while self.len.load(Ordering::Relaxed) > self.config.max_entries {
+            let oldest = self
+                .entries
+                .iter()
+                .flat_map(|variants| variants.value().clone())
+                .min_by_key(|entry| entry.used.load(Ordering::Relaxed));
+            match oldest {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+
+    /// Drop `entry`, returning whether it was still cached
+    fn remove(&self, entry: &Arc<Entry>) -> bool {
+        let key = &entry.meta.key;
+        let removed = match self.entries.get_mut(key) {
+            Some(mut variants) => {
+                let len = variants.len();
+                variants.retain(|variant| !Arc::ptr_eq(variant, entry));
+                variants.len() < len
+            }
+            None => false,
+        };
+        self.entries
+            .remove_if(key, |_, variants| variants.is_empty());
+        if removed {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            // not opening the directory, which may be loading this entry
+            if let (None, Some(dir)) = (&entry.body, self.dir.get().and_then(Option::as_deref)) {
+                entry.meta.delete(dir);
+            }
+        }
+        removed
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl Entry {
+    /// Whether the request has the header values this entry varies by
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        self.meta
+            .vary
+            .iter()
+            .all(|(name, value)| header_value(headers, name) == *value)
+    }
+}
+
+impl EntryMeta {
+    /// The name of a file of this entry, by the key and the variant
+    fn file(&self, ext: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.key);
+        for (name, value) in &self.vary {
+            hasher.update(format!(
+                "\n{name}: {}",
+                value.as_deref().unwrap_or_default()
+            ));
+        }
+        format!("{}.{ext}", hex(&hasher.finalize()[..16]))
+    }
+
+    fn write(&self, dir: &Path, body: &[u8]) -> Result<()> {
+        fs::write(dir.join(self.file("body")), body)?;
+        // the metadata is written last, so an entry is loaded complete
+        let tmp = dir.join(self.file("tmp"));
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(tmp, dir.join(self.file("json")))?;
+        Ok(())
+    }
+
+    fn delete(&self, dir: &Path) {
+        for ext in ["json", "body"] {
+            let _ = fs::remove_file(dir.join(self.file(ext)));
+        }
+    }
+}
+
+/// Turn a 200 to a GET or HEAD into a 304 if the request's `If-None-Match`
+/// has its `ETag`
+pub(crate) fn not_modified(parts: &Parts, res: Response) -> Response {
+    if !cacheable(parts) || res.status() != StatusCode::OK {
+        return res;
+    }
+    let (Some(etag), Some(wanted)) = (
+        res.headers()
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok()),
+        parts
+            .headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok()),
+    ) else {
+        return res;
+    };
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let matched = wanted
+        .split(',')
+        .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag));
+    if !matched {
+        return res;
+    }
+
+    let (mut head, _) = res.into_parts();
+    head.status = StatusCode::NOT_MODIFIED;
+    for name in [
+        header::CONTENT_TYPE,
+        header::CONTENT_LENGTH,
+        header::CONTENT_ENCODING,
+    ] {
+        head.headers.remove(name);
+    }
+    Response::from_parts(head, Body::empty())
+}
+
+/// Whether the response may depend on who sent the request, so it can't be
+/// shared unless marked `public`
+fn private(parts: &Parts, guarded: bool) -> bool {
+    guarded || parts.headers.contains_key(header::AUTHORIZATION)
+}
+
+/// The seconds a response may be kept by its `Cache-Control`, and whether it
+/// may answer requests with credentials. Responses which are private or
+/// have to be revalidated aren't kept.
+fn freshness(headers: &HeaderMap) -> Option<(u64, bool)> {
+    let (mut max_age, mut s_maxage, mut public) = (None, None, false);
+    for (name, value) in directives(headers) {
+        let seconds = value.and_then(|v| v.parse::<u64>().ok());
+        match name.as_str() {
+            "no-store" | "no-cache" | "private" => return None,
+            "public" => public = true,
+            "max-age" => max_age = seconds,
+            // a shared cache may answer requests with credentials with it
+            "s-maxage" => (s_maxage, public) = (seconds, true),
+            _ => {}
+        }
+    }
+    let ttl = s_maxage.or(max_age).filter(|ttl| *ttl > 0)?;
+    Some((ttl, public))
+}
+
+/// Whether the request asks for a response which isn't from the cache
+fn bypassed(headers: &HeaderMap) -> bool {
+    directives(headers).any(|(name, value)| {
+        matches!(name.as_str(), "no-cache" | "no-store")
+            || (name == "max-age" && value.as_deref() == Some("0"))
+    })
+}
+
+/// The `Cache-Control` directives, lowercase, and their values
+fn directives(headers: &HeaderMap) -> impl Iterator<Item = (String, Option<String>)> + '_ {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter(|directive| !directive.trim().is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+}
+
+fn vary_names(headers: &HeaderMap) -> Vec<String> {
+    let mut names = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+fn cacheable(parts: &Parts) -> bool {
+    matches!(parts.method, Method::GET | Method::HEAD)
+}
+
+/// HEAD is answered by the GET response, and the order of the query
+/// params doesn't matter
+fn cache_key(parts: &Parts) -> String {
+    let mut query = parts
+        .uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .collect::<Vec<_>>();
+    query.sort_unstable();
+    format!("{} {}?{}", Method::GET, parts.uri.path(), query.join("&"))
+}
+
+/// Compare in constant time
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    fn cache(config: &str) -> ResponseCache {
+        ResponseCache::new(serde_yaml::from_str(config).unwrap(), "g".into()).unwrap()
+    }
+
+    fn parts(uri: &str, headers: &[(&str, &str)]) -> Parts {
+        let mut request = Request::get(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    fn response(body: &'static str, headers: &[(&str, &str)]) -> Response {
+        let mut res = Response::builder();
+        for (name, value) in headers {
+            res = res.header(*name, *value);
+        }
+        res.body(Body::from(body)).unwrap()
+    }
+
+    async fn text(res: Response) -> String {
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn freshness_should_follow_cache_control() {
+        let headers = |value: &'static str| {
+            HeaderMap::from_iter([(header::CACHE_CONTROL, HeaderValue::from_static(value))])
+        };
+        assert_eq!(freshness(&headers("max-age=60")), Some((60, false)));
+        assert_eq!(
+            freshness(&headers("public, max-age=60, s-maxage=10")),
+            Some((10, true))
+        );
+        assert_eq!(freshness(&headers("private, max-age=60")), None);
+        assert_eq!(freshness(&headers("no-cache, max-age=60")), None);
+        assert_eq!(freshness(&headers("max-age=0")), None);
+        assert_eq!(freshness(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn cache_should_keep_fresh_responses_by_vary() {
+        let cache = cache("{}");
+        let fresh = [("cache-control", "max-age=60"), ("vary", "Accept-Language")];
+        let en = parts("/a?y=2&x=1", &[("accept-language", "en")]);
+        let res = cache
+            .store_at(&en, false, response("en", &fresh), 100)
+            .await
+            .unwrap();
+        assert_eq!(res.headers()[CACHE_STATUS], "ceno; fwd=miss; stored");
+        let etag = res.headers()[header::ETAG].clone();
+
+        let res = cache.get_at(
+            &parts("/a?x=1&y=2", &[("accept-language", "en")]),
+            false,
+            130,
+        );
+        let res = res.unwrap();
+        assert_eq!(res.headers()[header::AGE], "30");
+        assert_eq!(res.headers()[header::ETAG], etag);
+        assert_eq!(text(res).await, "en");
+        assert!(cache
+            .get_at(
+                &parts("/a?x=1&y=2", &[("accept-language", "fr")]),
+                false,
+                130
+            )
+            .is_none());
+        assert!(cache
+            .get_at(&parts("/a?x=1", &[("accept-language", "en")]), false, 130)
+            .is_none());
+        assert!(cache
+            .get_at(
+                &parts("/a?x=1&y=2", &[("cache-control", "no-cache")]),
+                false,
+                130
+            )
+            .is_none());
+        // expired
+        assert!(cache.get_at(&en, false, 160).is_none());
+
+        let res = response("private", &[("cache-control", "private, max-age=60")]);
+        cache
+            .store_at(&parts("/b", &[]), false, res, 100)
+            .await
+            .unwrap();
+        let res = response("auth", &[("cache-control", "max-age=60")]);
+        let auth = parts("/c", &[("authorization", "Bearer a")]);
+        cache.store_at(&auth, false, res, 100).await.unwrap();
+        assert!(cache.get_at(&parts("/b", &[]), false, 100).is_none());
+        assert!(cache.get_at(&parts("/c", &[]), false, 100).is_none());
+    }
+
+    #[tokio::test]
+    async fn cache_should_share_only_public_responses_of_guarded_routes() {
+        let cache = cache("{}");
+        let session = parts("/me", &[("cookie", "session=a")]);
+        let res = response("alice", &[("cache-control", "max-age=60")]);
+        let res = cache.store_at(&session, true, res, 100).await.unwrap();
+        assert_eq!(res.headers()[CACHE_STATUS], "ceno; fwd=miss");
+        assert!(cache.get_at(&parts("/me", &[]), true, 100).is_none());
+
+        let res = response("news", &[("cache-control", "public, max-age=60")]);
+        cache.store_at(&session, true, res, 100).await.unwrap();
+        let res = cache.get_at(&parts("/me", &[]), true, 100).unwrap();
+        assert_eq!(text(res).await, "news");
+    }
+
+    #[tokio::test]
+    async fn cache_should_evict_and_purge() {
+        let cache = cache("max_entries: 2");
+        let fresh = [("cache-control", "max-age=60")];
+        for path in ["/a", "/a/b"] {
+            let res = response("x", &fresh);
+            cache
+                .store_at(&parts(path, &[]), false, res, 100)
+                .await
+                .unwrap();
+        }
+        assert!(cache.get_at(&parts("/a", &[]), false, 100).is_some());
+        let res = response("x", &fresh);
+        cache
+            .store_at(&parts("/ab", &[]), false, res, 100)
+            .await
+            .unwrap();
+        // the least recently used is evicted
+        assert!(cache.get_at(&parts("/a/b", &[]), false, 100).is_none());
+        assert_eq!(cache.purge(Some("/a/")), 1);
+        assert_eq!(cache.purge(None), 1);
+    }
+
+    #[tokio::test]
+    async fn cache_should_reload_from_disk() {
+        let dir = std::env::temp_dir().join(format!("ceno-cache-{}", uuid::Uuid::new_v4()));
+        let config = format!("dir: {}", dir.display());
+        let fresh = [("cache-control", "max-age=60")];
+        fs::create_dir_all(dir.join("queue")).unwrap();
+        fs::write(dir.join("queue/job.json"), "{}").unwrap();
+        cache(&config)
+            .store(&parts("/a", &[]), false, response("on disk", &fresh))
+            .await
+            .unwrap();
+        let res = cache(&config).get(&parts("/a", &[]), false).unwrap();
+        assert_eq!(text(res).await, "on disk");
+
+        // another generation drops the entries
+        let other = ResponseCache::new(serde_yaml::from_str(&config).unwrap(), "h".into());
+        assert!(other.unwrap().get(&parts("/a", &[]), false).is_none());
+        assert!(!dir.join("g").exists());
+        assert!(dir.join("queue/job.json").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn not_modified_should_match_etags() {
+        let etag = [("etag", "\"abc\"")];
+        let res = not_modified(
+            &parts("/", &[("if-none-match", "\"x\", W/\"abc\"")]),
+            response("x", &etag),
+        );
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[header::ETAG], "\"abc\"");
+        let res = not_modified(
+            &parts("/", &[("if-none-match", "\"x\"")]),
+            response("x", &etag),
+        );
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -1,46 +1,1358 @@
-use anyhow::Result;
+use crate::params::RoutePath;
+use anyhow::{anyhow, bail, Context as _, Result};
 use axum::http::Method;
-use serde::{Deserialize, Deserializer};
-use std::{collections::HashMap, path::Path};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_path_to_error::Segment;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+};
 
-#[derive(Debug, Deserialize)]
+/// Config files of a project, one of which it must have
+pub const CONFIG_FILES: [&str; 4] = ["config.yml", "config.json", "config.toml", "ceno.json"];
+
+/// JSON Schema of the config, for editors to validate it
+pub const CONFIG_SCHEMA: &str = include_str!("../config.schema.json");
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ProjectConfig {
     pub name: String,
+    /// exported functions wrapping every handler, outermost first
+    #[serde(default)]
+    pub middleware: Vec<String>,
+    #[serde(default, serialize_with = "sorted")]
     pub routes: ProjectRoutes,
+    /// nested routes sharing a prefix and middleware, flattened into
+    /// `routes` by [`ProjectConfig::load`]
+    #[serde(default)]
+    pub groups: Vec<RouteGroup>,
+    /// files holding a [`RouteGroup`] each, relative to the project
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// also route the method exports of the files under `routes/`
+    #[serde(default)]
+    pub fs_routes: bool,
+    #[serde(default, serialize_with = "sorted")]
+    pub schedules: ProjectSchedules,
+    #[serde(default, serialize_with = "sorted")]
+    pub queues: ProjectQueues,
+    #[serde(default)]
+    pub openapi: OpenApiConfig,
+    /// limits of all the requests to the project, checked before routing
+    #[serde(default)]
+    pub rate_limits: Vec<ProjectRateLimit>,
+    /// compress responses for clients accepting it, if set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionConfig>,
+    /// keep the responses of GET handlers while they're fresh, if set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_cache: Option<ResponseCacheConfig>,
+    /// log every request of the project, if set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_log: Option<AccessLogConfig>,
 }
 
 pub type ProjectRoutes = HashMap<String, Vec<ProjectRoute>>;
 
-#[derive(Debug, Deserialize)]
+/// Routes sharing a path prefix and middleware, which may be nested or
+/// included from other files
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RouteGroup {
+    #[serde(default)]
+    pub prefix: String,
+    /// exported functions wrapping the handlers of the group, inside the
+    /// middleware of the enclosing groups
+    #[serde(default)]
+    pub middleware: Vec<String>,
+    #[serde(default, serialize_with = "sorted")]
+    pub routes: ProjectRoutes,
+    #[serde(default)]
+    pub groups: Vec<RouteGroup>,
+    /// files holding a group each, relative to the project
+    #[serde(default)]
+    pub include: Vec<String>,
+}
+
+pub type ProjectSchedules = HashMap<String, ProjectSchedule>;
+
+pub type ProjectQueues = HashMap<String, ProjectQueue>;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "RouteFields")]
 pub struct ProjectRoute {
-    #[serde(deserialize_with = "deserialize_method")]
-    pub method: Method,
+    /// a method, a list of them, or `ANY`
+    #[serde(
+        deserialize_with = "deserialize_methods",
+        serialize_with = "serialize_methods"
+    )]
+    pub method: RouteMethods,
+    #[serde(flatten)]
+    pub action: RouteAction,
+    #[serde(flatten)]
+    pub settings: RouteSettings,
+}
+
+/// A route as written, checked to have one action only
+#[derive(Deserialize)]
+struct RouteFields {
+    #[serde(deserialize_with = "deserialize_methods")]
+    method: RouteMethods,
+    #[serde(flatten)]
+    action: RouteAction,
+    /// taken after `action`, which removes its own
+    #[serde(flatten)]
+    others: RouteActions,
+    #[serde(flatten)]
+    settings: RouteSettings,
+}
+
+/// Which of the actions a route sets
+#[derive(Deserialize)]
+struct RouteActions {
+    handler: Option<IgnoredAny>,
+    redirect: Option<IgnoredAny>,
+    rewrite: Option<IgnoredAny>,
+    proxy: Option<IgnoredAny>,
+}
+
+impl TryFrom<RouteFields> for ProjectRoute {
+    type Error = String;
+
+    fn try_from(route: RouteFields) -> Result<Self, String> {
+        let RouteActions {
+            handler,
+            redirect,
+            rewrite,
+            proxy,
+        } = route.others;
+        let action = match route.action {
+            RouteAction::Handler(_) => "handler",
+            RouteAction::Redirect(_) => "redirect",
+            RouteAction::Rewrite(_) => "rewrite",
+            RouteAction::Proxy(_) => "proxy",
+        };
+        let set = [
+            ("handler", handler.is_some()),
+            ("redirect", redirect.is_some()),
+            ("rewrite", rewrite.is_some()),
+            ("proxy", proxy.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name));
+        let set = std::iter::once(action).chain(set).collect::<Vec<_>>();
+        if set.len() > 1 {
+            return Err(format!(
+                "a route has one of handler, redirect, rewrite and proxy, found {}",
+                set.join(" and ")
+            ));
+        }
+        Ok(Self {
+            method: route.method,
+            action: route.action,
+            settings: route.settings,
+        })
+    }
+}
+
+/// What answers a route
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteAction {
+    /// an exported function of the project
+    Handler(String),
+    #[serde(deserialize_with = "string_or_struct")]
+    Redirect(Redirect),
+    /// the route matching this path, templated from the params, answers
+    Rewrite(String),
+    #[serde(deserialize_with = "string_or_struct")]
+    Proxy(Proxy),
+}
+
+/// Send the client elsewhere, `to` may be a path or a URL templated from the
+/// params, e.g. `/users/{id}`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Redirect {
+    pub to: String,
+    /// 301, 302, 303, 307 or 308
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+/// Forward the request to `url` templated from the params, e.g.
+/// `http://backend/{rest}`, streaming the bodies both ways
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Proxy {
+    pub url: String,
+    /// rewrites of the request headers
+    #[serde(default)]
+    pub headers: HeaderRewrite,
+    /// rewrites of the response headers
+    #[serde(default)]
+    pub response_headers: HeaderRewrite,
+    /// send the `Host` of the request rather than the one of `url`
+    #[serde(default)]
+    pub preserve_host: bool,
+}
+
+/// Headers to remove, then to set, whose values are templated from the params
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct HeaderRewrite {
+    #[serde(default, serialize_with = "sorted")]
+    pub set: HashMap<String, String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+/// Methods answered by a route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteMethods {
+    /// every method without a route of its own
+    Any,
+    List(Vec<Method>),
+}
+
+/// Optional policies of a route, enforced before the request is dispatched
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RouteSettings {
+    /// exported functions wrapping the handler, inside the global middleware
+    #[serde(default)]
+    pub middleware: Vec<String>,
+    /// milliseconds to wait for the handler before responding 504
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// max request body in bytes, 2 MiB by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_body: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cors: Option<CorsPolicy>,
+    /// scheme the `Authorization` header must use, e.g. `Bearer`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<String>,
+    /// `Cache-Control` of successful responses which don't set one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema: Option<RouteSchemas>,
+}
+
+/// JSON Schemas the parts of a request, and the response body, must satisfy
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RouteSchemas {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<SchemaSource>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub params: Option<SchemaSource>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<SchemaSource>,
+    /// checked on 2xx responses when responses are validated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<SchemaSource>,
+}
+
+/// A schema written in the config, or the path of a JSON file relative to the project
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum SchemaSource {
+    Path(String),
+    Inline(serde_json::Value),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CorsPolicy {
+    /// allowed origins, `*` allows any
+    #[serde(default = "default_cors_origins")]
+    pub origins: Vec<String>,
+    /// allowed request headers, the requested ones are allowed if empty
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// response headers exposed to the page
+    #[serde(default)]
+    pub expose: Vec<String>,
+    #[serde(default)]
+    pub credentials: bool,
+    /// seconds a preflight response may be cached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+}
+
+/// Allow `requests` per `window` seconds for each client
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RateLimit {
+    pub requests: u32,
+    #[serde(default = "default_rate_limit_window")]
+    pub window: u64,
+    #[serde(default)]
+    pub key: RateLimitKey,
+}
+
+/// What tells the clients of a rate limit apart
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitKey {
+    #[default]
+    Ip,
+    /// the value of a header, like an API key, or the ip without it
+    Header(String),
+}
+
+/// A rate limit of the whole project
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ProjectRateLimit {
+    #[serde(flatten)]
+    pub limit: RateLimit,
+    /// a bucket per route and client, rather than per client
+    #[serde(default)]
+    pub per_route: bool,
+}
+
+/// Which responses are compressed, and how
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CompressionConfig {
+    /// offered encodings, preferred in this order when the client accepts
+    /// them equally
+    #[serde(default = "default_encodings")]
+    pub encodings: Vec<Encoding>,
+    /// bodies of fewer bytes are sent as they are
+    #[serde(default = "default_min_size")]
+    pub min_size: usize,
+    /// compressed content types, `text/*` matches every text type
+    #[serde(default = "default_content_types")]
+    pub content_types: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Br,
+    Zstd,
+    Gzip,
+}
+
+/// Where and how many handler responses are cached
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ResponseCacheConfig {
+    /// responses kept, the least recently used are evicted first
+    #[serde(default = "default_cache_entries")]
+    pub max_entries: usize,
+    /// larger bodies aren't cached, in bytes
+    #[serde(default = "default_cache_body")]
+    pub max_body: usize,
+    /// keep the bodies in this directory rather than in memory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+    /// an endpoint purging the cache, if set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin: Option<CacheAdmin>,
+}
+
+/// `DELETE {path}` purges the cache, or the entries under `?path=`, for
+/// requests bearing `token`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CacheAdmin {
+    pub path: String,
+    pub token: String,
+}
+
+/// Where and how requests are logged
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    /// appended to, stdout if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// bytes written before the file is rotated
+    #[serde(default = "default_log_size")]
+    pub max_size: u64,
+    /// rotated files kept, `{file}.1` being the newest
+    #[serde(default = "default_log_files")]
+    pub max_files: usize,
+}
+
+/// Apache's common and combined log formats, or a JSON object per line
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Common,
+    #[default]
+    Combined,
+    Json,
+}
+
+fn default_log_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_log_files() -> usize {
+    5
+}
+
+fn default_cache_entries() -> usize {
+    1000
+}
+
+fn default_cache_body() -> usize {
+    1024 * 1024
+}
+
+fn default_encodings() -> Vec<Encoding> {
+    vec![Encoding::Br, Encoding::Zstd, Encoding::Gzip]
+}
+
+fn default_min_size() -> usize {
+    1024
+}
+
+fn default_content_types() -> Vec<String> {
+    [
+        "text/*",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+        "image/svg+xml",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+impl Encoding {
+    /// As in `Accept-Encoding` and `Content-Encoding`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Br => "br",
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+}
+
+fn default_redirect_status() -> u16 {
+    302
+}
+
+impl From<String> for Redirect {
+    fn from(to: String) -> Self {
+        Self {
+            to,
+            status: default_redirect_status(),
+        }
+    }
+}
+
+impl From<String> for Proxy {
+    fn from(url: String) -> Self {
+        Self {
+            url,
+            headers: HeaderRewrite::default(),
+            response_headers: HeaderRewrite::default(),
+            preserve_host: false,
+        }
+    }
+}
+
+impl RouteAction {
+    /// The name of the handler, if the route has one
+    pub fn handler(&self) -> Option<&str> {
+        match self {
+            Self::Handler(handler) => Some(handler),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RouteAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Handler(handler) => write!(f, "handler {handler}"),
+            Self::Redirect(redirect) => write!(f, "redirect to {}", redirect.to),
+            Self::Rewrite(to) => write!(f, "rewrite to {to}"),
+            Self::Proxy(proxy) => write!(f, "proxy to {}", proxy.url),
+        }
+    }
+}
+
+fn default_cors_origins() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn default_rate_limit_window() -> u64 {
+    60
+}
+
+/// Info of the generated OpenAPI document, see [`openapi`](crate::openapi)
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct OpenApiConfig {
+    /// path the running server serves the document at, not served if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// the project name by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default = "default_openapi_version")]
+    pub version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+fn default_openapi_version() -> String {
+    "0.1.0".to_string()
+}
+
+/// A handler run periodically, see [`Scheduler`](crate::Scheduler)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ProjectSchedule {
+    /// cron expression, either `min hour dom month dow` or with leading seconds
+    pub cron: String,
     pub handler: String,
+    #[serde(default)]
+    pub overlap: OverlapPolicy,
+}
+
+/// What to do when a schedule fires while its previous run is still going
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlapPolicy {
+    /// skip this run
+    #[default]
+    Skip,
+    /// run concurrently
+    Allow,
+}
+
+/// The consumer of a queue, see [`QueueDispatcher`](crate::QueueDispatcher)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ProjectQueue {
+    pub handler: String,
+    /// attempts after the first one failed, unless set when enqueued
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// milliseconds before the first retry, doubled on each of the next ones
+    #[serde(default = "default_backoff")]
+    pub backoff: u64,
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_backoff() -> u64 {
+    1000
+}
+
+impl SchemaSource {
+    /// The schema, read from its file if it isn't inline
+    pub fn load(&self) -> Result<serde_json::Value> {
+        match self {
+            SchemaSource::Path(path) => {
+                let content = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read schema {path}"))?;
+                serde_json::from_str(&content).with_context(|| format!("invalid schema {path}"))
+            }
+            SchemaSource::Inline(schema) => Ok(schema.clone()),
+        }
+    }
+}
+
+/// Formats a config may be written in, told by the file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yml" | "yaml") => Ok(Self::Yaml),
+            Some("json") => Ok(Self::Json),
+            Some("toml") => Ok(Self::Toml),
+            _ => bail!("unknown config format of {}", path.display()),
+        }
+    }
+
+    /// Errors of every format carry the line they occurred at
+    fn parse<T: DeserializeOwned>(self, content: &str) -> Result<T> {
+        Ok(match self {
+            Self::Yaml => serde_yaml::from_str(content)?,
+            Self::Json => serde_json::from_str(content)?,
+            Self::Toml => toml::from_str(content)?,
+        })
+    }
 }
 
 impl ProjectConfig {
+    /// The config file of the project in `dir`, see [`CONFIG_FILES`]
+    pub fn find(dir: impl AsRef<Path>) -> Result<PathBuf> {
+        let dir = dir.as_ref();
+        let found = CONFIG_FILES
+            .iter()
+            .map(|name| dir.join(name))
+            .filter(|path| path.exists())
+            .collect::<Vec<_>>();
+        match found.as_slice() {
+            [path] => Ok(path.clone()),
+            [] => bail!(
+                "no config found, expected one of {}",
+                CONFIG_FILES.join(", ")
+            ),
+            _ => bail!(
+                "only one config is allowed, found {}",
+                found
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    /// Load the config, flattening its groups and included files into
+    /// `routes`. Errors point at the file, and the line if possible, the
+    /// route came from.
     pub fn load(filename: impl AsRef<Path>) -> Result<Self> {
-        let content = std::fs::read_to_string(filename)?;
-        let config: ProjectConfig = serde_yaml::from_str(&content)?;
+        Self::load_env(filename, None)
+    }
+
+    /// Load the config merged with its overlay for `env`, e.g.
+    /// `config.prod.yml` for `config.yml`. `${VAR}` and `${VAR:-default}` are
+    /// replaced by environment variables in the values of both.
+    pub fn load_env(filename: impl AsRef<Path>, env: Option<&str>) -> Result<Self> {
+        Self::load_in(filename, env, Path::new("."))
+    }
+
+    /// Load the config of the project in `root`
+    fn load_in(filename: impl AsRef<Path>, env: Option<&str>, root: &Path) -> Result<Self> {
+        let filename = filename.as_ref();
+        let format = ConfigFormat::from_path(filename)?;
+        let mut sources = vec![Source::read(filename)?];
+        let mut value = sources[0].parse(format)?;
+        if let Some(env) = env {
+            let overlay = Source::read(&overlay_path(filename, env))?;
+            merge_yaml(&mut value, overlay.parse(format)?);
+            sources.push(overlay);
+        }
+        let mut config: Self = deserialize(value, &sources)?;
+
+        let group = RouteGroup {
+            routes: std::mem::take(&mut config.routes),
+            groups: std::mem::take(&mut config.groups),
+            include: std::mem::take(&mut config.include),
+            ..Default::default()
+        };
+        let mut resolver = GroupResolver {
+            root,
+            routes: HashMap::new(),
+            origins: HashMap::new(),
+            including: Vec::new(),
+        };
+        resolver.add(group, &Scope::default(), &sources)?;
+        config.routes = resolver.routes;
         Ok(config)
     }
 }
 
-fn deserialize_method<'de, D>(deserializer: D) -> Result<Method, D::Error>
+/// `config.yml` and `prod` is `config.prod.yml`
+fn overlay_path(filename: &Path, env: &str) -> PathBuf {
+    let stem = filename.file_stem().unwrap_or_default().to_string_lossy();
+    match filename.extension() {
+        Some(ext) => filename.with_file_name(format!("{stem}.{env}.{}", ext.to_string_lossy())),
+        None => filename.with_file_name(format!("{stem}.{env}")),
+    }
+}
+
+/// Merge `overlay` into `base`, mappings are merged key by key while other
+/// values are replaced, and a `null` removes the key
+fn merge_yaml(base: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    use serde_yaml::Value;
+
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match (base.get_mut(&key), value) {
+                    (_, Value::Null) => {
+                        base.remove(&key);
+                    }
+                    (Some(base), value) => merge_yaml(base, value),
+                    (None, value) => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` in `value` with the values of
+/// `vars`, the default is used when the variable is unset or empty. `$${` is
+/// a literal `${`.
+fn interpolate(value: &str, vars: &impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut interpolated = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        // escaped
+        if rest[..start].ends_with('$') {
+            interpolated.push_str(&rest[..start - 1]);
+            interpolated.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let Some(len) = rest[start..].find('}') else {
+            bail!("unclosed ${{ in {value}");
+        };
+        let expr = &rest[start + 2..start + len];
+        let (var, default) = match expr.split_once(":-") {
+            Some((var, default)) => (var, Some(default)),
+            None => (expr, None),
+        };
+        let value = vars(var).filter(|v| !v.is_empty());
+        let value = match (value, default) {
+            (Some(value), _) => value,
+            (None, Some(default)) => default.to_string(),
+            (None, None) => bail!("{var} is not set"),
+        };
+        interpolated.push_str(&rest[..start]);
+        interpolated.push_str(&value);
+        rest = &rest[start + len + 1..];
+    }
+    interpolated.push_str(rest);
+    Ok(interpolated)
+}
+
+/// Interpolate the strings of `value`, keys are left as they are. A string
+/// which is just a variable becomes a number or a bool if its value is one,
+/// as if written in place, but never a mapping or a list.
+fn interpolate_value(
+    value: &mut serde_yaml::Value,
+    vars: &impl Fn(&str) -> Option<String>,
+) -> Result<()> {
+    use serde_yaml::Value;
+
+    match value {
+        Value::String(s) if s.contains("${") => {
+            let whole = s.starts_with("${") && s.find('}') == Some(s.len() - 1);
+            let interpolated = interpolate(s, vars)?;
+            *value = match serde_yaml::from_str(&interpolated) {
+                Ok(scalar @ (Value::Number(_) | Value::Bool(_))) if whole => scalar,
+                _ => Value::String(interpolated),
+            };
+        }
+        Value::Sequence(values) => {
+            for value in values {
+                interpolate_value(value, vars)?;
+            }
+        }
+        Value::Mapping(values) => {
+            for (_, value) in values.iter_mut() {
+                interpolate_value(value, vars)?;
+            }
+        }
+        Value::Tagged(tagged) => interpolate_value(&mut tagged.value, vars)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Deserialize the value parsed from `sources`, an error points at the
+/// line of the last source declaring the field it's about
+fn deserialize<T: DeserializeOwned>(value: serde_yaml::Value, sources: &[Source]) -> Result<T> {
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let path = e.path().iter().collect::<Vec<_>>();
+        let (depth, line, source) = sources
+            .iter()
+            .map(|source| {
+                let (depth, line) = source.locate_path(&path);
+                (depth, line, source)
+            })
+            .max_by_key(|(depth, ..)| *depth)
+            .expect("a config has a source");
+        let at = line
+            .filter(|_| depth > 0)
+            .map(|line| (e.path().to_string(), line + 1));
+        let e = match at {
+            Some((path, line)) => anyhow!("{path}: {} at line {line}", e.into_inner()),
+            None => anyhow!(e.into_inner()),
+        };
+        e.context(format!("invalid config {}", source.name))
+    })
+}
+
+/// Flattens route groups into [`ProjectRoutes`]
+struct GroupResolver<'a> {
+    root: &'a Path,
+    routes: ProjectRoutes,
+    /// where each path was declared, e.g. `config.yml line 4`
+    origins: HashMap<String, String>,
+    /// files being included, to catch cycles
+    including: Vec<PathBuf>,
+}
+
+/// Prefix and middleware of the enclosing groups
+#[derive(Default)]
+struct Scope {
+    prefix: String,
+    middleware: Vec<String>,
+}
+
+/// A config file and its content
+struct Source {
+    name: String,
+    content: String,
+}
+
+impl GroupResolver<'_> {
+    fn add(&mut self, group: RouteGroup, scope: &Scope, sources: &[Source]) -> Result<()> {
+        let scope = Scope {
+            prefix: join_path(&scope.prefix, &group.prefix),
+            middleware: [scope.middleware.as_slice(), &group.middleware].concat(),
+        };
+
+        // sorted, so errors are reported in a stable order
+        let mut routes = group.routes.into_iter().collect::<Vec<_>>();
+        routes.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (path, routes) in routes {
+            let full = join_path(&scope.prefix, &path);
+            let origin = locate(sources, &path);
+            RoutePath::parse(&full).with_context(|| format!("invalid route at {origin}"))?;
+            if let Some(other) = self.origins.insert(full.clone(), origin.clone()) {
+                bail!("route {full} at {origin} is declared at {other} too");
+            }
+            let entry = self.routes.entry(full).or_default();
+            for mut route in routes {
+                route
+                    .settings
+                    .middleware
+                    .splice(0..0, scope.middleware.clone());
+                entry.push(route);
+            }
+        }
+
+        for group in group.groups {
+            self.add(group, &scope, sources)?;
+        }
+        for include in group.include {
+            self.include(&include, &scope, &locate(sources, "include"))?;
+        }
+        Ok(())
+    }
+
+    /// Add the group of `include`, listed at `origin`
+    fn include(&mut self, include: &str, scope: &Scope, origin: &str) -> Result<()> {
+        let path = self.root.join(include);
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {include}, included at {origin}"))?;
+        let canonical = path.canonicalize()?;
+        if self.including.contains(&canonical) {
+            bail!("{include} includes itself, via {origin}");
+        }
+        let source = Source {
+            name: include.to_string(),
+            content,
+        };
+        let value = source.parse(ConfigFormat::from_path(include)?)?;
+        let sources = [source];
+        let group: RouteGroup = deserialize(value, &sources)?;
+
+        self.including.push(canonical);
+        self.add(group, scope, &sources)?;
+        self.including.pop();
+        Ok(())
+    }
+}
+
+impl Source {
+    /// Read the config file at `path`
+    fn read(path: &Path) -> Result<Self> {
+        let name = path.display().to_string();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {name}"))?;
+        Ok(Self { name, content })
+    }
+
+    /// The content, with the variables of its values replaced
+    fn parse(&self, format: ConfigFormat) -> Result<serde_yaml::Value> {
+        let mut value = format
+            .parse(&self.content)
+            .with_context(|| format!("invalid config {}", self.name))?;
+        let vars = |var: &str| std::env::var(var).ok();
+        interpolate_value(&mut value, &vars).with_context(|| {
+            // the first line failing on its own
+            let failing = |line: &&str| {
+                !line.trim_start().starts_with('#') && interpolate(line, &vars).is_err()
+            };
+            match self.content.lines().position(|line| failing(&line)) {
+                Some(i) => format!("invalid config {} line {}", self.name, i + 1),
+                None => format!("invalid config {}", self.name),
+            }
+        })?;
+        Ok(value)
+    }
+
+    /// `name line N` of the first line declaring `key`, or just `name`
+    fn locate(&self, key: &str) -> String {
+        match self.content.lines().position(|line| declares(line, key)) {
+            Some(i) => format!("{} line {}", self.name, i + 1),
+            None => self.name.clone(),
+        }
+    }
+
+    /// The line of the field at `path`, along with the number of segments
+    /// of the path found on the way to it, the ones not found are skipped
+    fn locate_path(&self, path: &[&Segment]) -> (usize, Option<usize>) {
+        let lines = self.content.lines().collect::<Vec<_>>();
+        let (mut depth, mut line, mut from) = (0, None, 0);
+        for segment in path {
+            let found = match segment {
+                Segment::Map { key } => lines[from..]
+                    .iter()
+                    .position(|line| declares(line, key))
+                    .map(|i| from + i),
+                // YAML items, at the indent of the first one
+                Segment::Seq { index } => {
+                    let items = lines[from..]
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, line)| line.trim_start().starts_with('-'))
+                        .map(|(i, line)| (from + i, line.len() - line.trim_start().len()))
+                        .collect::<Vec<_>>();
+                    let indent = items.first().map(|(_, indent)| *indent);
+                    items
+                        .iter()
+                        .filter(|(_, i)| Some(*i) == indent)
+                        .nth(*index)
+                        .map(|(i, _)| *i)
+                }
+                _ => continue,
+            };
+            // e.g. the `routes` of a TOML table `[[routes."/users"]]`, or the
+            // items of a JSON list
+            let Some(found) = found else {
+                continue;
+            };
+            depth += 1;
+            line = Some(found);
+            // the first key of an item is on its line
+            from = match segment {
+                Segment::Seq { .. } => found,
+                _ => found + 1,
+            };
+        }
+        (depth, line)
+    }
+}
+
+/// Whether `line` declares `key`, in any of the formats
+fn declares(line: &str, key: &str) -> bool {
+    let line = line.trim_start();
+    let line = line.strip_prefix("- ").unwrap_or(line).trim_start();
+    let table = format!(".\"{key}\"");
+    [
+        format!("{key}:"),
+        format!("\"{key}\":"),
+        format!("'{key}':"),
+        format!("\"{key}\" ="),
+        format!("{key} ="),
+    ]
+    .iter()
+    .any(|prefix| line.starts_with(prefix.as_str()))
+        // a TOML table, e.g. `[[routes."/users"]]`
+        || line.starts_with('[') && line.trim_end_matches(']').ends_with(&table)
+}
+
+/// `name line N` of the last of `sources` declaring `key`, e.g. the overlay
+/// of an environment, or the name of the first one
+fn locate(sources: &[Source], key: &str) -> String {
+    sources
+        .iter()
+        .rev()
+        .map(|source| source.locate(key))
+        .find(|origin| origin.contains(" line "))
+        .unwrap_or_else(|| sources[0].name.clone())
+}
+
+/// `/api` and `/users` is `/api/users`, and `/api` and `/` is `/api`
+fn join_path(prefix: &str, path: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    match path.trim_start_matches('/') {
+        "" if prefix.is_empty() => "/".to_string(),
+        "" => prefix.to_string(),
+        path => format!("{prefix}/{path}"),
+    }
+}
+
+/// Serialize a map sorted by its keys, so the output is stable
+fn sorted<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+    S: Serializer,
+{
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+/// A single method is written as a string, like it may be given
+fn serialize_methods<S: Serializer>(
+    methods: &RouteMethods,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match methods {
+        RouteMethods::Any => serializer.serialize_str("ANY"),
+        RouteMethods::List(methods) if methods.len() == 1 => {
+            serializer.serialize_str(methods[0].as_str())
+        }
+        RouteMethods::List(methods) => serializer.collect_seq(methods.iter().map(Method::as_str)),
+    }
+}
+
+/// A string converted to `T`, or `T` itself
+fn string_or_struct<'de, T, D>(deserializer: D) -> Result<T, D::Error>
 where
+    T: Deserialize<'de> + From<String>,
     D: Deserializer<'de>,
 {
-    let s = String::deserialize(deserializer)?;
-    match s.to_uppercase().as_str() {
-        "GET" => Ok(Method::GET),
-        "POST" => Ok(Method::POST),
-        "PUT" => Ok(Method::PUT),
-        "DELETE" => Ok(Method::DELETE),
-        "PATCH" => Ok(Method::PATCH),
-        "HEAD" => Ok(Method::HEAD),
-        "OPTIONS" => Ok(Method::OPTIONS),
-        "CONNECT" => Ok(Method::CONNECT),
-        "TRACE" => Ok(Method::TRACE),
-        _ => Err(serde::de::Error::custom("invalid method")),
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOr<T> {
+        String(String),
+        Struct(T),
+    }
+
+    Ok(match StringOr::deserialize(deserializer)? {
+        StringOr::String(s) => s.into(),
+        StringOr::Struct(t) => t,
+    })
+}
+
+fn deserialize_methods<'de, D>(deserializer: D) -> Result<RouteMethods, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let names = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(name) => vec![name],
+        OneOrMany::Many(names) => names,
+    };
+    if names.is_empty() {
+        return Err(serde::de::Error::custom("empty method list"));
+    }
+    if names
+        .iter()
+        .any(|n| n == "*" || n.eq_ignore_ascii_case("any"))
+    {
+        return Ok(RouteMethods::Any);
+    }
+    names
+        .iter()
+        .map(|name| {
+            // extension methods such as PURGE are allowed too
+            Method::from_bytes(name.to_uppercase().as_bytes())
+                .map_err(|_| serde::de::Error::custom(format!("invalid method {name}")))
+        })
+        .collect::<Result<_, _>>()
+        .map(RouteMethods::List)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn project(files: &[(&str, &str)]) -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (file, content) in files {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn load_should_flatten_groups_and_includes() {
+        let tmp = project(&[
+            (
+                "config.yml",
+                r#"
+name: test
+routes:
+  /health:
+    - method: GET
+      handler: health
+groups:
+  - prefix: /api
+    middleware: [auth]
+    routes:
+      /:
+        - method: GET
+          handler: index
+    groups:
+      - prefix: /admin
+        middleware: [admin]
+        include: [teams/admin.yml]
+"#,
+            ),
+            (
+                "teams/admin.yml",
+                r#"
+routes:
+  /users/{id:int}:
+    - method: DELETE
+      handler: deleteUser
+      middleware: [audit]
+"#,
+            ),
+        ]);
+        let dir = tmp.path();
+        let config = ProjectConfig::load_in(dir.join("config.yml"), None, dir).unwrap();
+        let mut paths = config.routes.keys().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths, ["/api", "/api/admin/users/{id:int}", "/health"]);
+        assert!(config.routes["/health"][0].settings.middleware.is_empty());
+        assert_eq!(config.routes["/api"][0].settings.middleware, ["auth"]);
+        assert_eq!(
+            config.routes["/api/admin/users/{id:int}"][0]
+                .settings
+                .middleware,
+            ["auth", "admin", "audit"]
+        );
+    }
+
+    #[test]
+    fn load_should_point_at_the_origin_of_errors() {
+        let tmp = project(
+            &[
+                (
+                    "config.yml",
+                    "name: test\nroutes:\n  /api/users:\n    - method: GET\n      handler: users\ninclude: [api.yml]\n",
+                ),
+                (
+                    "api.yml",
+                    "prefix: /api\nroutes:\n  /users:\n    - method: POST\n      handler: createUser\n",
+                ),
+                ("bad.yml", "routes:\n  /x:\n    - method: [GET]\n      handler: [x]\n"),
+                ("self.yml", "include: [self.yml]\n"),
+            ],
+        );
+        let dir = tmp.path();
+        let load = |content: &str| {
+            std::fs::write(dir.join("main.yml"), content).unwrap();
+            let err = ProjectConfig::load_in(dir.join("main.yml"), None, dir).unwrap_err();
+            format!("{err:#}")
+        };
+
+        let err = ProjectConfig::load_in(dir.join("config.yml"), None, dir).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "route /api/users at api.yml line 3 is declared at {} line 3 too",
+                dir.join("config.yml").display()
+            )
+        );
+        let err = load("name: test\ninclude: [bad.yml]\n");
+        assert!(err.starts_with("invalid config bad.yml: "), "{err}");
+        assert!(err.contains("line 3"), "{err}");
+        assert!(load("name: test\ninclude: [self.yml]\n").contains("includes itself"));
+        let err = load("name: test\n# ${CENO_UNSET}\nroutes:\n  /x:\n    - method: GET\n      handler: ${CENO_UNSET}\n");
+        assert!(
+            err.contains("main.yml line 6: CENO_UNSET is not set"),
+            "{err}"
+        );
+        assert!(load("name: test\ninclude: [missing.yml]\n").contains("missing.yml"));
+    }
+
+    #[test]
+    fn route_should_have_one_action() {
+        let parse = |actions: &str| {
+            serde_yaml::from_str::<ProjectRoute>(&format!("method: GET\n{actions}\ntimeout: 10\n"))
+        };
+        let route = parse("handler: users").unwrap();
+        assert_eq!(route.action.to_string(), "handler users");
+        assert_eq!(route.settings.timeout, Some(10));
+
+        let err = parse("handler: users\nrewrite: /x").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("a route has one of handler, redirect, rewrite and proxy, found handler and rewrite"),
+            "{err}"
+        );
+        assert!(parse("proxy: http://up\nredirect: { to: /x }").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn interpolate_should_replace_vars() {
+        let vars = |var: &str| match var {
+            "PORT" => Some("8080".to_string()),
+            "TITLE" => Some("a: b\nc: [d]".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            interpolate("${HOST:-localhost}:${PORT:-1}", &vars).unwrap(),
+            "localhost:8080"
+        );
+        assert_eq!(interpolate("$${PORT}", &vars).unwrap(), "${PORT}");
+        let err = interpolate("${HOST}", &vars).unwrap_err();
+        assert_eq!(err.to_string(), "HOST is not set");
+
+        // comments are left alone, and values can't add to the document
+        let content = "# ${HOST}\na: ${PORT}\nb: \"${PORT}/x\"\nc: ${TITLE}\n";
+        let mut value = serde_yaml::from_str(content).unwrap();
+        interpolate_value(&mut value, &vars).unwrap();
+        let expected = "a: 8080\nb: 8080/x\nc: \"a: b\\nc: [d]\"\n";
+        assert_eq!(
+            value,
+            serde_yaml::from_str::<serde_yaml::Value>(expected).unwrap()
+        );
+    }
+
+    #[test]
+    fn load_env_should_merge_the_overlay() {
+        let tmp = project(
+            &[
+                (
+                    "config.yml",
+                    "name: test\nmiddleware: [auth]\nroutes:\n  /users:\n    - method: GET\n      handler: users\n      timeout: 1000\n",
+                ),
+                (
+                    "config.prod.yml",
+                    "middleware: ~\nroutes:\n  /health:\n    - method: GET\n      handler: health\n",
+                ),
+                ("config.bad.yml", "name: test\nroutes:\n  /x:\n    - method: [GET]\n"),
+            ],
+        );
+        let dir = tmp.path();
+        let config = ProjectConfig::load_in(dir.join("config.yml"), Some("prod"), dir).unwrap();
+        assert!(config.middleware.is_empty());
+        let mut paths = config.routes.keys().collect::<Vec<_>>();
+        paths.sort();
+        // routes of the overlay are merged path by path
+        assert_eq!(paths, ["/health", "/users"]);
+        assert_eq!(config.routes["/users"][0].settings.timeout, Some(1000));
+
+        let err = ProjectConfig::load_in(dir.join("config.yml"), Some("bad"), dir).unwrap_err();
+        let err = format!("{err:#}");
+        let bad = dir.join("config.bad.yml");
+        assert!(
+            err.starts_with(&format!("invalid config {}: ", bad.display())),
+            "{err}"
+        );
+        assert!(err.contains("line 4"), "{err}");
+        assert!(ProjectConfig::load_in(dir.join("config.yml"), Some("dev"), dir).is_err());
+        assert_eq!(
+            overlay_path(Path::new(".build/abc.yml"), "prod"),
+            Path::new(".build/abc.prod.yml")
+        );
+    }
+
+    #[test]
+    fn load_should_detect_the_format() {
+        let tmp = project(
+            &[
+                (
+                    "config.json",
+                    r#"{
+  "$schema": "./config.schema.json",
+  "name": "test",
+  "routes": { "/users": [{ "method": "GET", "handler": "users" }] },
+  "include": ["api.toml"]
+}"#,
+                ),
+                (
+                    "api.toml",
+                    "prefix = \"/api\"\n\n[[routes.\"/users\"]]\nmethod = \"POST\"\nhandler = \"createUser\"\n\n[[routes.\"/items\"]]\nmethod = [\"GET\", \"PUT\"]\nproxy = \"http://backend/items\"\n",
+                ),
+                ("config.toml", "name = \"test\"\n\n[[routes.\"/users\"]]\nmethod = \"GET\"\n"),
+            ],
+        );
+        let dir = tmp.path();
+        let config = ProjectConfig::load_in(dir.join("config.json"), None, dir).unwrap();
+        let mut paths = config.routes.keys().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths, ["/api/items", "/api/users", "/users"]);
+        assert_eq!(
+            config.routes["/api/items"][0].action.to_string(),
+            "proxy to http://backend/items"
+        );
+
+        let err = ProjectConfig::load_in(dir.join("config.toml"), None, dir).unwrap_err();
+        assert!(format!("{err:#}").contains("line 3"), "{err:#}");
+        let err = ProjectConfig::find(dir).unwrap_err();
+        assert!(
+            err.to_string().starts_with("only one config is allowed"),
+            "{err}"
+        );
+        assert!(ConfigFormat::from_path("config.ini").is_err());
+    }
+
+    #[test]
+    fn schema_should_validate_configs() {
+        let schema = serde_json::from_str(CONFIG_SCHEMA).unwrap();
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        let config = r#"
+name: test
+middleware: [auth]
+routes:
+  /users/{id:int}:
+    - method: [GET, PUT]
+      handler: user
+      timeout: 1000
+      cors: { origins: ["https://example.com"], credentials: true }
+      schema: { params: schemas/params.json, body: { type: object } }
+  /old:
+    - method: ANY
+      redirect: { to: /new, status: 301 }
+groups:
+  - prefix: /api
+    include: [api.yml]
+schedules:
+  cleanup: { cron: "0 * * * * *", handler: cleanup, overlap: allow }
+queues:
+  emails: { handler: sendEmail }
+openapi: { path: /openapi.json }
+rate_limits:
+  - { requests: 100, key: { header: x-api-key }, per_route: true }
+  - { requests: 1000, window: 3600 }
+compression: { encodings: [zstd, gzip], min_size: 256 }
+response_cache: { dir: .ceno/cache, admin: { path: /_cache, token: secret } }
+access_log: { format: json, file: logs/access.log }
+"#;
+        let value: serde_json::Value = serde_yaml::from_str(config).unwrap();
+        assert!(validator.is_valid(&value));
+        // as printed by `ceno config print`
+        let loaded: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        assert!(validator.is_valid(&serde_json::to_value(&loaded).unwrap()));
+
+        let invalid = [
+            "routes: {}",
+            "name: test\nroutes:\n  /x:\n    - method: GET\n      handler: x\n      rewrite: /y",
+            "name: test\nroutes:\n  /x:\n    - method: GET\n      handlr: x",
+            "name: test\nqueues:\n  emails: { retries: 1 }",
+        ];
+        for config in invalid {
+            let value: serde_json::Value = serde_yaml::from_str(config).unwrap();
+            assert!(!validator.is_valid(&value), "{config}");
+        }
+    }
+
+    #[test]
+    fn join_path_should_work() {
+        assert_eq!(join_path("", ""), "/");
+        assert_eq!(join_path("", "/users"), "/users");
+        assert_eq!(join_path("/api/", "/"), "/api");
+        assert_eq!(join_path("/api", "users"), "/api/users");
     }
 }
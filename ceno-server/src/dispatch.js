@@ -0,0 +1,45 @@
+// Route a request to a handler through the configured middleware, see `engine.rs`
+(function (handlers, middleware) {
+  const resolve = (name) => {
+    if (typeof handlers[name] !== "function") {
+      throw new TypeError(`middleware ${name} is not an exported function`);
+    }
+    return handlers[name];
+  };
+  const global = middleware.map(resolve);
+
+  // promises passed to `ctx.waitUntil` which aren't settled yet, and the
+  // errors of the rejected ones, see `JsWorker::drain`
+  let pending = 0;
+  const errors = [];
+
+  function waitUntil(promise) {
+    pending++;
+    Promise.resolve(promise)
+      .catch((e) => {
+        errors.push(e instanceof Error ? `${e.name}: ${e.message}` : String(e));
+      })
+      .finally(() => {
+        pending--;
+      });
+  }
+
+  // handlers get the request and `ctx`, each middleware gets the request,
+  // `next` and `ctx`, where `next` runs the rest of the chain with either the
+  // given request or the original one. The middleware of the route runs
+  // inside the global one.
+  async function dispatch(name, req, route = []) {
+    const handler = handlers[name];
+    if (typeof handler !== "function") {
+      throw new TypeError(`handler ${name} is not an exported function`);
+    }
+    const chain = route.length ? global.concat(route.map(resolve)) : global;
+    const ctx = Object.freeze({ waitUntil });
+    const call = (i, req) =>
+      i === chain.length ? handler(req, ctx) : chain[i](req, (next = req) => call(i + 1, next), ctx);
+    return call(0, req);
+  }
+
+  dispatch.background = () => ({ pending, errors: errors.splice(0) });
+  return dispatch;
+})
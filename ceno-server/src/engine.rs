@@ -1,10 +1,19 @@
-use std::collections::HashMap;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use crate::{builtins, bytecode::eval_bytecode, services, Services};
+use anyhow::{anyhow, Result};
 use axum::{body::Body, response::Response};
 use ceno_macros::{FromJs, IntoJs};
-use rquickjs::{Context, Function, Object, Promise, Runtime};
-use tracing::{info_span, instrument};
+use rquickjs::{
+    module::Declared, CatchResultExt, Context, Ctx, Function, Module, Object, Promise, Runtime,
+    Value,
+};
+use serde::Serialize;
+use tracing::{info_span, instrument, warn};
 use ts_rs::TS;
 use typed_builder::TypedBuilder;
 
@@ -13,8 +22,35 @@ pub struct JsWorker {
     ctx: Context,
 }
 
+/// The bundled handler code, optionally along with its precompiled bytecode
+/// which is preferred since it skips parsing on worker startup.
+#[derive(Debug, Clone, Default)]
+pub struct JsCode {
+    pub source: String,
+    pub format: JsFormat,
+    /// exported functions wrapping every handler, outermost first
+    pub middleware: Vec<String>,
+    pub bytecode: Option<Vec<u8>>,
+}
+
+/// How the handlers are exposed by the bundled code
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JsFormat {
+    /// A script whose completion value is the handlers object
+    #[default]
+    Iife,
+    /// An ES module whose named exports are the handlers
+    Es,
+}
+
+/// Name of the bundled ES module, also used as `import.meta.url`
+pub(crate) const MODULE_NAME: &str = "main.mjs";
+
 #[derive(Debug, TypedBuilder, TS, IntoJs)]
 pub struct Req {
+    /// the `x-request-id` of the request
+    #[builder(default)]
+    pub id: Option<String>,
     #[builder(setter(into))]
     pub method: String,
     #[builder(setter(into))]
@@ -22,13 +58,24 @@ pub struct Req {
     #[builder(default)]
     pub query: HashMap<String, String>,
     #[builder(default)]
-    pub params: HashMap<String, String>,
+    #[ts(type = "Record<string, string | number | boolean>")]
+    pub params: HashMap<String, ParamValue>,
     #[builder(default)]
     pub headers: HashMap<String, String>,
     #[builder(default)]
     pub body: Option<String>,
 }
 
+/// A route param, converted according to its type in the route path
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum ParamValue {
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
 #[derive(Debug, TS, FromJs)]
 pub struct Res {
     pub status: u16,
@@ -50,13 +97,114 @@ impl From<Res> for Response {
     }
 }
 
+impl<'js> rquickjs::IntoJs<'js> for ParamValue {
+    fn into_js(self, ctx: &Ctx<'js>) -> rquickjs::Result<Value<'js>> {
+        match self {
+            ParamValue::String(v) => v.into_js(ctx),
+            ParamValue::Int(v) => Ok(Value::new_number(ctx.clone(), v as f64)),
+            ParamValue::Float(v) => Ok(Value::new_number(ctx.clone(), v)),
+            ParamValue::Bool(v) => Ok(Value::new_bool(ctx.clone(), v)),
+        }
+    }
+}
+
+thread_local! {
+    /// id of the request the worker on this thread runs, or ran last, so
+    /// `waitUntil` work logs it too
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
 fn print(msg: String) {
-    println!("{msg}");
+    REQUEST_ID.with_borrow(|id| match id {
+        Some(id) => println!("[{id}] {msg}"),
+        None => println!("{msg}"),
+    });
+}
+
+impl JsCode {
+    pub fn new(source: impl Into<String>) -> Self {
+        Self {
+            source: source.into(),
+            format: JsFormat::default(),
+            middleware: Vec::new(),
+            bytecode: None,
+        }
+    }
+
+    pub fn with_format(mut self, format: JsFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_middleware(mut self, middleware: Vec<String>) -> Self {
+        self.middleware = middleware;
+        self
+    }
+
+    /// Attach bytecode produced by [`compile_bytecode`](crate::compile_bytecode) from `source`
+    pub fn with_bytecode(mut self, bytecode: Vec<u8>) -> Self {
+        self.bytecode = Some(bytecode);
+        self
+    }
+}
+
+impl From<&str> for JsCode {
+    fn from(source: &str) -> Self {
+        Self::new(source)
+    }
+}
+
+impl From<String> for JsCode {
+    fn from(source: String) -> Self {
+        Self::new(source)
+    }
 }
 
 impl JsWorker {
-    #[instrument]
+    /// Create a worker by evaluating the bundled IIFE source
+    #[instrument(skip(module))]
     pub fn try_new(module: &str) -> Result<Self> {
+        Self::init(&Services::default(), |ctx| Ok(ctx.eval(module)?))
+    }
+
+    /// Create a worker by evaluating the bundled ES module source
+    #[instrument(skip(module))]
+    pub fn try_new_module(module: &str) -> Result<Self> {
+        Self::init(&Services::default(), |ctx| load_module(ctx, module))
+    }
+
+    /// Create a worker from `code`, falling back to its source if the
+    /// bytecode can't be loaded, e.g. it was built by another ceno version.
+    #[instrument(skip_all)]
+    pub fn try_load(code: &JsCode, services: &Services) -> Result<Self> {
+        let loaded = code.bytecode.as_ref().and_then(|bytecode| {
+            // `JsCode::with_bytecode` requires the bytecode to come from `compile_bytecode`
+            Self::init(services, |ctx| unsafe {
+                eval_bytecode(ctx, bytecode, code.format)
+            })
+            .inspect_err(|e| warn!("load bytecode failed, fallback to source: {}", e))
+            .ok()
+        });
+        let worker = match (loaded, code.format) {
+            (Some(worker), _) => worker,
+            (None, JsFormat::Iife) => {
+                Self::init(services, |ctx| Ok(ctx.eval(code.source.as_str())?))?
+            }
+            (None, JsFormat::Es) => Self::init(services, |ctx| load_module(ctx, &code.source))?,
+        };
+        if !code.middleware.is_empty() {
+            worker
+                .ctx
+                .with(|ctx| set_dispatch(&ctx, &code.middleware))?;
+        }
+        Ok(worker)
+    }
+
+    /// Set up a runtime whose handlers are the object returned by `load`
+    fn init<F>(services: &Services, load: F) -> Result<Self>
+    where
+        F: for<'js> FnOnce(&Ctx<'js>) -> Result<Object<'js>>,
+    {
         let span = info_span!("init runtime");
         let _enter = span.enter();
 
@@ -69,9 +217,13 @@ impl JsWorker {
         let _enter = span.enter();
 
         ctx.with(|ctx| {
+            builtins::init(&ctx)?;
+            services::init(&ctx, services)?;
+
             let global = ctx.globals();
-            let ret: Object = ctx.eval(module)?;
+            let ret = load(&ctx)?;
             global.set("handlers", ret)?;
+            set_dispatch(&ctx, &[])?;
             // setup print function
             let fun = Function::new(ctx.clone(), print)?.with_name("rust_print")?;
             global.set("rust_print", fun)?;
@@ -82,19 +234,148 @@ impl JsWorker {
         Ok(Self { ctx })
     }
 
-    #[instrument(skip(self))]
     pub fn run(&self, name: &str, req: Req) -> anyhow::Result<Res> {
+        self.run_with(name, &[], req)
+    }
+
+    /// Run the handler `name` through `middleware` as well, inside the global one
+    #[instrument(name = "run", skip(self))]
+    pub fn run_with(&self, name: &str, middleware: &[String], req: Req) -> anyhow::Result<Res> {
+        REQUEST_ID.set(req.id.clone());
+        crate::trace::reset();
         self.ctx.with(|ctx| {
             let global = ctx.globals();
-            let handlers: Object = global.get("handlers")?;
-            let fun: Function = handlers.get(name)?;
-            let v: Promise = fun.call((req,))?;
+            let dispatch: Function = global.get("dispatch")?;
+
+            dispatch
+                .call::<_, Promise>((name, req, middleware.to_vec()))
+                .and_then(|v| v.finish())
+                .catch(&ctx)
+                .map_err(|e| anyhow!("run handler {name} failed: {e}"))
+        })
+    }
+
+    /// Drive the promises passed to `ctx.waitUntil` after the response is sent,
+    /// until they are settled or `timeout` elapses. Rejections are logged.
+    ///
+    /// Promises left behind, e.g. after a timeout, are abandoned, though their
+    /// jobs may still run while the next request is handled.
+    pub fn drain(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let pending = self.ctx.with(|ctx| {
+                let dispatch: Function = ctx.globals().get("dispatch")?;
+                let background: Function = dispatch.get("background")?;
+                let state: Object = background.call(())?;
+                for error in state.get::<_, Vec<String>>("errors")? {
+                    warn!("waitUntil promise rejected: {}", error);
+                }
+                state.get::<_, usize>("pending")
+            })?;
+
+            if pending == 0 {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(anyhow!(
+                    "{pending} waitUntil promises not settled in {timeout:?}"
+                ));
+            }
+            // nothing left to run means the promises can never settle
+            if !self.run_pending_jobs(deadline)? {
+                return Err(anyhow!("{pending} waitUntil promises never settle"));
+            }
+        }
+    }
+
+    /// Run queued promise jobs until there is none or `deadline` is reached,
+    /// returns `false` if there was nothing to run
+    fn run_pending_jobs(&self, deadline: Instant) -> Result<bool> {
+        let rt = self.ctx.runtime();
+        let mut ran = false;
+        while Instant::now() < deadline {
+            match rt.execute_pending_job() {
+                Ok(true) => ran = true,
+                Ok(false) => break,
+                Err(e) => warn!("waitUntil job failed: {}", e),
+            }
+        }
+        Ok(ran)
+    }
+
+    /// Bytes allocated by the runtime
+    pub fn heap_size(&self) -> u64 {
+        self.ctx.runtime().memory_usage().malloc_size.max(0) as u64
+    }
+
+    /// Call the exported `onInit` if any, once the worker has started
+    pub fn on_init(&self) -> Result<()> {
+        self.call_hook("onInit")
+    }
+
+    /// Call the exported `onShutdown` if any, before the worker exits
+    pub fn on_shutdown(&self) -> Result<()> {
+        self.call_hook("onShutdown")
+    }
 
-            Ok::<_, anyhow::Error>(v.finish()?)
+    /// Call the lifecycle hook `name`, waiting for it to settle if it returns a promise
+    fn call_hook(&self, name: &str) -> Result<()> {
+        self.ctx.with(|ctx| {
+            let handlers: Object = ctx.globals().get("handlers")?;
+            let Some(hook) = handlers.get::<_, Option<Function>>(name)? else {
+                return Ok(());
+            };
+
+            hook.call::<_, Value>(())
+                .and_then(|v| match v.into_promise() {
+                    Some(promise) => promise.finish(),
+                    None => Ok(()),
+                })
+                .catch(&ctx)
+                .map_err(|e| anyhow!("{name} failed: {e}"))
         })
     }
 }
 
+/// Install the global `dispatch` running handlers through `middleware`
+fn set_dispatch(ctx: &Ctx<'_>, middleware: &[String]) -> Result<()> {
+    let setup: Function = ctx.eval(include_str!("dispatch.js"))?;
+    let handlers: Object = ctx.globals().get("handlers")?;
+    let dispatch: Function = setup
+        .call((handlers, middleware.to_vec()))
+        .catch(ctx)
+        .map_err(|e| anyhow!("setup middleware failed: {e}"))?;
+    ctx.globals().set("dispatch", dispatch)?;
+    Ok(())
+}
+
+fn load_module<'js>(ctx: &Ctx<'js>, source: &str) -> Result<Object<'js>> {
+    let module = Module::declare(ctx.clone(), MODULE_NAME, source)
+        .catch(ctx)
+        .map_err(|e| anyhow!("compile module failed: {e}"))?;
+    eval_module(ctx, module)
+}
+
+/// Evaluate a declared module and return its namespace once top-level await settles
+pub(crate) fn eval_module<'js>(
+    ctx: &Ctx<'js>,
+    module: Module<'js, Declared>,
+) -> Result<Object<'js>> {
+    let meta = module.meta()?;
+    meta.set("url", format!("file:///{MODULE_NAME}"))?;
+    meta.set("main", true)?;
+
+    let namespace = module
+        .eval()
+        .and_then(|(module, promise)| {
+            promise.finish::<()>()?;
+            module.namespace()
+        })
+        .catch(ctx)
+        .map_err(|e| anyhow!("eval module failed: {e}"))?;
+    Ok(namespace)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -125,4 +406,182 @@ mod tests {
         let ret = worker.run("hello", req).unwrap();
         assert_eq!(ret.status, 200);
     }
+
+    #[test]
+    fn js_worker_should_load_bytecode() {
+        let code = r#"
+    (function(){
+        async function hello(req){
+            return { status: 201, headers: {}, body: req.url };
+        }
+        return{hello:hello};
+    })();
+    "#;
+        let bytecode = crate::compile_bytecode(&code.into()).unwrap();
+        for bytecode in [bytecode, b"stale bytecode".to_vec()] {
+            let code = JsCode::new(code).with_bytecode(bytecode);
+            let worker = JsWorker::try_load(&code, &Services::default()).unwrap();
+            let req = Req::builder().method("GET").url("/hello").build();
+            let ret = worker.run("hello", req).unwrap();
+            assert_eq!(ret.status, 201);
+            assert_eq!(ret.body.as_deref(), Some("/hello"));
+        }
+    }
+
+    #[test]
+    fn js_worker_should_run_module() {
+        let code = r#"
+    const greeting = await Promise.resolve("hello");
+    export async function hello(req) {
+        return { status: 200, headers: {}, body: `${greeting} ${req.url} ${import.meta.main}` };
+    }
+    export const notHandler = 1;
+    "#;
+        let code = JsCode::new(code).with_format(JsFormat::Es);
+        let bytecode = crate::compile_bytecode(&code).unwrap();
+        for code in [code.clone(), code.with_bytecode(bytecode)] {
+            let worker = JsWorker::try_load(&code, &Services::default()).unwrap();
+            let req = Req::builder().method("GET").url("/hello").build();
+            let ret = worker.run("hello", req).unwrap();
+            assert_eq!(ret.body.as_deref(), Some("hello /hello true"));
+        }
+    }
+
+    #[test]
+    fn js_worker_should_report_module_error() {
+        let code =
+            JsCode::new("await Promise.reject(new Error('boom'));").with_format(JsFormat::Es);
+        let err = JsWorker::try_load(&code, &Services::default())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("boom"));
+    }
+
+    #[test]
+    fn js_worker_should_run_middleware() {
+        let code = r#"
+    let calls = [];
+    export async function hello(req) {
+        return { status: 200, headers: {}, body: [...calls, req.headers.user].join(",") };
+    }
+    export async function auth(req, next) {
+        calls.push("auth");
+        if (!req.headers.token) {
+            return { status: 401, headers: {}, body: null };
+        }
+        return next({ ...req, headers: { ...req.headers, user: "alice" } });
+    }
+    export async function timing(req, next) {
+        calls = ["timing"];
+        const res = await next();
+        res.headers["x-timing"] = "1";
+        return res;
+    }
+    "#;
+        let code = JsCode::new(code)
+            .with_format(JsFormat::Es)
+            .with_middleware(vec!["timing".into(), "auth".into()]);
+        let worker = JsWorker::try_load(&code, &Services::default()).unwrap();
+
+        let req = Req::builder().method("GET").url("/hello").build();
+        let ret = worker.run("hello", req).unwrap();
+        assert_eq!(ret.status, 401);
+        assert_eq!(ret.headers["x-timing"], "1");
+
+        let headers = HashMap::from([("token".to_string(), "t".to_string())]);
+        let req = Req::builder()
+            .method("GET")
+            .url("/hello")
+            .headers(headers)
+            .build();
+        let ret = worker.run("hello", req).unwrap();
+        assert_eq!(ret.body.as_deref(), Some("timing,auth,alice"));
+
+        // the middleware of the route runs inside the global one
+        let code = code.clone().with_middleware(vec!["timing".into()]);
+        let worker = JsWorker::try_load(&code, &Services::default()).unwrap();
+        let req = Req::builder().method("GET").url("/hello").build();
+        let ret = worker.run_with("hello", &["auth".into()], req).unwrap();
+        assert_eq!((ret.status, ret.headers["x-timing"].as_str()), (401, "1"));
+        let req = Req::builder().method("GET").url("/hello").build();
+        assert!(worker.run_with("hello", &["missing".into()], req).is_err());
+
+        let req = Req::builder().method("GET").url("/missing").build();
+        assert!(worker.run("missing", req).is_err());
+
+        let code = code.with_middleware(vec!["missing".into()]);
+        assert!(JsWorker::try_load(&code, &Services::default()).is_err());
+    }
+
+    #[test]
+    fn js_worker_should_call_lifecycle_hooks() {
+        let code = r#"
+    let state = "created";
+    export async function onInit() {
+        state = await Promise.resolve("ready");
+    }
+    export function onShutdown() {
+        if (state !== "ready") throw new Error(`shutdown in ${state}`);
+        state = "closed";
+    }
+    export async function hello(req) {
+        return { status: 200, headers: {}, body: state };
+    }
+    "#;
+        let worker = JsWorker::try_new_module(code).unwrap();
+        worker.on_init().unwrap();
+        let req = Req::builder().method("GET").url("/hello").build();
+        assert_eq!(
+            worker.run("hello", req).unwrap().body.as_deref(),
+            Some("ready")
+        );
+        worker.on_shutdown().unwrap();
+        let err = worker.on_shutdown().unwrap_err();
+        assert!(err.to_string().contains("shutdown in closed"), "{err}");
+
+        // hooks are optional
+        let worker = JsWorker::try_new_module("export const hello = 1;").unwrap();
+        assert!(worker.on_init().is_ok() && worker.on_shutdown().is_ok());
+    }
+
+    #[test]
+    fn js_worker_should_drain_wait_until() {
+        let code = r#"
+    let log = [];
+    async function write(entry) {
+        for (let i = 0; i < 3; i++) await null;
+        log.push(entry);
+    }
+    export async function hello(req, ctx) {
+        ctx.waitUntil(write(req.url));
+        ctx.waitUntil(Promise.reject(new Error("cache down")));
+        return { status: 200, headers: {}, body: log.join(",") };
+    }
+    export async function stuck(req, ctx) {
+        ctx.waitUntil(new Promise(() => {}));
+        return { status: 200, headers: {}, body: null };
+    }
+    export async function spin(req, ctx) {
+        ctx.waitUntil((async () => { while (true) await null; })());
+        return { status: 200, headers: {}, body: null };
+    }
+    "#;
+        let worker = JsWorker::try_new_module(code).unwrap();
+        for (url, body) in [("/a", ""), ("/b", "/a")] {
+            let req = Req::builder().method("GET").url(url).build();
+            let ret = worker.run("hello", req).unwrap();
+            assert_eq!(ret.body.as_deref(), Some(body));
+            worker.drain(Duration::from_secs(1)).unwrap();
+        }
+
+        let req = Req::builder().method("GET").url("/").build();
+        worker.run("stuck", req).unwrap();
+        let err = worker.drain(Duration::from_secs(1)).unwrap_err();
+        assert!(err.to_string().contains("never settle"), "{err}");
+
+        let req = Req::builder().method("GET").url("/").build();
+        worker.run("spin", req).unwrap();
+        let err = worker.drain(Duration::from_millis(50)).unwrap_err();
+        assert!(err.to_string().contains("not settled"), "{err}");
+    }
 }
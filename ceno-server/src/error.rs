@@ -1,7 +1,10 @@
+use crate::{middleware::RateLimitStatus, router::allow_header, schema::SchemaViolation};
 use axum::{
-    http::{Method, StatusCode},
+    http::{header, HeaderValue, Method, StatusCode},
     response::{IntoResponse, Response},
+    Json,
 };
+use serde_json::json;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -12,8 +15,45 @@ pub enum AppError {
     #[error("Path not found: {0}")]
     RoutePathNotFound(String),
 
-    #[error("Method not found: {0}")]
-    RouteMethodNotAllowed(Method),
+    /// the param and the type it should have
+    #[error("Invalid route param {0}, expected {1}")]
+    RouteParamInvalid(String, String),
+
+    /// the method and the ones allowed on the path
+    #[error("Method not allowed: {0}")]
+    RouteMethodNotAllowed(Method, Vec<Method>),
+
+    /// the part of the request and why it's invalid
+    #[error("Invalid request {0}")]
+    RequestInvalid(&'static str, Vec<SchemaViolation>),
+
+    #[error("Invalid response body")]
+    ResponseInvalid(Vec<SchemaViolation>),
+
+    #[error("Payload too large, the limit is {0} bytes")]
+    PayloadTooLarge(usize),
+
+    #[error("Unauthorized, {0} authorization required")]
+    Unauthorized(String),
+
+    #[error("Too many requests, retry after {}s", .0.reset)]
+    TooManyRequests(RateLimitStatus),
+
+    #[error("Handler timed out after {0}ms")]
+    Timeout(u64),
+
+    #[error("CORS request not allowed from origin: {0}")]
+    CorsForbidden(String),
+
+    #[error("Handler failed: {0}")]
+    HandlerFailed(String),
+
+    #[error("Upstream failed: {0}")]
+    BadGateway(String),
+
+    /// the path which kept being rewritten
+    #[error("Too many rewrites of {0}")]
+    RewriteLoop(String),
 
     #[error("Anyhow error: {0}")]
     Anyhow(#[from] anyhow::Error),
@@ -24,14 +64,48 @@ pub enum AppError {
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let code = match self {
+        let code = match &self {
             AppError::HostNotFound(_) => StatusCode::NOT_FOUND,
             AppError::RoutePathNotFound(_) => StatusCode::NOT_FOUND,
-            AppError::RouteMethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::RouteParamInvalid(..) => StatusCode::BAD_REQUEST,
+            AppError::RouteMethodNotAllowed(..) => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::RequestInvalid(..) => StatusCode::BAD_REQUEST,
+            AppError::ResponseInvalid(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::CorsForbidden(_) => StatusCode::FORBIDDEN,
+            AppError::HandlerFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            AppError::RewriteLoop(_) => StatusCode::LOOP_DETECTED,
             AppError::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::Serde(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
-        (code, self.to_string()).into_response()
+        let mut res = match &self {
+            AppError::RequestInvalid(_, details) | AppError::ResponseInvalid(details) => {
+                let body = json!({ "error": self.to_string(), "details": details });
+                (code, Json(body)).into_response()
+            }
+            _ => (code, self.to_string()).into_response(),
+        };
+        match &self {
+            AppError::RouteMethodNotAllowed(_, allow) => {
+                res.headers_mut().insert(header::ALLOW, allow_header(allow));
+            }
+            AppError::Unauthorized(scheme) => {
+                if let Ok(value) = HeaderValue::from_str(scheme) {
+                    res.headers_mut().insert(header::WWW_AUTHENTICATE, value);
+                }
+            }
+            AppError::TooManyRequests(status) => {
+                status.insert_headers(res.headers_mut());
+                res.headers_mut()
+                    .insert(header::RETRY_AFTER, status.reset.into());
+            }
+            _ => {}
+        }
+        res
     }
 }
@@ -0,0 +1,468 @@
+use crate::{
+    config::{HeaderRewrite, Proxy, Redirect, RouteAction, RouteSettings},
+    policy, trace, AppError, ParamValue,
+};
+use anyhow::{bail, Result};
+use axum::{
+    body::Body,
+    http::{
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+        request::Parts,
+        StatusCode, Uri,
+    },
+    response::Response,
+};
+use http_body_util::{LengthLimitError, Limited};
+use percent_encoding::percent_decode_str;
+use std::{
+    borrow::Cow, collections::HashMap, error::Error as _, net::IpAddr, sync::OnceLock,
+    time::Duration,
+};
+use tracing::Span;
+
+/// Rewrites a request may go through before reaching a route answering it
+pub(crate) const MAX_REWRITES: usize = 8;
+
+/// Headers of a single connection, which a proxy must not forward
+const HOP_BY_HOP: [HeaderName; 7] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+];
+
+/// `{name}` placeholders of a template
+pub(crate) fn template_params(template: &str) -> Vec<&str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|s| s.split_once('}').map(|(name, _)| name))
+        .collect()
+}
+
+/// Replace the placeholders of `template` with the params, missing ones,
+/// like an absent optional param, are left empty
+pub(crate) fn render(template: &str, params: &HashMap<String, ParamValue>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some((head, tail)) = rest.split_once('{') {
+        let Some((name, tail)) = tail.split_once('}') else {
+            break;
+        };
+        rendered.push_str(head);
+        match params.get(name) {
+            Some(ParamValue::String(v)) => rendered.push_str(v),
+            Some(ParamValue::Int(v)) => rendered.push_str(&v.to_string()),
+            Some(ParamValue::Float(v)) => rendered.push_str(&v.to_string()),
+            Some(ParamValue::Bool(v)) => rendered.push_str(&v.to_string()),
+            None => {}
+        }
+        rest = tail;
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Turn away the params of a proxy url with `.` or `..` segments, which
+/// the url would resolve out of the path of the upstream
+fn check_segments(template: &str, params: &HashMap<String, ParamValue>) -> Result<(), AppError> {
+    for name in template_params(template) {
+        let Some(ParamValue::String(value)) = params.get(name) else {
+            continue;
+        };
+        // `%2e` is a dot, and `\` a slash, to the url as well
+        let value = percent_decode_str(value).decode_utf8_lossy();
+        if value.split(['/', '\\']).any(|s| s == "." || s == "..") {
+            return Err(AppError::RouteParamInvalid(
+                name.to_string(),
+                "a path without . or .. segments".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The rendered `target` along with the query of the request, unless it
+/// has one of its own
+fn with_query(mut target: String, query: Option<&str>) -> String {
+    if let Some(query) = query.filter(|_| !target.contains('?')) {
+        target.push('?');
+        target.push_str(query);
+    }
+    target
+}
+
+/// Check a redirect, rewrite or proxy route, whose templates may only use
+/// the params of its path
+pub(crate) fn validate(
+    action: &RouteAction,
+    settings: &RouteSettings,
+    params: &[&str],
+) -> Result<()> {
+    let mut templates = Vec::new();
+    match action {
+        RouteAction::Handler(_) => {}
+        RouteAction::Redirect(redirect) => {
+            if !matches!(redirect.status, 301 | 302 | 303 | 307 | 308) {
+                bail!("invalid redirect status {}", redirect.status);
+            }
+            templates.push(redirect.to.as_str());
+        }
+        RouteAction::Rewrite(to) => {
+            if !to.starts_with('/') {
+                bail!("rewrite {to} must be a path");
+            }
+            // auth, rate_limit and middleware apply on the way, the rest
+            // only on the route answering the request
+            let unused = [
+                ("timeout", settings.timeout.is_some()),
+                ("max_body", settings.max_body.is_some()),
+                ("cors", settings.cors.is_some()),
+                ("cache", settings.cache.is_some()),
+                ("schema", settings.schema.is_some()),
+            ];
+            if let Some((name, _)) = unused.into_iter().find(|(_, set)| *set) {
+                bail!("rewrite {to} can't have {name}, set it on the route answering it");
+            }
+            templates.push(to.as_str());
+        }
+        RouteAction::Proxy(proxy) => {
+            if !proxy.url.starts_with("http://") && !proxy.url.starts_with("https://") {
+                bail!("proxy url {} must be http or https", proxy.url);
+            }
+            templates.push(proxy.url.as_str());
+            for rewrite in [&proxy.headers, &proxy.response_headers] {
+                for name in rewrite.set.keys().chain(&rewrite.remove) {
+                    if HeaderName::try_from(name.as_str()).is_err() {
+                        bail!("invalid header {name}");
+                    }
+                }
+                templates.extend(rewrite.set.values().map(String::as_str));
+            }
+        }
+    }
+    for name in templates.into_iter().flat_map(template_params) {
+        if !params.contains(&name) {
+            bail!("unknown param {{{name}}}");
+        }
+    }
+    Ok(())
+}
+
+/// Respond with the `Location` of `redirect`
+pub(crate) fn redirect(
+    redirect: &Redirect,
+    params: &HashMap<String, ParamValue>,
+    query: Option<&str>,
+) -> Result<Response, AppError> {
+    let location = render(&redirect.to, params);
+    // `//host` or `/\host` would send the client to another site
+    let off_site = |to: &str| to.starts_with("//") || to.starts_with("/\\");
+    if redirect.to.starts_with('/') && !off_site(&redirect.to) && off_site(&location) {
+        let name = template_params(&redirect.to)
+            .first()
+            .copied()
+            .unwrap_or_default();
+        return Err(AppError::RouteParamInvalid(
+            name.to_string(),
+            "a path on this site".to_string(),
+        ));
+    }
+    let location = with_query(location, query);
+    let location = HeaderValue::try_from(location).map_err(anyhow::Error::from)?;
+    let status = StatusCode::from_u16(redirect.status).map_err(anyhow::Error::from)?;
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = status;
+    res.headers_mut().insert(header::LOCATION, location);
+    Ok(res)
+}
+
+/// The uri a request is rewritten to, keeping its query
+pub(crate) fn rewrite(
+    to: &str,
+    params: &HashMap<String, ParamValue>,
+    uri: &Uri,
+) -> Result<Uri, AppError> {
+    let target = with_query(render(to, params), uri.query());
+    Ok(target.parse::<Uri>().map_err(anyhow::Error::from)?)
+}
+
+/// The middleware of the routes a request was rewritten by, outermost
+/// first, followed by that of the route answering it, each run once
+pub(crate) fn middleware<'a>(hops: &[&'a RouteSettings]) -> Cow<'a, [String]> {
+    match hops {
+        [settings] => Cow::Borrowed(&settings.middleware),
+        _ => {
+            let mut middleware: Vec<String> = Vec::new();
+            for name in hops.iter().flat_map(|settings| &settings.middleware) {
+                if !middleware.contains(name) {
+                    middleware.push(name.clone());
+                }
+            }
+            Cow::Owned(middleware)
+        }
+    }
+}
+
+/// Forward the request to the upstream of `proxy`, streaming the request
+/// body, up to `max_body` of the route, and the response body
+pub(crate) async fn proxy(
+    proxy: &Proxy,
+    params: &HashMap<String, ParamValue>,
+    settings: &RouteSettings,
+    parts: &Parts,
+    client: Option<IpAddr>,
+    body: Body,
+) -> Result<Response, AppError> {
+    let limit = policy::body_limit(settings, parts)?;
+    check_segments(&proxy.url, params)?;
+    let url = with_query(render(&proxy.url, params), parts.uri.query());
+
+    let mut headers = parts.headers.clone();
+    remove_hop_by_hop(&mut headers);
+    if !proxy.preserve_host {
+        // set from the url by the client
+        headers.remove(header::HOST);
+    }
+    forwarded(&mut headers, parts, client);
+    rewrite_headers(&mut headers, &proxy.headers, params);
+    trace::inject(&Span::current(), &mut headers);
+
+    let body = Body::new(Limited::new(body, limit)).into_data_stream();
+    let req = http_client()
+        .request(parts.method.clone(), url)
+        .headers(headers)
+        .body(reqwest::Body::wrap_stream(body));
+    let upstream = match settings.timeout {
+        Some(ms) => tokio::time::timeout(Duration::from_millis(ms), req.send())
+            .await
+            .map_err(|_| AppError::Timeout(ms))?,
+        None => req.send().await,
+    }
+    .map_err(|e| upstream_error(e, limit))?;
+
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = upstream.status();
+    *res.headers_mut() = upstream.headers().clone();
+    remove_hop_by_hop(res.headers_mut());
+    rewrite_headers(res.headers_mut(), &proxy.response_headers, params);
+    *res.body_mut() = Body::from_stream(upstream.bytes_stream());
+    Ok(res)
+}
+
+/// A request body going over the limit fails the request as well
+fn upstream_error(e: reqwest::Error, limit: usize) -> AppError {
+    let mut source = e.source();
+    while let Some(cause) = source {
+        if cause.is::<LengthLimitError>() {
+            return AppError::PayloadTooLarge(limit);
+        }
+        source = cause.source();
+    }
+    AppError::BadGateway(e.to_string())
+}
+
+/// Shared by the proxy routes, redirects of the upstream are passed on
+fn http_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("http client should build")
+    })
+}
+
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    // as well as the ones listed by `Connection`
+    let listed = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::try_from(name.trim()).ok())
+        .collect::<Vec<_>>();
+    for name in HOP_BY_HOP.iter().chain(&listed) {
+        headers.remove(name);
+    }
+}
+
+/// Tell the upstream about the client and the host it asked for
+fn forwarded(headers: &mut HeaderMap, parts: &Parts, client: Option<IpAddr>) {
+    const FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+    const FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+    const FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+    if let Some(client) = client {
+        let value = match headers.get(&FORWARDED_FOR).and_then(|v| v.to_str().ok()) {
+            Some(previous) => format!("{previous}, {client}"),
+            None => client.to_string(),
+        };
+        if let Ok(value) = HeaderValue::try_from(value) {
+            headers.insert(FORWARDED_FOR, value);
+        }
+    }
+    if let Some(host) = parts.headers.get(header::HOST) {
+        headers.insert(FORWARDED_HOST, host.clone());
+    }
+    if !headers.contains_key(&FORWARDED_PROTO) {
+        headers.insert(FORWARDED_PROTO, HeaderValue::from_static("http"));
+    }
+}
+
+fn rewrite_headers(
+    headers: &mut HeaderMap,
+    rewrite: &HeaderRewrite,
+    params: &HashMap<String, ParamValue>,
+) {
+    // names are checked when the route is loaded
+    for name in &rewrite.remove {
+        headers.remove(name.as_str());
+    }
+    for (name, value) in &rewrite.set {
+        let (Ok(name), Ok(value)) = (
+            HeaderName::try_from(name.as_str()),
+            HeaderValue::try_from(render(value, params)),
+        ) else {
+            continue;
+        };
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_should_fill_params() {
+        let params = HashMap::from([
+            ("id".to_string(), ParamValue::Int(7)),
+            ("rest".to_string(), ParamValue::String("a/b".into())),
+        ]);
+        assert_eq!(render("/users/{id}/{rest}", &params), "/users/7/a/b");
+        assert_eq!(render("/x/{page}", &params), "/x/");
+        assert_eq!(template_params("http://up/{id}?p={page}"), ["id", "page"]);
+        assert_eq!(with_query("/a".into(), Some("q=1")), "/a?q=1");
+        assert_eq!(with_query("/a?b=2".into(), Some("q=1")), "/a?b=2");
+    }
+
+    #[test]
+    fn proxy_should_reject_dot_segments() {
+        let param =
+            |rest: &str| HashMap::from([("rest".to_string(), ParamValue::String(rest.into()))]);
+        let url = "http://backend/public/{rest}";
+        for rest in ["..", "../x", "a/./b", "..%2Fsecret", "%2e%2E/x", "a\\..\\x"] {
+            assert!(
+                matches!(
+                    check_segments(url, &param(rest)),
+                    Err(AppError::RouteParamInvalid(name, _)) if name == "rest"
+                ),
+                "{rest}"
+            );
+        }
+        for rest in ["a/b", "..a", "a..", "v1.2/x.json"] {
+            assert!(check_segments(url, &param(rest)).is_ok(), "{rest}");
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_should_stop_bodies_over_the_limit() {
+        let app =
+            axum::Router::new().route("/", axum::routing::post(|_: axum::body::Bytes| async {}));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let to: Proxy = serde_json::from_value(serde_json::json!({ "url": url })).unwrap();
+        let settings = RouteSettings {
+            max_body: Some(4),
+            ..Default::default()
+        };
+        let (parts, _) = axum::http::Request::post("/")
+            .body(())
+            .unwrap()
+            .into_parts();
+        // no content length, the limit is hit while streaming
+        let chunks = ["ab", "cd", "ef"].map(Ok::<_, std::io::Error>);
+        let body = Body::from_stream(futures_util::stream::iter(chunks));
+        let res = proxy(&to, &HashMap::new(), &settings, &parts, None, body).await;
+        assert!(matches!(res, Err(AppError::PayloadTooLarge(4))), "{res:?}");
+    }
+
+    #[test]
+    fn redirect_should_set_location() {
+        let params = HashMap::from([("id".to_string(), ParamValue::Int(7))]);
+        let to = Redirect {
+            to: "/users/{id}".into(),
+            status: 301,
+        };
+        let res = redirect(&to, &params, Some("tab=posts")).unwrap();
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers()[header::LOCATION], "/users/7?tab=posts");
+        let settings = RouteSettings::default();
+        let action = |to: Redirect| RouteAction::Redirect(to);
+        assert!(validate(&action(to.clone()), &settings, &["id"]).is_ok());
+        assert!(validate(&action(to.clone()), &settings, &[]).is_err());
+        assert!(validate(&action(Redirect { status: 200, ..to }), &settings, &["id"]).is_err());
+        let rewrite = RouteAction::Rewrite("/users/{id}".into());
+        assert!(validate(&rewrite, &settings, &["id"]).is_ok());
+        let settings = RouteSettings {
+            auth: Some("Bearer".into()),
+            middleware: vec!["auth".into()],
+            ..Default::default()
+        };
+        assert!(validate(&rewrite, &settings, &["id"]).is_ok());
+        let settings = RouteSettings {
+            max_body: Some(1),
+            ..Default::default()
+        };
+        let err = validate(&rewrite, &settings, &["id"]).unwrap_err();
+        assert!(err.to_string().contains("can't have max_body"), "{err}");
+
+        let to = Redirect {
+            to: "/{rest}".into(),
+            status: 301,
+        };
+        let rest =
+            |rest: &str| HashMap::from([("rest".to_string(), ParamValue::String(rest.into()))]);
+        for value in ["/evil.example", "\\evil.example"] {
+            assert!(
+                matches!(redirect(&to, &rest(value), None), Err(AppError::RouteParamInvalid(name, _)) if name == "rest"),
+                "{value}"
+            );
+        }
+        let res = redirect(&to, &rest("new/a"), None).unwrap();
+        assert_eq!(res.headers()[header::LOCATION], "/new/a");
+        let to = Redirect {
+            to: "https://example.com/{rest}".into(),
+            status: 301,
+        };
+        let res = redirect(&to, &rest("/a"), None).unwrap();
+        assert_eq!(res.headers()[header::LOCATION], "https://example.com//a");
+    }
+
+    #[test]
+    fn proxy_headers_should_be_rewritten() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("x-hop"));
+        headers.insert("x-hop", HeaderValue::from_static("1"));
+        headers.insert("cookie", HeaderValue::from_static("a=1"));
+        headers.insert("x-keep", HeaderValue::from_static("1"));
+        remove_hop_by_hop(&mut headers);
+        let rewrite = HeaderRewrite {
+            set: HashMap::from([("x-user".to_string(), "{id}".to_string())]),
+            remove: vec!["cookie".into()],
+        };
+        let params = HashMap::from([("id".to_string(), ParamValue::Int(7))]);
+        rewrite_headers(&mut headers, &rewrite, &params);
+
+        let mut names = headers.keys().map(HeaderName::as_str).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["x-keep", "x-user"]);
+        assert_eq!(headers["x-user"], "7");
+    }
+}
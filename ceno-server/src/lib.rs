@@ -1,41 +1,90 @@
 #![feature(impl_trait_in_assoc_type)]
 
+mod builtins;
+mod bytecode;
+mod cache;
 mod config;
 mod engine;
 mod error;
+mod forward;
+mod metrics;
 mod middleware;
+mod openapi;
+mod params;
+mod policy;
 mod pool;
+mod queue;
 mod router;
+mod scheduler;
+mod schema;
+mod services;
+mod trace;
 
 use anyhow::Result;
 use axum::{
-    body::Bytes,
-    extract::{Host, Query, State},
-    http::{request::Parts, Response},
-    response::IntoResponse,
-    routing::any,
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Host, Query, State},
+    http::{header, request::Parts, Method, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{any, get},
     Router,
 };
 use dashmap::DashMap;
-use matchit::Match;
-use middleware::ServerTimeLayer;
-use std::collections::HashMap;
+use middleware::{
+    AccessLogLayer, CompressionLayer, RateLimitLayer, RequestId, RequestIdLayer, ServerTimeLayer,
+};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
 use tokio::net::TcpListener;
 use tokio::signal;
-use tracing::{info, instrument, Instrument};
+use tracing::{info, instrument, warn, Instrument};
+use typed_builder::TypedBuilder;
 
+pub use bytecode::compile_bytecode;
+pub use cache::ResponseCache;
 pub use config::*;
-pub use engine::{Req, Res};
+pub use engine::{JsCode, JsFormat, JsWorker, ParamValue, Req, Res};
 pub use error::*;
+pub use metrics::Metrics;
+pub use middleware::{MemoryStore, RateLimitStatus, RateLimitStore};
+pub use openapi::openapi;
 pub use pool::*;
+pub use queue::{EnqueueOptions, Job, JobQueue, QueueDispatcher, QUEUE_METHOD};
 pub use router::*;
+pub use scheduler::{Scheduler, SCHEDULED_METHOD};
+pub use services::Services;
 
 #[derive(Clone)]
 pub struct AppState {
     pools: DashMap<String, SwappableThreadPool>,
     routers: DashMap<String, SwappableAppRouter>,
+    options: ServerOptions,
+    /// buckets of the rate limits of the tenants and of their routes
+    rate_limit_store: Arc<dyn RateLimitStore>,
+}
+
+/// Behavior of the server shared by all tenants
+#[derive(Debug, Clone, Default, TypedBuilder)]
+pub struct ServerOptions {
+    /// check successful responses against the `response` schema of their route
+    #[builder(default)]
+    pub validate_responses: bool,
+    /// keeps the buckets of the `rate_limits` of the tenants, in memory if unset
+    #[builder(default, setter(strip_option))]
+    pub rate_limit_store: Option<Arc<dyn RateLimitStore>>,
+    /// counts requests, and is served on `/metrics` of `metrics_port` along
+    /// with the load of the pools, if set
+    #[builder(default, setter(strip_option))]
+    pub metrics: Option<Arc<Metrics>>,
+    /// [`DEFAULT_METRICS_PORT`] if unset, `/metrics` is never taken from
+    /// the tenants
+    #[builder(default, setter(strip_option))]
+    pub metrics_port: Option<u16>,
 }
 
+/// Port of `/metrics` when `metrics_port` isn't set, the one of OpenTelemetry
+/// Prometheus exporters
+pub const DEFAULT_METRICS_PORT: u16 = 9464;
+
 #[derive(Clone)]
 pub struct TenentRouter {
     host: String,
@@ -46,6 +95,7 @@ pub async fn start_server(
     port: u16,
     routers: Vec<TenentRouter>,
     pools: Vec<(String, SwappableThreadPool)>,
+    options: ServerOptions,
 ) -> Result<()> {
     let addr = format!("0.0.0.0:{port}");
     let listener = TcpListener::bind(addr).await?;
@@ -57,21 +107,59 @@ pub async fn start_server(
     for TenentRouter { host, router } in routers {
         map.insert(host, router);
     }
+    let store = options
+        .rate_limit_store
+        .clone()
+        .unwrap_or_else(|| Arc::new(MemoryStore::default()));
+    let tenants = Arc::new(map.clone());
+    let rate_limit = RateLimitLayer::new(tenants.clone(), store.clone());
     for (host, pool) in pools {
         pool_map.insert(host, pool);
     }
-    let state = AppState::new(map, pool_map);
+    let mut state = AppState::new(map, pool_map);
+    state.options = options;
+    state.rate_limit_store = store;
+    let metrics = state.options.metrics.clone();
+    if metrics.is_some() {
+        let port = state.options.metrics_port.unwrap_or(DEFAULT_METRICS_PORT);
+        let listener = TcpListener::bind(format!("0.0.0.0:{port}")).await?;
+        info!("serving metrics on {}", listener.local_addr()?);
+        let app = Router::new()
+            .route("/metrics", get(serve_metrics))
+            .with_state(state.clone());
+        tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+        });
+    }
     let app = Router::new()
+        .route("/", any(handler))
         .route("/*path", any(handler))
-        .layer(ServerTimeLayer)
+        .layer(CompressionLayer::new(tenants.clone()))
+        .layer(rate_limit)
+        .layer(AccessLogLayer::new(tenants.clone()))
+        .layer(ServerTimeLayer::new(tenants, metrics))
+        .layer(RequestIdLayer)
         .with_state(state);
 
-    axum::serve(listener, app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await?;
     Ok(())
 }
 
+async fn serve_metrics(State(state): State<AppState>) -> Response {
+    let Some(metrics) = &state.options.metrics else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let content_type = [(header::CONTENT_TYPE, "text/plain; version=0.0.4")];
+    (content_type, metrics.render(&state.pools)).into_response()
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
@@ -96,34 +184,131 @@ async fn shutdown_signal() {
     }
 }
 
-#[instrument(skip(state))]
+#[instrument(skip(state, connect_info, body))]
 async fn handler(
     State(state): State<AppState>,
-    parts: Parts,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    mut parts: Parts,
     Host(host): Host,
     Query(query): Query<HashMap<String, String>>,
-    body: Option<Bytes>,
-) -> Result<impl IntoResponse, AppError> {
+    body: Body,
+) -> Result<Response, AppError> {
+    let validate_responses = state.options.validate_responses;
+    let store = state.rate_limit_store.clone();
+    // without the port, which doesn't make a tenant of its own
+    let tenant = host.split(':').next().unwrap_or_default().to_string();
     let (router, pool) = get_router_by_host(host, state)?;
-    let matched = router.match_it(parts.method.clone(), parts.uri.path())?;
-    info!(%matched.value, "router matched");
-
-    let req = assemble_req(&matched, &parts, query, body)?;
-    let handler = matched.value;
-    // let worker = JsWorker::try_new(&router.code)?;
-    //
-    // let res = worker.run(handler, req)?;
-    // info!(?res, "run JsWorker");
-
-    let res = pool
-        .load()
-        .execute(handler, req)
-        .instrument(tracing::Span::current())
-        .await
-        .map_err(|_| AppError::HostNotFound("".to_string()))?;
-    info!(?res, "pool execute");
-
-    Ok(Response::from(res))
+    if let Some(res) = router.serve_docs(&parts) {
+        return Ok(res);
+    }
+    if let Some(res) = router.cache.as_ref().map(|cache| cache.admin(&parts)) {
+        if let Some(res) = res? {
+            return Ok(res);
+        }
+    }
+    if let Some(res) = policy::preflight(&router, &parts)? {
+        return Ok(res);
+    }
+    let mut matched = match router.match_it(parts.method.clone(), parts.uri.path()) {
+        // OPTIONS without a route of its own lists the allowed methods
+        Err(AppError::RouteMethodNotAllowed(Method::OPTIONS, allow)) => {
+            let allow = [(header::ALLOW, router::allow_header(&allow))];
+            return Ok((StatusCode::NO_CONTENT, allow).into_response());
+        }
+        matched => matched?,
+    };
+    // a rewritten request is answered by the route of its new path, after
+    // the checks of every route on the way
+    let mut hops = vec![matched.value];
+    for _ in 0..forward::MAX_REWRITES {
+        let RouteAction::Rewrite(to) = &matched.value.action else {
+            break;
+        };
+        parts.uri = forward::rewrite(to, &matched.params, &parts.uri)?;
+        info!(uri = %parts.uri, "rewritten");
+        matched = router.match_it(parts.method.clone(), parts.uri.path())?;
+        hops.push(matched.value);
+    }
+    let endpoint = matched.value;
+    info!(action = %endpoint.action, "router matched");
+
+    let client = connect_info.map(|info| info.ip());
+    let res = async {
+        for hop in &hops {
+            policy::check(hop, store.as_ref(), &tenant, &parts, client)?;
+        }
+        let handler = match &endpoint.action {
+            RouteAction::Handler(handler) => handler,
+            RouteAction::Redirect(redirect) => {
+                let mut res = forward::redirect(redirect, &matched.params, parts.uri.query())?;
+                policy::apply(&endpoint.settings, &parts, &mut res);
+                return Ok(res);
+            }
+            RouteAction::Proxy(proxy) => {
+                let settings = &endpoint.settings;
+                let mut res =
+                    forward::proxy(proxy, &matched.params, settings, &parts, client, body).await?;
+                policy::apply(settings, &parts, &mut res);
+                return Ok(res);
+            }
+            RouteAction::Rewrite(_) => {
+                return Err(AppError::RewriteLoop(parts.uri.path().to_string()));
+            }
+        };
+
+        let settings = hops.iter().map(|hop| &hop.settings).collect::<Vec<_>>();
+        let middleware = forward::middleware(&settings);
+        // middleware may turn the client away, which the cache can't tell
+        let guarded = !router.middleware.is_empty() || !middleware.is_empty();
+        if let Some(mut res) = router
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(&parts, guarded))
+        {
+            info!("response cache hit");
+            policy::apply(&endpoint.settings, &parts, &mut res);
+            return Ok(cache::not_modified(&parts, res));
+        }
+
+        let body = policy::read_body(&endpoint.settings, &parts, body).await?;
+        if let Some(validator) = &endpoint.validator {
+            validator.validate_request(&query, &matched.params, &body)?;
+        }
+        let req = assemble_req(&matched, &parts, query, Some(body))?;
+
+        let rx = pool
+            .load()
+            .execute_with(handler, &middleware, req)
+            .instrument(tracing::Span::current());
+        let res = match endpoint.settings.timeout {
+            Some(ms) => tokio::time::timeout(Duration::from_millis(ms), rx)
+                .await
+                .map_err(|_| AppError::Timeout(ms))?,
+            None => rx.await,
+        }
+        .map_err(|_| AppError::HandlerFailed(handler.clone()))?;
+        info!(?res, "pool execute");
+        if let Some(validator) = endpoint.validator.as_ref().filter(|_| validate_responses) {
+            validator.validate_response(&res).inspect_err(|e| {
+                warn!(%handler, "{}", e);
+            })?;
+        }
+
+        let mut res = Response::from(res);
+        // cached before the CORS headers, which depend on the request
+        if let Some(cache) = &router.cache {
+            policy::apply_cache(&endpoint.settings, &mut res);
+            res = cache.store(&parts, guarded, res).await?;
+        }
+        policy::apply(&endpoint.settings, &parts, &mut res);
+        Ok(cache::not_modified(&parts, res))
+    }
+    .await;
+    res.or_else(|e| {
+        let mut res = e.into_response();
+        policy::apply_cors(&endpoint.settings, &parts, &mut res);
+        Ok(res)
+    })
 }
 
 impl AppState {
@@ -131,7 +316,12 @@ impl AppState {
         routers: DashMap<String, SwappableAppRouter>,
         pools: DashMap<String, SwappableThreadPool>,
     ) -> Self {
-        Self { routers, pools }
+        Self {
+            routers,
+            pools,
+            options: ServerOptions::default(),
+            rate_limit_store: Arc::new(MemoryStore::default()),
+        }
     }
 }
 
@@ -169,16 +359,12 @@ fn get_router_by_host(
 }
 
 fn assemble_req(
-    matched: &Match<&str>,
+    matched: &RouteMatch,
     parts: &Parts,
     query: HashMap<String, String>,
     body: Option<Bytes>,
 ) -> Result<Req, AppError> {
-    let params: HashMap<String, String> = matched
-        .params
-        .iter()
-        .map(|(k, v)| (k.to_string(), v.to_string()))
-        .collect();
+    let params = matched.params.clone();
     // convert request data into Req
     let headers = parts
         .headers
@@ -188,6 +374,7 @@ fn assemble_req(
     let body = body.and_then(|v| String::from_utf8(v.into()).ok());
 
     let req = Req::builder()
+        .id(parts.extensions.get::<RequestId>().map(|id| id.0.clone()))
         .method(parts.method.to_string())
         .url(parts.uri.to_string())
         .query(query)
@@ -198,3 +385,141 @@ fn assemble_req(
 
     Ok(req)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    const CONFIG: &str = r#"
+name: test
+routes:
+  /items:
+    - method: GET
+      handler: items
+      auth: Bearer
+      cors:
+        origins: ["https://example.com"]
+      rate_limit:
+        requests: 1
+        window: 60
+"#;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn handler_should_add_cors_headers_to_errors() {
+        let code = r#"
+    (function(){
+        async function items(req){
+            return { status: 200, headers: {}, body: "[]" };
+        }
+        return { items };
+    })();
+    "#;
+        let config: ProjectConfig = serde_yaml::from_str(CONFIG).unwrap();
+        let router = SwappableAppRouter::try_new(code, &config).unwrap();
+        let pool = SwappableThreadPool::new(code).unwrap();
+        let state = AppState::new(
+            DashMap::from_iter([("localhost".to_string(), router)]),
+            DashMap::from_iter([("localhost".to_string(), pool)]),
+        );
+        let get = |authorization: Option<&str>| {
+            let mut req = Request::get("/items").header(header::ORIGIN, "https://example.com");
+            if let Some(authorization) = authorization {
+                req = req.header(header::AUTHORIZATION, authorization);
+            }
+            let parts = req.body(()).unwrap().into_parts().0;
+            handler(
+                State(state.clone()),
+                None,
+                parts,
+                // another port is the same tenant
+                Host(format!(
+                    "localhost:{}",
+                    3000 + authorization.is_some() as u16
+                )),
+                Query(HashMap::new()),
+                Body::empty(),
+            )
+        };
+
+        let statuses = [
+            get(None).await.unwrap(),
+            get(Some("Bearer a")).await.unwrap(),
+            get(Some("Bearer a")).await.unwrap(),
+        ];
+        let statuses = statuses.map(|res| {
+            let origin = &res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN];
+            assert_eq!(origin, "https://example.com");
+            res.status()
+        });
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::UNAUTHORIZED,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn handler_should_check_rewrite_routes() {
+        let config = r#"
+name: test
+routes:
+  /old:
+    - method: GET
+      rewrite: /items
+      auth: Bearer
+      middleware: [tag]
+  /items:
+    - method: GET
+      handler: items
+      middleware: [tag]
+"#;
+        let code = r#"
+    (function(){
+        async function items(req){
+            return { status: 200, headers: {}, body: "[]" };
+        }
+        async function tag(req, next){
+            const res = await next(req);
+            res.headers["x-tag"] = (res.headers["x-tag"] || "") + "t";
+            return res;
+        }
+        return { items, tag };
+    })();
+    "#;
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        let router = SwappableAppRouter::try_new(code, &config).unwrap();
+        let pool = SwappableThreadPool::new(code).unwrap();
+        let state = AppState::new(
+            DashMap::from_iter([("localhost".to_string(), router)]),
+            DashMap::from_iter([("localhost".to_string(), pool)]),
+        );
+        let get = |path: &str, authorization: Option<&str>| {
+            let mut req = Request::get(path);
+            if let Some(authorization) = authorization {
+                req = req.header(header::AUTHORIZATION, authorization);
+            }
+            let parts = req.body(()).unwrap().into_parts().0;
+            handler(
+                State(state.clone()),
+                None,
+                parts,
+                Host("localhost".to_string()),
+                Query(HashMap::new()),
+                Body::empty(),
+            )
+        };
+
+        let res = get("/old", None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        // the middleware both routes share runs once
+        for res in [get("/old", Some("Bearer a")), get("/items", None)] {
+            let res = res.await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["x-tag"], "t");
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use std::{fmt::Write as _, sync::atomic::Ordering, time::Duration};
+
+use crate::SwappableThreadPool;
+use axum::http::{Method, StatusCode};
+use dashmap::DashMap;
+
+/// Upper bounds of the latency buckets in seconds, the Prometheus defaults
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Route label of requests which match no route
+pub(crate) const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Requests and reloads counted by the server, rendered in the Prometheus
+/// text format along with the load of the pools
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: DashMap<RequestLabels, Histogram>,
+    /// by tenant and whether the reload succeeded
+    reloads: DashMap<(String, bool), u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct RequestLabels {
+    tenant: String,
+    route: String,
+    method: String,
+    status: u16,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// observations per bucket, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    /// Count a request answered with `status` after `elapsed`
+    pub fn observe_request(
+        &self,
+        tenant: &str,
+        route: &str,
+        method: &Method,
+        status: StatusCode,
+        elapsed: Duration,
+    ) {
+        let labels = RequestLabels {
+            tenant: tenant.to_string(),
+            route: route.to_string(),
+            method: method.to_string(),
+            status: status.as_u16(),
+        };
+        let seconds = elapsed.as_secs_f64();
+        let mut histogram = self.requests.entry(labels).or_default();
+        if let Some(i) = BUCKETS.iter().position(|le| seconds <= *le) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    /// Count a hot swap of the code and config of `tenant`
+    pub fn reloaded(&self, tenant: &str, ok: bool) {
+        *self.reloads.entry((tenant.to_string(), ok)).or_default() += 1;
+    }
+
+    /// The metrics in the Prometheus text format, with the load of `pools`
+    pub fn render(&self, pools: &DashMap<String, SwappableThreadPool>) -> String {
+        let mut out = String::new();
+        let mut requests = self
+            .requests
+            .iter()
+            .map(|entry| {
+                let histogram = entry.value();
+                (
+                    entry.key().clone(),
+                    histogram.buckets,
+                    histogram.count,
+                    histogram.sum,
+                )
+            })
+            .collect::<Vec<_>>();
+        requests.sort_by(|a, b| a.0.cmp(&b.0));
+
+        header(
+            &mut out,
+            "ceno_requests_total",
+            "counter",
+            "Requests answered",
+        );
+        for (labels, _, count, _) in &requests {
+            let _ = writeln!(out, "ceno_requests_total{{{}}} {count}", labels.render());
+        }
+        header(
+            &mut out,
+            "ceno_request_duration_seconds",
+            "histogram",
+            "Time until the response headers are sent",
+        );
+        for (labels, buckets, count, sum) in &requests {
+            let labels = labels.render();
+            let mut cumulative = 0;
+            for (le, n) in BUCKETS.iter().zip(buckets) {
+                cumulative += n;
+                let _ = writeln!(
+                    out,
+                    "ceno_request_duration_seconds_bucket{{{labels},le=\"{le}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "ceno_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {count}"
+            );
+            let _ = writeln!(out, "ceno_request_duration_seconds_sum{{{labels}}} {sum}");
+            let _ = writeln!(
+                out,
+                "ceno_request_duration_seconds_count{{{labels}}} {count}"
+            );
+        }
+
+        let mut pools = pools
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().load()))
+            .collect::<Vec<_>>();
+        pools.sort_by(|a, b| a.0.cmp(&b.0));
+        header(
+            &mut out,
+            "ceno_pool_workers",
+            "gauge",
+            "Workers of the pool",
+        );
+        for (tenant, pool) in &pools {
+            let tenant = label(tenant);
+            let workers = pool.stats().heap.len();
+            let _ = writeln!(out, "ceno_pool_workers{{tenant=\"{tenant}\"}} {workers}");
+        }
+        header(
+            &mut out,
+            "ceno_pool_queue_depth",
+            "gauge",
+            "Requests waiting for a worker",
+        );
+        for (tenant, pool) in &pools {
+            let tenant = label(tenant);
+            let queued = pool.stats().queued.load(Ordering::Relaxed);
+            let _ = writeln!(out, "ceno_pool_queue_depth{{tenant=\"{tenant}\"}} {queued}");
+        }
+        header(
+            &mut out,
+            "ceno_pool_busy_workers",
+            "gauge",
+            "Workers running a request or its waitUntil work",
+        );
+        for (tenant, pool) in &pools {
+            let tenant = label(tenant);
+            let busy = pool.stats().busy.load(Ordering::Relaxed);
+            let _ = writeln!(out, "ceno_pool_busy_workers{{tenant=\"{tenant}\"}} {busy}");
+        }
+        header(
+            &mut out,
+            "ceno_js_heap_bytes",
+            "gauge",
+            "Bytes allocated by the JS runtime of each worker, after its last request",
+        );
+        for (tenant, pool) in &pools {
+            let tenant = label(tenant);
+            for (worker, heap) in pool.stats().heap.iter().enumerate() {
+                let heap = heap.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "ceno_js_heap_bytes{{tenant=\"{tenant}\",worker=\"{worker}\"}} {heap}"
+                );
+            }
+        }
+
+        let mut reloads = self
+            .reloads
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect::<Vec<_>>();
+        reloads.sort();
+        header(
+            &mut out,
+            "ceno_reloads_total",
+            "counter",
+            "Hot swaps of the code and config",
+        );
+        for ((tenant, ok), count) in reloads {
+            let result = if ok { "ok" } else { "failed" };
+            let _ = writeln!(
+                out,
+                "ceno_reloads_total{{tenant=\"{}\",result=\"{result}\"}} {count}",
+                label(&tenant)
+            );
+        }
+        out
+    }
+}
+
+impl RequestLabels {
+    fn render(&self) -> String {
+        format!(
+            "tenant=\"{}\",route=\"{}\",method=\"{}\",status=\"{}\"",
+            label(&self.tenant),
+            label(&self.route),
+            label(&self.method),
+            self.status
+        )
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+/// Escape a label value
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_should_render() {
+        let metrics = Metrics::default();
+        let observe = |ms| {
+            metrics.observe_request(
+                "localhost",
+                "/users/{id}",
+                &Method::GET,
+                StatusCode::OK,
+                Duration::from_millis(ms),
+            )
+        };
+        observe(3);
+        observe(30);
+        metrics.reloaded("localhost", true);
+        metrics.reloaded("localhost", false);
+        metrics.reloaded("localhost", true);
+
+        let pools = DashMap::new();
+        let code = "(function(){ return {}; })();";
+        pools.insert(
+            "localhost".to_string(),
+            SwappableThreadPool::new(code).unwrap(),
+        );
+        let out = metrics.render(&pools);
+        let labels = r#"tenant="localhost",route="/users/{id}",method="GET",status="200""#;
+        for line in [
+            format!("ceno_requests_total{{{labels}}} 2"),
+            format!("ceno_request_duration_seconds_bucket{{{labels},le=\"0.005\"}} 1"),
+            format!("ceno_request_duration_seconds_bucket{{{labels},le=\"0.025\"}} 1"),
+            format!("ceno_request_duration_seconds_bucket{{{labels},le=\"0.05\"}} 2"),
+            format!("ceno_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} 2"),
+            format!("ceno_request_duration_seconds_count{{{labels}}} 2"),
+            r#"ceno_pool_workers{tenant="localhost"} 4"#.to_string(),
+            r#"ceno_pool_queue_depth{tenant="localhost"} 0"#.to_string(),
+            r#"ceno_reloads_total{tenant="localhost",result="ok"} 2"#.to_string(),
+            r#"ceno_reloads_total{tenant="localhost",result="failed"} 1"#.to_string(),
+        ] {
+            assert!(out.lines().any(|l| l == line), "{line} not in\n{out}");
+        }
+        assert!(out.contains("# TYPE ceno_request_duration_seconds histogram"));
+    }
+}
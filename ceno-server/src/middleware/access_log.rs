@@ -0,0 +1,384 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::{self, Write as _},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use super::{tenant_router, RequestId};
+use crate::{
+    config::{AccessLogConfig, AccessLogFormat},
+    SwappableAppRouter,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Request},
+    http::{header, HeaderMap, Method, StatusCode, Version},
+    response::Response,
+};
+use chrono::{DateTime, Local};
+use dashmap::DashMap;
+use http_body::{Body as HttpBody, Frame, SizeHint};
+use serde_json::json;
+use tower::{Layer, Service};
+use tracing::warn;
+
+/// Where the requests of a tenant are logged, the file is opened on the
+/// first request
+#[derive(Debug)]
+pub struct AccessLog {
+    config: AccessLogConfig,
+    file: Mutex<Option<LogFile>>,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    file: File,
+    size: u64,
+}
+
+/// A request and how it was answered
+#[derive(Debug)]
+struct Record {
+    time: DateTime<Local>,
+    start: Instant,
+    request_id: Option<String>,
+    host: Option<String>,
+    client: Option<IpAddr>,
+    method: Method,
+    uri: String,
+    version: Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    status: StatusCode,
+    /// of the body sent, which may be compressed
+    bytes: u64,
+}
+
+/// Logs the requests of the tenants with an `access_log` config, once their
+/// response is sent
+#[derive(Clone)]
+pub struct AccessLogLayer {
+    routers: Arc<DashMap<String, SwappableAppRouter>>,
+}
+
+#[derive(Clone)]
+pub struct AccessLogMiddleware<S> {
+    inner: S,
+    layer: AccessLogLayer,
+}
+
+/// A response body logging its request when dropped, that is once it's
+/// sent or the client is gone
+struct LoggedBody {
+    inner: Body,
+    log: Arc<AccessLog>,
+    record: Record,
+}
+
+impl AccessLog {
+    pub fn new(config: AccessLogConfig) -> Self {
+        Self {
+            config,
+            file: Mutex::new(None),
+        }
+    }
+
+    fn write(&self, record: &Record, elapsed: Duration) {
+        let line = record.format(self.config.format, elapsed);
+        let written = match &self.config.file {
+            Some(path) => self.append(Path::new(path), &line),
+            None => writeln!(io::stdout().lock(), "{line}"),
+        };
+        if let Err(e) = written {
+            warn!("access log not written, {e}");
+        }
+    }
+
+    /// Append `line` to the file, rotating it first if it would be too large
+    fn append(&self, path: &Path, line: &str) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let len = line.len() as u64 + 1;
+        if file
+            .as_ref()
+            .is_some_and(|file| file.size > 0 && file.size + len > self.config.max_size)
+        {
+            *file = None;
+            rotate(path, self.config.max_files)?;
+        }
+        let file = match &mut *file {
+            Some(file) => file,
+            None => file.insert(LogFile::open(path)?),
+        };
+        writeln!(file.file, "{line}")?;
+        file.size += len;
+        Ok(())
+    }
+}
+
+impl LogFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { file, size })
+    }
+}
+
+/// Shift `{path}.1` to `{path}.2` and so on, dropping the oldest, and move
+/// `path` to `{path}.1`
+fn rotate(path: &Path, max_files: usize) -> io::Result<()> {
+    let rotated = |i: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{i}"));
+        PathBuf::from(name)
+    };
+    if max_files == 0 {
+        return fs::remove_file(path);
+    }
+    for i in (1..max_files).rev() {
+        if rotated(i).exists() {
+            fs::rename(rotated(i), rotated(i + 1))?;
+        }
+    }
+    fs::rename(path, rotated(1))
+}
+
+impl Record {
+    fn new(request: &Request) -> Self {
+        let headers = request.headers();
+        Self {
+            time: Local::now(),
+            start: Instant::now(),
+            request_id: request
+                .extensions()
+                .get::<RequestId>()
+                .map(|id| id.0.clone()),
+            host: header_str(headers, header::HOST),
+            client: request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|info| info.ip()),
+            method: request.method().clone(),
+            uri: request
+                .uri()
+                .path_and_query()
+                .map_or_else(|| request.uri().path().to_string(), |pq| pq.to_string()),
+            version: request.version(),
+            referer: header_str(headers, header::REFERER),
+            user_agent: header_str(headers, header::USER_AGENT),
+            status: StatusCode::OK,
+            bytes: 0,
+        }
+    }
+
+    fn format(&self, format: AccessLogFormat, elapsed: Duration) -> String {
+        let client = self
+            .client
+            .map_or_else(|| "-".to_string(), |ip| ip.to_string());
+        let common = || {
+            // `%b` is `-` rather than 0
+            let bytes = match self.bytes {
+                0 => "-".to_string(),
+                bytes => bytes.to_string(),
+            };
+            format!(
+                "{client} - - [{}] \"{} {} {:?}\" {} {bytes}",
+                self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+                self.method,
+                quoted(&self.uri),
+                self.version,
+                self.status.as_u16(),
+            )
+        };
+        match format {
+            AccessLogFormat::Common => common(),
+            AccessLogFormat::Combined => {
+                let or_dash = |v: &Option<String>| v.as_deref().map_or("-".to_string(), quoted);
+                format!(
+                    "{} \"{}\" \"{}\"",
+                    common(),
+                    or_dash(&self.referer),
+                    or_dash(&self.user_agent)
+                )
+            }
+            AccessLogFormat::Json => json!({
+                "time": self.time.to_rfc3339(),
+                "request_id": self.request_id,
+                "host": self.host,
+                "client": self.client,
+                "method": self.method.as_str(),
+                "uri": self.uri,
+                "protocol": format!("{:?}", self.version),
+                "status": self.status.as_u16(),
+                "bytes": self.bytes,
+                "duration_ms": elapsed.as_secs_f64() * 1000.0,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+            })
+            .to_string(),
+        }
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+/// Escape what would end a quoted field of the common log format
+fn quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl AccessLogLayer {
+    pub fn new(routers: Arc<DashMap<String, SwappableAppRouter>>) -> Self {
+        Self { routers }
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLogMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AccessLogMiddleware {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+impl<S> Service<Request> for AccessLogMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = impl Future<Output = Result<Self::Response, Self::Error>> + Send + 'static;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let logged = tenant_router(&self.layer.routers, &request)
+            .and_then(|(_, router)| router.access_log.clone())
+            .map(|log| (log, Record::new(&request)));
+        let future = self.inner.call(request);
+        async move {
+            let res = future.await?;
+            let Some((log, mut record)) = logged else {
+                return Ok(res);
+            };
+            record.status = res.status();
+            let (parts, inner) = res.into_parts();
+            let body = LoggedBody { inner, log, record };
+            Ok(Response::from_parts(parts, Body::new(body)))
+        }
+    }
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let polled = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &polled {
+            if let Some(data) = frame.data_ref() {
+                self.record.bytes += data.len() as u64;
+            }
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.log.write(&self.record, self.record.start.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone as _;
+
+    fn record() -> Record {
+        Record {
+            time: Local.with_ymd_and_hms(2024, 10, 10, 13, 55, 36).unwrap(),
+            start: Instant::now(),
+            request_id: Some("abc".into()),
+            host: Some("localhost".into()),
+            client: Some([127, 0, 0, 1].into()),
+            method: Method::GET,
+            uri: "/a?b=\"c\"".into(),
+            version: Version::HTTP_11,
+            referer: None,
+            user_agent: Some("curl/8.0".into()),
+            status: StatusCode::OK,
+            bytes: 42,
+        }
+    }
+
+    #[test]
+    fn record_should_format() {
+        let record = record();
+        let elapsed = Duration::from_millis(3);
+        let time = record.time.format("%d/%b/%Y:%H:%M:%S %z");
+        assert_eq!(
+            record.format(AccessLogFormat::Common, elapsed),
+            format!("127.0.0.1 - - [{time}] \"GET /a?b=\\\"c\\\" HTTP/1.1\" 200 42")
+        );
+        assert!(record
+            .format(AccessLogFormat::Combined, elapsed)
+            .ends_with(" 200 42 \"-\" \"curl/8.0\""));
+        let json: serde_json::Value =
+            serde_json::from_str(&record.format(AccessLogFormat::Json, elapsed)).unwrap();
+        assert_eq!(json["request_id"], "abc");
+        assert_eq!(json["client"], "127.0.0.1");
+        assert_eq!(json["duration_ms"], 3.0);
+    }
+
+    #[test]
+    fn access_log_should_rotate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("logs");
+        let path = dir.join("access.log");
+        let log = AccessLog::new(AccessLogConfig {
+            format: AccessLogFormat::Common,
+            file: Some(path.display().to_string()),
+            max_size: 10,
+            max_files: 2,
+        });
+        for line in ["first", "second", "third", "fourth"] {
+            log.append(&path, line).unwrap();
+        }
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "fourth\n");
+        assert_eq!(read(dir.join("access.log.1")), "third\n");
+        assert_eq!(read(dir.join("access.log.2")), "second\n");
+        assert!(!dir.join("access.log.3").exists());
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    future::Future,
+    io,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use super::tenant_router;
+use crate::{
+    config::{CompressionConfig, Encoding},
+    SwappableAppRouter,
+};
+use async_compression::{
+    tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder},
+    Level,
+};
+use axum::{
+    body::{Body, HttpBody as _},
+    extract::Request,
+    http::{header, HeaderValue, Method, StatusCode},
+    response::Response,
+};
+use dashmap::DashMap;
+use futures_util::TryStreamExt as _;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tower::{Layer, Service};
+
+/// brotli is slow at its default quality, 11
+const BROTLI_QUALITY: i32 = 4;
+
+/// Compresses the responses of the tenants with a `compression` config
+#[derive(Clone)]
+pub struct CompressionLayer {
+    routers: Arc<DashMap<String, SwappableAppRouter>>,
+}
+
+#[derive(Clone)]
+pub struct CompressionMiddleware<S> {
+    inner: S,
+    layer: CompressionLayer,
+}
+
+impl CompressionLayer {
+    pub fn new(routers: Arc<DashMap<String, SwappableAppRouter>>) -> Self {
+        Self { routers }
+    }
+
+    /// The config of the tenant, if it compresses, and the encoding
+    /// accepted by the client
+    fn negotiate(&self, request: &Request) -> Option<(CompressionConfig, Option<Encoding>)> {
+        let (_, router) = tenant_router(&self.routers, request)?;
+        let config = router.compression.clone()?;
+        let encoding = request
+            .headers()
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|accept| negotiate(accept, &config.encodings))
+            .filter(|_| request.method() != Method::HEAD);
+        Some((config, encoding))
+    }
+}
+
+impl<S> Layer<S> for CompressionLayer {
+    type Service = CompressionMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CompressionMiddleware {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+impl<S> Service<Request> for CompressionMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = impl Future<Output = Result<Self::Response, Self::Error>> + Send + 'static;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let negotiated = self.layer.negotiate(&request);
+        let future = self.inner.call(request);
+        async move {
+            let res = future.await?;
+            match negotiated {
+                Some((config, encoding)) => Ok(compress(res, &config, encoding)),
+                None => Ok(res),
+            }
+        }
+    }
+}
+
+/// The encoding of `offered` with the highest q-value in `accept`, the first
+/// one offered wins a tie
+fn negotiate(accept: &str, offered: &[Encoding]) -> Option<Encoding> {
+    let accepted = accept
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let name = parts.next().filter(|name| !name.is_empty())?;
+            let q = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            Some((name, q))
+        })
+        .collect::<Vec<_>>();
+    let q_of = |name: &str| {
+        let q = |wanted: &str| {
+            accepted
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+                .map(|(_, q)| *q)
+        };
+        q(name).or_else(|| q("*")).unwrap_or(0.0)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in offered {
+        let q = q_of(encoding.as_str());
+        if q > 0.0 && best.is_none_or(|(_, best)| q > best) {
+            best = Some((*encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether `res` is worth compressing under `config`
+fn compressible(res: &Response, config: &CompressionConfig) -> bool {
+    let headers = res.headers();
+    let status = res.status();
+    if status.is_informational()
+        || matches!(
+            status,
+            StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED | StatusCode::PARTIAL_CONTENT
+        )
+        || headers.contains_key(header::CONTENT_ENCODING)
+        || headers.contains_key(header::CONTENT_RANGE)
+    {
+        return false;
+    }
+    let no_transform = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.to_ascii_lowercase().contains("no-transform"));
+    if no_transform {
+        return false;
+    }
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase());
+    let Some(content_type) = content_type else {
+        return false;
+    };
+    let allowed = config
+        .content_types
+        .iter()
+        .any(|allowed| match allowed.strip_suffix("/*") {
+            Some(prefix) => content_type
+                .split_once('/')
+                .is_some_and(|(ty, _)| ty.eq_ignore_ascii_case(prefix)),
+            None => allowed.eq_ignore_ascii_case(&content_type),
+        });
+
+    // streamed bodies of unknown size are compressed
+    let size = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .or(res.body().size_hint().exact());
+    allowed && size.is_none_or(|size| size >= config.min_size as u64)
+}
+
+/// Compress `res` with `encoding` if it's worth it
+fn compress(mut res: Response, config: &CompressionConfig, encoding: Option<Encoding>) -> Response {
+    // the response varies by `Accept-Encoding` whether or not this one is compressed
+    res.headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    let Some(encoding) = encoding.filter(|_| compressible(&res, config)) else {
+        return res;
+    };
+
+    let (mut parts, body) = res.into_parts();
+    let reader = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+    let body = match encoding {
+        Encoding::Br => Body::from_stream(ReaderStream::new(BrotliEncoder::with_quality(
+            reader,
+            Level::Precise(BROTLI_QUALITY),
+        ))),
+        Encoding::Zstd => Body::from_stream(ReaderStream::new(ZstdEncoder::new(reader))),
+        Encoding::Gzip => Body::from_stream(ReaderStream::new(GzipEncoder::new(reader))),
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.remove(header::ACCEPT_RANGES);
+    parts.headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::bufread::GzipDecoder;
+    use tokio::io::AsyncReadExt as _;
+
+    fn config() -> CompressionConfig {
+        serde_yaml::from_str("min_size: 4").unwrap()
+    }
+
+    #[test]
+    fn negotiate_should_follow_q_values() {
+        let offered = [Encoding::Br, Encoding::Zstd, Encoding::Gzip];
+        assert_eq!(negotiate("gzip, br", &offered), Some(Encoding::Br));
+        assert_eq!(
+            negotiate("gzip;q=1, br;q=0.5", &offered),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("*;q=0.1, zstd", &offered), Some(Encoding::Zstd));
+        assert_eq!(negotiate("br;q=0, *", &offered), Some(Encoding::Zstd));
+        assert_eq!(negotiate("identity", &offered), None);
+        assert_eq!(negotiate("", &offered), None);
+    }
+
+    #[tokio::test]
+    async fn compress_should_skip_what_it_should() {
+        let response = |content_type: &str, body: &'static str| {
+            Response::builder()
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let res = compress(
+            response("application/json; charset=utf-8", "{\"a\":1}"),
+            &config(),
+            Some(Encoding::Gzip),
+        );
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(res.headers()[header::VARY], "accept-encoding");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let mut decoded = String::new();
+        GzipDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .await
+            .unwrap();
+        assert_eq!(decoded, "{\"a\":1}");
+
+        let res = compress(
+            response("text/plain", "abc"),
+            &config(),
+            Some(Encoding::Gzip),
+        );
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+        let res = compress(
+            response("image/png", "abcdef"),
+            &config(),
+            Some(Encoding::Gzip),
+        );
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+        let mut res = response("text/html", "abcdef");
+        res.headers_mut()
+            .insert(header::CONTENT_ENCODING, HeaderValue::from_static("br"));
+        let res = compress(res, &config(), Some(Encoding::Gzip));
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "br");
+    }
+}
@@ -1,5 +1,36 @@
+mod access_log;
+mod compression;
+mod rate_limit;
+mod request_id;
 mod server_time;
 
+use crate::{AppRouter, SwappableAppRouter};
+use axum::{
+    extract::Request,
+    http::{header, HeaderName},
+};
+use dashmap::DashMap;
+
+pub use access_log::{AccessLog, AccessLogLayer};
+pub use compression::CompressionLayer;
+pub(crate) use rate_limit::client_key;
+pub use rate_limit::{MemoryStore, RateLimitLayer, RateLimitStatus, RateLimitStore};
+pub use request_id::{RequestId, RequestIdLayer};
 pub use server_time::ServerTimeLayer;
 
 const SERVER_TIME_HEADER: &str = "x-server-time";
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The host of the tenant a request is sent to, and its router
+fn tenant_router<'r>(
+    routers: &DashMap<String, SwappableAppRouter>,
+    request: &'r Request,
+) -> Option<(&'r str, AppRouter)> {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or(request.uri().host())?;
+    let host = host.split(':').next().unwrap_or_default();
+    routers.get(host).map(|router| (host, router.load()))
+}
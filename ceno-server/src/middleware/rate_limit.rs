@@ -0,0 +1,432 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex, OnceLock, PoisonError},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use super::tenant_router;
+use crate::{
+    config::{RateLimit, RateLimitKey},
+    AppError, SwappableAppRouter,
+};
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{HeaderMap, HeaderName},
+    response::{IntoResponse, Response},
+};
+use dashmap::DashMap;
+use tower::{Layer, Service};
+
+/// Buckets kept by a [`MemoryStore`], the least recently used one is
+/// dropped to make room for another
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+/// Values of a header which get buckets of their own from one ip, the
+/// requests with others share the bucket of the ip
+const MAX_HEADER_KEYS_PER_IP: usize = 64;
+
+const RATE_LIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATE_LIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATE_LIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// Where the token buckets of rate limits are kept, see [`MemoryStore`]
+pub trait RateLimitStore: fmt::Debug + Send + Sync + 'static {
+    /// Take a token from the bucket of `key`, which holds `limit.requests`
+    /// tokens refilled over `limit.window`. Returns the status of the bucket,
+    /// as an error if it's empty.
+    fn acquire(&self, key: &str, limit: &RateLimit) -> Result<RateLimitStatus, RateLimitStatus>;
+
+    /// Give back a token taken by `acquire`, for a request turned away by
+    /// another limit
+    fn release(&self, key: &str, limit: &RateLimit);
+}
+
+/// What's left of a bucket, sent in the `RateLimit-*` headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    /// seconds until the bucket is full again, or until the next token if
+    /// it's empty
+    pub reset: u64,
+}
+
+/// Token buckets kept in the memory of the server, as many as `capacity`
+#[derive(Debug)]
+pub struct MemoryStore {
+    capacity: usize,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    /// the keys by when they were last used, oldest first
+    used: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    used: u64,
+}
+
+/// Rate limits of the tenants, checked before requests are routed
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    routers: Arc<DashMap<String, SwappableAppRouter>>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+#[derive(Clone)]
+pub struct RateLimitMiddleware<S> {
+    inner: S,
+    layer: RateLimitLayer,
+}
+
+impl RateLimitStore for MemoryStore {
+    fn acquire(&self, key: &str, limit: &RateLimit) -> Result<RateLimitStatus, RateLimitStatus> {
+        self.acquire_at(key, limit, Instant::now())
+    }
+
+    fn release(&self, key: &str, limit: &RateLimit) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(bucket) = buckets.by_key.get_mut(key) {
+            bucket.tokens = (bucket.tokens + 1.0).min(limit.requests as f64);
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::with_capacity(MAX_TRACKED_BUCKETS)
+    }
+}
+
+impl MemoryStore {
+    /// A store keeping up to `capacity` buckets. Clients whose bucket is
+    /// dropped for another start over with a full one.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            buckets: Mutex::default(),
+        }
+    }
+
+    fn acquire_at(
+        &self,
+        key: &str,
+        limit: &RateLimit,
+        now: Instant,
+    ) -> Result<RateLimitStatus, RateLimitStatus> {
+        let window = Duration::from_secs(limit.window.max(1));
+        let capacity = limit.requests as f64;
+        // tokens per second
+        let rate = capacity / window.as_secs_f64();
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let buckets = &mut *buckets;
+        if !buckets.by_key.contains_key(key) && buckets.by_key.len() >= self.capacity {
+            if let Some((_, oldest)) = buckets.used.pop_first() {
+                buckets.by_key.remove(&oldest);
+            }
+        }
+        buckets.clock += 1;
+        let bucket = buckets.by_key.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+            used: 0,
+        });
+        buckets.used.remove(&bucket.used);
+        bucket.used = buckets.clock;
+        buckets.used.insert(bucket.used, key.to_string());
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        let seconds = |tokens: f64| match rate > 0.0 {
+            true => (tokens / rate).ceil() as u64,
+            false => window.as_secs(),
+        };
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(RateLimitStatus {
+                limit: limit.requests,
+                remaining: bucket.tokens as u32,
+                reset: seconds(capacity - bucket.tokens),
+            })
+        } else {
+            Err(RateLimitStatus {
+                limit: limit.requests,
+                remaining: 0,
+                reset: seconds(1.0 - bucket.tokens),
+            })
+        }
+    }
+}
+
+impl RateLimitStatus {
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(RATE_LIMIT_LIMIT, self.limit.into());
+        headers.insert(RATE_LIMIT_REMAINING, self.remaining.into());
+        headers.insert(RATE_LIMIT_RESET, self.reset.into());
+    }
+}
+
+/// What tells clients apart for `key`, requests without the header, or
+/// with more values of it than an ip may use, are told apart by their ip
+pub(crate) fn client_key(
+    key: &RateLimitKey,
+    headers: &HeaderMap,
+    client: Option<IpAddr>,
+) -> String {
+    // without the peer address, all clients share a bucket
+    let ip = client.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    if let RateLimitKey::Header(name) = key {
+        if let Some(value) = headers.get(name.as_str()).and_then(|v| v.to_str().ok()) {
+            let key = format!("{name}={value}");
+            if admit_header_key(ip, &key) {
+                return key;
+            }
+        }
+    }
+    ip.to_string()
+}
+
+/// Whether `ip` may have a bucket of its own for `key`, the first header
+/// values of an ip do. Otherwise a client rotating them would get a full
+/// bucket, and drop the bucket of another client, on every request.
+fn admit_header_key(ip: IpAddr, key: &str) -> bool {
+    static SEEN: OnceLock<Mutex<HashMap<IpAddr, HashSet<String>>>> = OnceLock::new();
+    let mut seen = SEEN
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    // forgetting them all lets each ip start over, as a full store does
+    if !seen.contains_key(&ip) && seen.len() >= MAX_TRACKED_BUCKETS {
+        seen.clear();
+    }
+    let keys = seen.entry(ip).or_default();
+    if keys.contains(key) {
+        return true;
+    }
+    if keys.len() >= MAX_HEADER_KEYS_PER_IP {
+        return false;
+    }
+    keys.insert(key.to_string());
+    true
+}
+
+impl RateLimitLayer {
+    pub fn new(
+        routers: Arc<DashMap<String, SwappableAppRouter>>,
+        store: Arc<dyn RateLimitStore>,
+    ) -> Self {
+        Self { routers, store }
+    }
+
+    /// Take a token for every limit of the tenant, returning the status of
+    /// the one with the fewest left. None is taken if one of them is reached.
+    fn check(&self, request: &Request) -> Result<Option<RateLimitStatus>, RateLimitStatus> {
+        let Some((host, router)) = tenant_router(&self.routers, request) else {
+            return Ok(None);
+        };
+        let client = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|info| info.ip());
+
+        let mut buckets = Vec::with_capacity(router.rate_limits.len());
+        for (i, limit) in router.rate_limits.iter().enumerate() {
+            let mut key = format!(
+                "{host}/{i}/{}",
+                client_key(&limit.limit.key, request.headers(), client)
+            );
+            if limit.per_route {
+                let route = router
+                    .router
+                    .at(request.uri().path())
+                    .map(|matched| matched.value.path.as_str())
+                    .unwrap_or_default();
+                key.push_str(route);
+            }
+            buckets.push((key, &limit.limit));
+        }
+        acquire_all(self.store.as_ref(), &buckets)
+    }
+}
+
+/// Take a token from each bucket, giving them back if one of them is empty
+fn acquire_all(
+    store: &dyn RateLimitStore,
+    buckets: &[(String, &RateLimit)],
+) -> Result<Option<RateLimitStatus>, RateLimitStatus> {
+    let mut tightest: Option<RateLimitStatus> = None;
+    for (i, (key, limit)) in buckets.iter().enumerate() {
+        let status = match store.acquire(key, limit) {
+            Ok(status) => status,
+            Err(status) => {
+                for (key, limit) in &buckets[..i] {
+                    store.release(key, limit);
+                }
+                return Err(status);
+            }
+        };
+        if tightest.is_none_or(|tightest| status.remaining < tightest.remaining) {
+            tightest = Some(status);
+        }
+    }
+    Ok(tightest)
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitMiddleware {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+impl<S> Service<Request> for RateLimitMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = impl Future<Output = Result<Self::Response, Self::Error>> + Send + 'static;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // a limited request isn't passed on
+        let checked = match self.layer.check(&request) {
+            Ok(status) => Ok((status, self.inner.call(request))),
+            Err(status) => Err(status),
+        };
+        async move {
+            let (status, future) = match checked {
+                Ok(checked) => checked,
+                Err(status) => return Ok(AppError::TooManyRequests(status).into_response()),
+            };
+            let mut res = future.await?;
+            // the limit of the route is reported if it's the one reached
+            if let Some(status) = status.filter(|_| !res.headers().contains_key(RATE_LIMIT_LIMIT)) {
+                status.insert_headers(res.headers_mut());
+            }
+            Ok(res)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn memory_store_should_refill() {
+        let store = MemoryStore::default();
+        let limit = RateLimit {
+            requests: 2,
+            window: 10,
+            key: RateLimitKey::Ip,
+        };
+        let now = Instant::now();
+        let status = store.acquire_at("a", &limit, now).unwrap();
+        assert_eq!((status.remaining, status.reset), (1, 5));
+        let status = store.acquire_at("a", &limit, now).unwrap();
+        assert_eq!((status.remaining, status.reset), (0, 10));
+        let status = store.acquire_at("a", &limit, now).unwrap_err();
+        assert_eq!((status.remaining, status.reset), (0, 5));
+        assert!(store.acquire_at("b", &limit, now).is_ok());
+        assert!(store
+            .acquire_at("a", &limit, now + Duration::from_secs(5))
+            .is_ok());
+    }
+
+    #[test]
+    fn memory_store_should_drop_the_least_recently_used() {
+        let store = MemoryStore::with_capacity(2);
+        let limit = RateLimit {
+            requests: 1,
+            window: 60,
+            key: RateLimitKey::Ip,
+        };
+        let now = Instant::now();
+        assert!(store.acquire_at("a", &limit, now).is_ok());
+        assert!(store.acquire_at("b", &limit, now).is_ok());
+        assert!(store.acquire_at("a", &limit, now).is_err());
+        // b is dropped for c, a is kept as it was used since
+        assert!(store.acquire_at("c", &limit, now).is_ok());
+        assert!(store.acquire_at("a", &limit, now).is_err());
+        assert!(store.acquire_at("b", &limit, now).is_ok());
+        let buckets = store.buckets.lock().unwrap();
+        assert_eq!((buckets.by_key.len(), buckets.used.len()), (2, 2));
+    }
+
+    #[test]
+    fn acquire_all_should_release_when_a_limit_is_reached() {
+        let store = MemoryStore::default();
+        let limit = |requests| RateLimit {
+            requests,
+            window: 60,
+            key: RateLimitKey::Ip,
+        };
+        let (wide, narrow) = (limit(2), limit(1));
+        let buckets = [("wide".to_string(), &wide), ("narrow".to_string(), &narrow)];
+        assert_eq!(acquire_all(&store, &buckets).unwrap().unwrap().remaining, 0);
+        assert!(acquire_all(&store, &buckets).is_err());
+        assert!(acquire_all(&store, &buckets).is_err());
+        // the wide bucket still has the token the rejected requests took
+        let status = store.acquire("wide", &wide).unwrap();
+        assert_eq!(status.remaining, 0);
+    }
+
+    #[test]
+    fn client_key_should_prefer_the_header() {
+        let mut headers = HeaderMap::new();
+        let client = Some([10, 0, 0, 1].into());
+        let key = RateLimitKey::Header("x-api-key".into());
+        assert_eq!(client_key(&key, &headers, client), "10.0.0.1");
+        headers.insert("x-api-key", HeaderValue::from_static("abc"));
+        assert_eq!(client_key(&key, &headers, client), "x-api-key=abc");
+        assert_eq!(client_key(&RateLimitKey::Ip, &headers, None), "0.0.0.0");
+    }
+
+    #[test]
+    fn rotating_header_keys_should_keep_other_buckets() {
+        let store = MemoryStore::with_capacity(MAX_HEADER_KEYS_PER_IP + 2);
+        let limit = RateLimit {
+            requests: 2,
+            window: 60,
+            key: RateLimitKey::Header("x-api-key".into()),
+        };
+        let acquire = |value: String, client: [u8; 4]| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-api-key", HeaderValue::try_from(value).unwrap());
+            let key = client_key(&limit.key, &headers, Some(client.into()));
+            store.acquire(&key, &limit)
+        };
+        assert_eq!(acquire("a".into(), [10, 0, 1, 1]).unwrap().remaining, 1);
+        // past the values an ip may use, the rest share its bucket
+        let rotated = (0..MAX_HEADER_KEYS_PER_IP * 2)
+            .filter(|i| acquire(format!("b{i}"), [10, 0, 1, 2]).is_ok())
+            .count();
+        assert_eq!(rotated, MAX_HEADER_KEYS_PER_IP + 2);
+        assert_eq!(acquire("a".into(), [10, 0, 1, 1]).unwrap().remaining, 0);
+        assert!(acquire("a".into(), [10, 0, 1, 1]).is_err());
+    }
+}
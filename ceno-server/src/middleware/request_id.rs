@@ -0,0 +1,97 @@
+use std::{
+    future::Future,
+    task::{Context, Poll},
+};
+
+use super::REQUEST_ID_HEADER;
+use crate::trace;
+use axum::{extract::Request, http::HeaderValue, response::Response};
+use tower::{Layer, Service};
+use tracing::{info_span, Instrument as _};
+use uuid::Uuid;
+
+/// Longest `x-request-id` taken from a client
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The id of a request, kept in its extensions
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Gives every request an `x-request-id`, keeping the one it has if valid,
+/// and sends it back in the response
+#[derive(Clone)]
+pub struct RequestIdLayer;
+
+#[derive(Clone)]
+pub struct RequestIdMiddleware<S> {
+    inner: S,
+}
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdMiddleware { inner }
+    }
+}
+
+impl<S> Service<Request> for RequestIdMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = impl Future<Output = Result<Self::Response, Self::Error>> + Send + 'static;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        let id = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| valid(id))
+            .map_or_else(|| Uuid::new_v4().to_string(), String::from);
+        // valid ids are visible ASCII, as are uuids
+        let value = HeaderValue::from_str(&id).ok();
+        if let Some(value) = &value {
+            request
+                .headers_mut()
+                .insert(REQUEST_ID_HEADER, value.clone());
+        }
+        let span = info_span!("request", request_id = %id);
+        trace::set_parent(&span, request.headers());
+        request.extensions_mut().insert(RequestId(id));
+
+        let future = span.in_scope(|| self.inner.call(request));
+        async move {
+            let mut res = future.await?;
+            if let Some(value) = value {
+                res.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            Ok(res)
+        }
+        .instrument(span)
+    }
+}
+
+/// Whether an id sent by a client can be kept
+fn valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_should_reject_odd_ids() {
+        assert!(valid("0b9e8c1a-trace"));
+        assert!(!valid(""));
+        assert!(!valid("with space"));
+        assert!(!valid(&"x".repeat(MAX_REQUEST_ID_LEN + 1)));
+    }
+}
@@ -1,28 +1,61 @@
 use std::{
     future::Future,
+    sync::Arc,
     task::{Context, Poll},
 };
 
-use super::SERVER_TIME_HEADER;
+use super::{tenant_router, SERVER_TIME_HEADER};
+use crate::{metrics::UNMATCHED_ROUTE, Metrics, SwappableAppRouter};
 use axum::{extract::Request, response::Response};
+use dashmap::DashMap;
 use tokio::time::Instant;
 use tower::{Layer, Service};
 use tracing::warn;
 
+/// Sets `x-server-time`, and counts the requests of the tenants if there are
+/// metrics
 #[derive(Clone)]
-pub struct ServerTimeLayer;
+pub struct ServerTimeLayer {
+    routers: Arc<DashMap<String, SwappableAppRouter>>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl ServerTimeLayer {
+    pub fn new(
+        routers: Arc<DashMap<String, SwappableAppRouter>>,
+        metrics: Option<Arc<Metrics>>,
+    ) -> Self {
+        Self { routers, metrics }
+    }
+
+    /// The tenant and route labels of a request, unknown tenants aren't
+    /// counted
+    fn labels(&self, request: &Request) -> Option<(String, String)> {
+        self.metrics.as_ref()?;
+        let (host, router) = tenant_router(&self.routers, request)?;
+        let route = router
+            .router
+            .at(request.uri().path())
+            .map_or(UNMATCHED_ROUTE, |matched| matched.value.path.as_str());
+        Some((host.to_string(), route.to_string()))
+    }
+}
 
 impl<S> Layer<S> for ServerTimeLayer {
     type Service = ServerTimeMiddleware<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        ServerTimeMiddleware { inner }
+        ServerTimeMiddleware {
+            inner,
+            layer: self.clone(),
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct ServerTimeMiddleware<S> {
     inner: S,
+    layer: ServerTimeLayer,
 }
 
 impl<S> Service<Request> for ServerTimeMiddleware<S>
@@ -40,10 +73,17 @@ where
 
     fn call(&mut self, request: Request) -> Self::Future {
         let start = Instant::now();
+        let labels = self.layer.labels(&request);
+        let method = request.method().clone();
+        let metrics = self.layer.metrics.clone();
         let future = self.inner.call(request);
         async move {
             let mut res: Response = future.await?;
-            let elapsed = format!("{}us", start.elapsed().as_micros());
+            let elapsed = start.elapsed();
+            if let (Some(metrics), Some((tenant, route))) = (metrics, labels) {
+                metrics.observe_request(&tenant, &route, &method, res.status(), elapsed);
+            }
+            let elapsed = format!("{}us", elapsed.as_micros());
             match elapsed.parse() {
                 Ok(v) => {
                     res.headers_mut().insert(SERVER_TIME_HEADER, v);
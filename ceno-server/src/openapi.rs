@@ -0,0 +1,258 @@
+use crate::{
+    config::{ProjectConfig, ProjectRoute, RouteAction, RouteMethods, SchemaSource},
+    params::{ParamKind, RoutePath},
+};
+use anyhow::Result;
+use axum::http::Method;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashSet};
+
+/// Methods an `ANY` route is documented with
+const ANY_METHODS: [Method; 5] = [
+    Method::GET,
+    Method::POST,
+    Method::PUT,
+    Method::PATCH,
+    Method::DELETE,
+];
+
+/// Generate the OpenAPI 3.1 document of the routes of `config`.
+///
+/// Path params are described by their type in the route path, unless the
+/// `params` schema of the route has a property for them. The `query`, `body`
+/// and `response` schemas become the query params, request body and 200
+/// response. Extension methods can't be described, so they are left out.
+pub fn openapi(config: &ProjectConfig) -> Result<Value> {
+    // sorted, so the document is stable
+    let mut paths = BTreeMap::<String, Map<String, Value>>::new();
+    let mut security_schemes = Map::new();
+    let mut operation_ids = HashSet::new();
+
+    let mut routes = config.routes.iter().collect::<Vec<_>>();
+    routes.sort_by_key(|(path, _)| path.as_str());
+    for (path, methods) in routes {
+        let route_path = RoutePath::parse(path)?;
+        let declared = methods
+            .iter()
+            .filter_map(|route| match &route.method {
+                RouteMethods::List(methods) => Some(methods.clone()),
+                RouteMethods::Any => None,
+            })
+            .flatten()
+            .collect::<HashSet<_>>();
+
+        for route in methods {
+            let methods = match &route.method {
+                RouteMethods::List(methods) => methods.clone(),
+                RouteMethods::Any => ANY_METHODS
+                    .into_iter()
+                    .filter(|m| !declared.contains(m))
+                    .collect(),
+            };
+            for compiled in &route_path.paths {
+                let item = paths.entry(to_template(compiled)).or_default();
+                for method in &methods {
+                    let Some(key) = method_key(method) else {
+                        continue;
+                    };
+                    let mut operation = operation(route, compiled, &route_path)?;
+                    if let Some(handler) = route.action.handler() {
+                        if operation_ids.insert(handler.to_string()) {
+                            operation.insert("operationId".into(), handler.into());
+                        }
+                    }
+                    if let Some(scheme) = &route.settings.auth {
+                        let name = scheme.to_lowercase();
+                        security_schemes
+                            .insert(name.clone(), json!({ "type": "http", "scheme": name }));
+                        operation.insert("security".into(), json!([{ name: [] }]));
+                    }
+                    item.insert(key.into(), operation.into());
+                }
+            }
+        }
+    }
+
+    let mut info = json!({
+        "title": config.openapi.title.as_deref().unwrap_or(&config.name),
+        "version": config.openapi.version,
+    });
+    if let Some(description) = &config.openapi.description {
+        info["description"] = description.clone().into();
+    }
+    let mut doc = json!({ "openapi": "3.1.0", "info": info, "paths": paths });
+    if !security_schemes.is_empty() {
+        doc["components"] = json!({ "securitySchemes": security_schemes });
+    }
+    Ok(doc)
+}
+
+fn operation(route: &ProjectRoute, compiled: &str, path: &RoutePath) -> Result<Map<String, Value>> {
+    let schemas = route.settings.schema.clone().unwrap_or_default();
+    let load = |source: &Option<SchemaSource>| source.as_ref().map(|s| s.load()).transpose();
+    let params_schema = load(&schemas.params)?;
+    let query_schema = load(&schemas.query)?;
+
+    let mut parameters = Vec::new();
+    for segment in compiled.split('/') {
+        let Some(name) = segment.strip_prefix([':', '*']) else {
+            continue;
+        };
+        let schema = property(params_schema.as_ref(), name).unwrap_or_else(|| {
+            path.params
+                .get(name)
+                .map_or(json!({ "type": "string" }), kind_schema)
+        });
+        parameters.push(json!({ "name": name, "in": "path", "required": true, "schema": schema }));
+    }
+    if let Some(Value::Object(properties)) = query_schema.as_ref().and_then(|s| s.get("properties"))
+    {
+        let required = required(query_schema.as_ref());
+        for (name, schema) in properties {
+            parameters.push(json!({
+                "name": name,
+                "in": "query",
+                "required": required.contains(name),
+                "schema": schema,
+            }));
+        }
+    }
+
+    let mut operation = Map::new();
+    if let Some(handler) = route.action.handler() {
+        operation.insert("x-handler".into(), handler.into());
+    }
+    if !parameters.is_empty() {
+        operation.insert("parameters".into(), parameters.into());
+    }
+    if let Some(schema) = load(&schemas.body)? {
+        operation.insert(
+            "requestBody".into(),
+            json!({ "required": true, "content": { "application/json": { "schema": schema } } }),
+        );
+    }
+    let responses = match &route.action {
+        RouteAction::Redirect(redirect) => json!({
+            redirect.status.to_string(): { "description": format!("Redirect to {}", redirect.to) }
+        }),
+        _ => {
+            let mut ok = json!({ "description": "Successful response" });
+            if let Some(schema) = load(&schemas.response)? {
+                ok["content"] = json!({ "application/json": { "schema": schema } });
+            }
+            json!({ "200": ok })
+        }
+    };
+    operation.insert("responses".into(), responses);
+    Ok(operation)
+}
+
+fn kind_schema(kind: &ParamKind) -> Value {
+    match kind {
+        ParamKind::String => json!({ "type": "string" }),
+        ParamKind::Int => json!({ "type": "integer" }),
+        ParamKind::Float => json!({ "type": "number" }),
+        ParamKind::Bool => json!({ "type": "boolean" }),
+        ParamKind::Uuid => json!({ "type": "string", "format": "uuid" }),
+        ParamKind::Enum(values) => json!({ "type": "string", "enum": values }),
+        ParamKind::Regex(re) => json!({ "type": "string", "pattern": re.as_str() }),
+    }
+}
+
+fn property(schema: Option<&Value>, name: &str) -> Option<Value> {
+    schema?.get("properties")?.get(name).cloned()
+}
+
+fn required(schema: Option<&Value>) -> Vec<String> {
+    schema
+        .and_then(|s| s.get("required"))
+        .and_then(|r| serde_json::from_value(r.clone()).ok())
+        .unwrap_or_default()
+}
+
+/// `/users/:id/*rest` to `/users/{id}/{rest}`
+fn to_template(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match segment.strip_prefix([':', '*']) {
+            Some(name) => format!("{{{name}}}"),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn method_key(method: &Method) -> Option<&'static str> {
+    let key = match *method {
+        Method::GET => "get",
+        Method::PUT => "put",
+        Method::POST => "post",
+        Method::DELETE => "delete",
+        Method::OPTIONS => "options",
+        Method::HEAD => "head",
+        Method::PATCH => "patch",
+        Method::TRACE => "trace",
+        _ => return None,
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openapi_should_describe_routes() {
+        let config: ProjectConfig = serde_yaml::from_str(
+            r#"
+name: shop
+openapi:
+  version: 1.2.0
+routes:
+  /items/{id:int}/{tab?:info|stock}:
+    - method: [GET, PURGE]
+      handler: item
+      auth: Bearer
+      schema:
+        query: { type: object, required: [lang], properties: { lang: { type: string } } }
+        response: { type: object }
+  /files/{*path}:
+    - method: ANY
+      handler: files
+    - method: GET
+      handler: file
+"#,
+        )
+        .unwrap();
+        let doc = openapi(&config).unwrap();
+
+        assert_eq!(doc["openapi"], "3.1.0");
+        assert_eq!(doc["info"], json!({ "title": "shop", "version": "1.2.0" }));
+        let item = &doc["paths"]["/items/{id}/{tab}"]["get"];
+        assert_eq!(item["operationId"], "item");
+        assert_eq!(
+            item["parameters"][0],
+            json!({ "name": "id", "in": "path", "required": true, "schema": { "type": "integer" } })
+        );
+        assert_eq!(
+            item["parameters"][1]["schema"]["enum"],
+            json!(["info", "stock"])
+        );
+        assert_eq!(item["parameters"][2]["in"], "query");
+        assert_eq!(item["parameters"][2]["required"], true);
+        assert_eq!(item["security"], json!([{ "bearer": [] }]));
+        assert_eq!(
+            item["responses"]["200"]["content"]["application/json"]["schema"],
+            json!({ "type": "object" })
+        );
+        assert!(doc["paths"]["/items/{id}"]["get"].is_object());
+        assert!(doc["paths"]["/items/{id}/{tab}"].get("purge").is_none());
+        assert_eq!(
+            doc["components"]["securitySchemes"]["bearer"],
+            json!({ "type": "http", "scheme": "bearer" })
+        );
+
+        let files = &doc["paths"]["/files/{path}"];
+        assert_eq!(files["get"]["x-handler"], "file");
+        assert_eq!(files["delete"]["x-handler"], "files");
+    }
+}
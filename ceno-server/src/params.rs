@@ -0,0 +1,238 @@
+use crate::{AppError, ParamValue};
+use anyhow::{anyhow, bail, Result};
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Largest integer a JS number holds exactly
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+/// A route path of the config compiled for `matchit`, along with the types of
+/// its params.
+///
+/// Params take a whole segment: `{id}`, `{id:int}`, `{id:float}`,
+/// `{id:bool}`, `{id:uuid}`, `{kind:a|b|c}` for one of the values,
+/// `{slug:/[a-z-]+/}` for a regex, and `{*rest}` for the rest of the path. The
+/// last segment may be optional with `{page?}` or `{page?:int}`. `matchit`
+/// params like `:id` and `*rest` are accepted as strings.
+#[derive(Debug)]
+pub(crate) struct RoutePath {
+    /// the path with and, if its last param is optional, without it
+    pub paths: Vec<String>,
+    pub params: PathParams,
+}
+
+pub(crate) type PathParams = HashMap<String, ParamKind>;
+
+#[derive(Debug, Clone)]
+pub enum ParamKind {
+    String,
+    Int,
+    Float,
+    Bool,
+    Uuid,
+    Enum(Vec<String>),
+    Regex(Regex),
+}
+
+impl RoutePath {
+    pub fn parse(path: &str) -> Result<Self> {
+        let segments = split_segments(path);
+        let mut params = HashMap::new();
+        let mut compiled = Vec::with_capacity(segments.len());
+        let mut optional = false;
+
+        for (i, segment) in segments.iter().enumerate() {
+            let Some(inner) = segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) else {
+                if segment.contains(['{', '}']) {
+                    bail!("invalid route {path}: a param must take a whole segment");
+                }
+                compiled.push(segment.to_string());
+                continue;
+            };
+
+            if let Some(name) = inner.strip_prefix('*') {
+                compiled.push(format!("*{name}"));
+                continue;
+            }
+            let (name, kind) = match inner.split_once(':') {
+                Some((name, kind)) => (name, ParamKind::parse(kind)?),
+                None => (inner, ParamKind::String),
+            };
+            let name = match name.strip_suffix('?') {
+                Some(name) if i == segments.len() - 1 => {
+                    optional = true;
+                    name
+                }
+                Some(_) => bail!("invalid route {path}: only the last param may be optional"),
+                None => name,
+            };
+            if name.is_empty() {
+                bail!("invalid route {path}: a param must have a name");
+            }
+            params.insert(name.to_string(), kind);
+            compiled.push(format!(":{name}"));
+        }
+
+        let full = compiled.join("/");
+        let paths = if optional {
+            let short = compiled[..compiled.len() - 1].join("/");
+            let short = if short.is_empty() { "/".into() } else { short };
+            vec![full, short]
+        } else {
+            vec![full]
+        };
+        Ok(Self { paths, params })
+    }
+}
+
+impl ParamKind {
+    fn parse(kind: &str) -> Result<Self> {
+        let kind = match kind {
+            "string" => Self::String,
+            "int" => Self::Int,
+            "float" => Self::Float,
+            "bool" => Self::Bool,
+            "uuid" => Self::Uuid,
+            _ => {
+                if let Some(re) = kind.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+                    Self::Regex(Regex::new(&format!("^(?:{re})$"))?)
+                } else if kind.contains('|') {
+                    Self::Enum(kind.split('|').map(str::to_string).collect())
+                } else {
+                    return Err(anyhow!("unknown param type {kind}"));
+                }
+            }
+        };
+        Ok(kind)
+    }
+
+    fn expected(&self) -> &'static str {
+        match self {
+            Self::Int => "an integer",
+            Self::Float => "a number",
+            Self::Bool => "true or false",
+            Self::Uuid => "a uuid",
+            Self::String | Self::Enum(_) | Self::Regex(_) => "a string",
+        }
+    }
+}
+
+/// Split `path` on the slashes outside of braces, which may be part of a regex
+fn split_segments(path: &str) -> Vec<&str> {
+    let mut segments = Vec::new();
+    let (mut depth, mut start) = (0usize, 0);
+    for (i, c) in path.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            '/' if depth == 0 => {
+                segments.push(&path[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    segments.push(&path[start..]);
+    segments
+}
+
+/// Validate the params matched on `path`, converting the typed ones. Values
+/// of the wrong type are a bad request, while values failing an enum or regex
+/// constraint don't match the route at all.
+pub(crate) fn coerce<'a>(
+    kinds: &PathParams,
+    path: &str,
+    params: impl Iterator<Item = (&'a str, &'a str)>,
+) -> Result<HashMap<String, ParamValue>, AppError> {
+    params
+        .map(|(name, raw)| {
+            let invalid = |kind: &ParamKind| {
+                AppError::RouteParamInvalid(name.to_string(), kind.expected().to_string())
+            };
+            let value = match kinds.get(name).unwrap_or(&ParamKind::String) {
+                ParamKind::String => ParamValue::String(raw.to_string()),
+                kind @ ParamKind::Int => raw
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|v| v.abs() <= MAX_SAFE_INTEGER)
+                    .map(ParamValue::Int)
+                    .ok_or_else(|| invalid(kind))?,
+                kind @ ParamKind::Float => raw
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|v| v.is_finite())
+                    .map(ParamValue::Float)
+                    .ok_or_else(|| invalid(kind))?,
+                kind @ ParamKind::Bool => raw
+                    .parse::<bool>()
+                    .map(ParamValue::Bool)
+                    .map_err(|_| invalid(kind))?,
+                kind @ ParamKind::Uuid => uuid::Uuid::parse_str(raw)
+                    .map(|_| ParamValue::String(raw.to_string()))
+                    .map_err(|_| invalid(kind))?,
+                ParamKind::Enum(values) if values.iter().any(|v| v == raw) => {
+                    ParamValue::String(raw.to_string())
+                }
+                ParamKind::Regex(re) if re.is_match(raw) => ParamValue::String(raw.to_string()),
+                ParamKind::Enum(_) | ParamKind::Regex(_) => {
+                    return Err(AppError::RoutePathNotFound(path.to_string()));
+                }
+            };
+            Ok((name.to_string(), value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_path_should_parse() {
+        let route = RoutePath::parse("/users/{id:int}/posts/{page?:int}").unwrap();
+        assert_eq!(route.paths, ["/users/:id/posts/:page", "/users/:id/posts"]);
+        assert!(matches!(route.params["id"], ParamKind::Int));
+        assert!(matches!(route.params["page"], ParamKind::Int));
+
+        let route = RoutePath::parse("/{kind:a|b}/{slug:/[a-z]{2,}/}/{*rest}").unwrap();
+        assert_eq!(route.paths, ["/:kind/:slug/*rest"]);
+        assert!(matches!(&route.params["kind"], ParamKind::Enum(v) if v == &["a", "b"]));
+
+        assert_eq!(RoutePath::parse("/{page?}").unwrap().paths, ["/:page", "/"]);
+        assert_eq!(RoutePath::parse("/api/:id").unwrap().paths, ["/api/:id"]);
+        assert!(RoutePath::parse("/{a?}/b").is_err());
+        assert!(RoutePath::parse("/{id:number}").is_err());
+        assert!(RoutePath::parse("/v{version}").is_err());
+    }
+
+    #[test]
+    fn coerce_should_convert_and_validate() {
+        let route = RoutePath::parse("/{id:int}/{ok:bool}/{kind:a|b}/{slug:/[a-z]+/}").unwrap();
+        let coerce = |values: [&'static str; 4]| {
+            let names = ["id", "ok", "kind", "slug"];
+            coerce(&route.params, "/", names.into_iter().zip(values))
+        };
+
+        let params = coerce(["42", "true", "a", "abc"]).unwrap();
+        assert_eq!(params["id"], ParamValue::Int(42));
+        assert_eq!(params["ok"], ParamValue::Bool(true));
+        assert_eq!(params["slug"], ParamValue::String("abc".into()));
+
+        assert!(matches!(
+            coerce(["x", "true", "a", "abc"]),
+            Err(AppError::RouteParamInvalid(name, _)) if name == "id"
+        ));
+        assert!(matches!(
+            coerce(["9007199254740993", "true", "a", "abc"]),
+            Err(AppError::RouteParamInvalid(..))
+        ));
+        assert!(matches!(
+            coerce(["1", "true", "c", "abc"]),
+            Err(AppError::RoutePathNotFound(_))
+        ));
+        assert!(matches!(
+            coerce(["1", "true", "a", "ab1"]),
+            Err(AppError::RoutePathNotFound(_))
+        ));
+    }
+}
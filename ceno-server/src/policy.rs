@@ -0,0 +1,413 @@
+use crate::{
+    config::{CorsPolicy, RateLimit, RouteSettings},
+    middleware::{client_key, RateLimitStatus, RateLimitStore},
+    AppError, AppRouter, Endpoint,
+};
+use axum::{
+    body::{Body, Bytes},
+    http::{
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+        request::Parts,
+        Method, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use std::net::IpAddr;
+
+/// Max request body when a route doesn't set `max_body`
+pub const DEFAULT_MAX_BODY: usize = 2 * 1024 * 1024;
+
+/// Rate limit of a route, whose buckets are kept in the store shared by the
+/// tenants, keyed by the route, e.g. `GET,POST /users`
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    route: String,
+}
+
+impl RateLimiter {
+    pub fn new(limit: &RateLimit, route: impl Into<String>) -> Self {
+        Self {
+            limit: limit.clone(),
+            route: route.into(),
+        }
+    }
+
+    /// Take a token of the client sending the request to the route of `host`
+    pub fn acquire(
+        &self,
+        store: &dyn RateLimitStore,
+        host: &str,
+        parts: &Parts,
+        client: Option<IpAddr>,
+    ) -> Result<RateLimitStatus, RateLimitStatus> {
+        let client = client_key(&self.limit.key, &parts.headers, client);
+        let key = format!("{host}/{}/{client}", self.route);
+        store.acquire(&key, &self.limit)
+    }
+}
+
+/// Respond to a CORS preflight for a route with a `cors` policy. Returns
+/// `None` if the request isn't one, so it's routed as usual.
+pub(crate) fn preflight(router: &AppRouter, parts: &Parts) -> Result<Option<Response>, AppError> {
+    if parts.method != Method::OPTIONS {
+        return Ok(None);
+    }
+    let (Some(origin), Some(requested)) = (
+        parts.headers.get(header::ORIGIN),
+        parts.headers.get(header::ACCESS_CONTROL_REQUEST_METHOD),
+    ) else {
+        return Ok(None);
+    };
+    let Ok(method) = Method::from_bytes(requested.as_bytes()) else {
+        return Ok(None);
+    };
+    let Ok(matched) = router.match_it(method, parts.uri.path()) else {
+        return Ok(None);
+    };
+    let Some(cors) = &matched.value.settings.cors else {
+        return Ok(None);
+    };
+
+    let forbidden = || AppError::CorsForbidden(origin.to_str().unwrap_or_default().to_string());
+    let allow_origin = allowed_origin(cors, origin).ok_or_else(forbidden)?;
+    let allow_headers = match parts.headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+        Some(requested) if !cors.headers.is_empty() => {
+            let requested = requested.to_str().map_err(|_| forbidden())?;
+            let allowed = requested.split(',').map(str::trim).all(|name| {
+                name.is_empty() || cors.headers.iter().any(|h| h.eq_ignore_ascii_case(name))
+            });
+            if !allowed {
+                return Err(forbidden());
+            }
+            Some(requested.to_string())
+        }
+        Some(requested) => Some(requested.to_str().unwrap_or_default().to_string()),
+        None => None,
+    };
+
+    let mut res = StatusCode::NO_CONTENT.into_response();
+    let headers = res.headers_mut();
+    add_allow_origin(cors, allow_origin, headers);
+    headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, requested.clone());
+    if let Some(value) = allow_headers.and_then(|v| HeaderValue::from_str(&v).ok()) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, value);
+    }
+    if let Some(max_age) = cors.max_age {
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, max_age.into());
+    }
+    Ok(Some(res))
+}
+
+/// Reject the request if it breaks the auth scheme or rate limit of `endpoint`
+pub(crate) fn check(
+    endpoint: &Endpoint,
+    store: &dyn RateLimitStore,
+    host: &str,
+    parts: &Parts,
+    client: Option<IpAddr>,
+) -> Result<(), AppError> {
+    if let Some(scheme) = &endpoint.settings.auth {
+        let authorized = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split_once(' '))
+            .is_some_and(|(s, credentials)| {
+                s.eq_ignore_ascii_case(scheme) && !credentials.trim().is_empty()
+            });
+        if !authorized {
+            return Err(AppError::Unauthorized(scheme.clone()));
+        }
+    }
+
+    if let Some(limiter) = &endpoint.rate_limiter {
+        limiter
+            .acquire(store, host, parts, client)
+            .map_err(AppError::TooManyRequests)?;
+    }
+    Ok(())
+}
+
+/// The `max_body` of the route, if the declared length of the body is within it
+pub(crate) fn body_limit(settings: &RouteSettings, parts: &Parts) -> Result<usize, AppError> {
+    let limit = settings.max_body.unwrap_or(DEFAULT_MAX_BODY);
+    let length = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if length.is_some_and(|length| length > limit) {
+        return Err(AppError::PayloadTooLarge(limit));
+    }
+    Ok(limit)
+}
+
+/// Read the body, up to `max_body` of the route
+pub(crate) async fn read_body(
+    settings: &RouteSettings,
+    parts: &Parts,
+    body: Body,
+) -> Result<Bytes, AppError> {
+    let limit = body_limit(settings, parts)?;
+    axum::body::to_bytes(body, limit)
+        .await
+        .map_err(|_| AppError::PayloadTooLarge(limit))
+}
+
+/// Add the CORS and cache headers of the route to the handler's response
+pub(crate) fn apply(settings: &RouteSettings, parts: &Parts, res: &mut Response) {
+    apply_cache(settings, res);
+    apply_cors(settings, parts, res);
+}
+
+/// Add the CORS headers of the route, errors get them too so browsers can
+/// read their status
+pub(crate) fn apply_cors(settings: &RouteSettings, parts: &Parts, res: &mut Response) {
+    let Some(cors) = &settings.cors else {
+        return;
+    };
+    let Some(allow_origin) = parts
+        .headers
+        .get(header::ORIGIN)
+        .and_then(|origin| allowed_origin(cors, origin))
+    else {
+        return;
+    };
+    let headers = res.headers_mut();
+    add_allow_origin(cors, allow_origin, headers);
+    if let Some(value) = join_header(&cors.expose) {
+        headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, value);
+    }
+}
+
+/// Add the `Cache-Control` of the route if the response has none
+pub(crate) fn apply_cache(settings: &RouteSettings, res: &mut Response) {
+    let Some(cache) = &settings.cache else {
+        return;
+    };
+    let headers = res.headers_mut();
+    if !headers.contains_key(header::CACHE_CONTROL) {
+        if let Ok(value) = HeaderValue::from_str(cache) {
+            headers.insert(header::CACHE_CONTROL, value);
+        }
+    }
+}
+
+/// The `Access-Control-Allow-Origin` for `origin`, `None` if not allowed.
+/// Credentials can't be used with `*`, so the origin is echoed instead.
+fn allowed_origin(cors: &CorsPolicy, origin: &HeaderValue) -> Option<HeaderValue> {
+    let any = cors.origins.iter().any(|o| o == "*");
+    if any && !cors.credentials {
+        return Some(HeaderValue::from_static("*"));
+    }
+    let origin_str = origin.to_str().ok()?;
+    (any || cors.origins.iter().any(|o| o == origin_str)).then(|| origin.clone())
+}
+
+fn add_allow_origin(cors: &CorsPolicy, allow_origin: HeaderValue, headers: &mut HeaderMap) {
+    if allow_origin != "*" {
+        headers.append(header::VARY, HeaderValue::from_static("origin"));
+    }
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+    if cors.credentials {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+}
+
+fn join_header(names: &[String]) -> Option<HeaderValue> {
+    let names = names
+        .iter()
+        .filter(|name| HeaderName::from_bytes(name.as_bytes()).is_ok())
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if names.is_empty() {
+        return None;
+    }
+    HeaderValue::from_str(&names.join(", ")).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::RateLimitKey, MemoryStore, ProjectConfig, SwappableAppRouter};
+    use axum::http::Request;
+
+    const CONFIG: &str = r#"
+name: test
+routes:
+  /api/items:
+    - method: GET
+      handler: list
+      cache: public, max-age=60
+      cors:
+        origins: ["https://example.com"]
+        headers: [content-type]
+        expose: [x-total]
+        credentials: true
+        max_age: 600
+    - method: POST
+      handler: create
+      auth: Bearer
+      max_body: 4
+      rate_limit:
+        requests: 2
+        window: 10
+"#;
+
+    fn router() -> AppRouter {
+        let config: ProjectConfig = serde_yaml::from_str(CONFIG).unwrap();
+        SwappableAppRouter::try_new("", &config).unwrap().load()
+    }
+
+    fn parts(method: Method, headers: &[(&str, &str)]) -> Parts {
+        let mut req = Request::builder().method(method).uri("/api/items");
+        for (k, v) in headers {
+            req = req.header(*k, *v);
+        }
+        req.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn rate_limiter_should_tell_clients_apart() {
+        let limit = RateLimit {
+            requests: 1,
+            window: 10,
+            key: RateLimitKey::Header("x-api-key".into()),
+        };
+        let limiter = RateLimiter::new(&limit, "GET /api/items");
+        let store = MemoryStore::default();
+        let acquire =
+            |host: &str, parts: &Parts, client| limiter.acquire(&store, host, parts, client);
+        let (client, other) = (Some([127, 0, 0, 1].into()), Some([127, 0, 0, 2].into()));
+        let key = parts(Method::GET, &[("x-api-key", "a")]);
+        assert!(acquire("a.com", &key, client).is_ok());
+        assert_eq!(acquire("a.com", &key, other).unwrap_err().reset, 10);
+        assert!(acquire("b.com", &key, other).is_ok());
+        assert!(acquire("a.com", &parts(Method::GET, &[]), client).is_ok());
+        assert!(acquire("a.com", &parts(Method::GET, &[]), other).is_ok());
+        // the buckets are the ones of the store, which outlive the router
+        let limiter = RateLimiter::new(&limit, "GET /api/items");
+        assert!(limiter.acquire(&store, "a.com", &key, client).is_err());
+    }
+
+    #[test]
+    fn preflight_should_follow_cors_policy() {
+        let router = router();
+        let res = preflight(
+            &router,
+            &parts(
+                Method::OPTIONS,
+                &[
+                    ("origin", "https://example.com"),
+                    ("access-control-request-method", "GET"),
+                    ("access-control-request-headers", "Content-Type"),
+                ],
+            ),
+        )
+        .unwrap()
+        .unwrap();
+        let headers = res.headers();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "Content-Type"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        let denied = |headers: &[(&str, &str)]| {
+            matches!(
+                preflight(&router, &parts(Method::OPTIONS, headers)),
+                Err(AppError::CorsForbidden(_))
+            )
+        };
+        assert!(denied(&[
+            ("origin", "https://evil.com"),
+            ("access-control-request-method", "GET"),
+        ]));
+        assert!(denied(&[
+            ("origin", "https://example.com"),
+            ("access-control-request-method", "GET"),
+            ("access-control-request-headers", "x-secret"),
+        ]));
+
+        // POST has no cors policy, routed as usual
+        let res = preflight(
+            &router,
+            &parts(
+                Method::OPTIONS,
+                &[
+                    ("origin", "https://example.com"),
+                    ("access-control-request-method", "POST"),
+                ],
+            ),
+        );
+        assert!(res.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn check_should_enforce_auth_limits() {
+        let router = router();
+        let endpoint = router.match_it(Method::POST, "/api/items").unwrap().value;
+        let client = Some([10, 0, 0, 1].into());
+        let store = MemoryStore::default();
+        let check = |parts: &Parts, client| check(endpoint, &store, "localhost", parts, client);
+
+        let anonymous = parts(Method::POST, &[]);
+        assert!(matches!(
+            check(&anonymous, client),
+            Err(AppError::Unauthorized(_))
+        ));
+        let basic = parts(Method::POST, &[("authorization", "Basic YTpi")]);
+        assert!(check(&basic, client).is_err());
+
+        let authorized = parts(Method::POST, &[("authorization", "bearer token")]);
+        assert!(check(&authorized, client).is_ok());
+        assert!(check(&authorized, client).is_ok());
+        assert!(matches!(
+            check(&authorized, client),
+            Err(AppError::TooManyRequests(RateLimitStatus { reset: 5, .. }))
+        ));
+
+        let body = read_body(&endpoint.settings, &authorized, Body::from("12345")).await;
+        assert!(matches!(body, Err(AppError::PayloadTooLarge(4))));
+        let body = read_body(&endpoint.settings, &authorized, Body::from("1234")).await;
+        assert_eq!(body.unwrap(), "1234");
+    }
+
+    #[test]
+    fn apply_should_add_cors_and_cache_headers() {
+        let router = router();
+        let endpoint = router.match_it(Method::GET, "/api/items").unwrap().value;
+
+        let mut res = StatusCode::OK.into_response();
+        let origin = parts(Method::GET, &[("origin", "https://example.com")]);
+        apply(&endpoint.settings, &origin, &mut res);
+        let headers = res.headers();
+        assert_eq!(headers[header::CACHE_CONTROL], "public, max-age=60");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_EXPOSE_HEADERS], "x-total");
+        assert_eq!(headers[header::VARY], "origin");
+
+        // the handler's own cache-control is kept, unknown origins get no cors headers
+        let mut res = ([(header::CACHE_CONTROL, "no-store")], "").into_response();
+        let other = parts(Method::GET, &[("origin", "https://evil.com")]);
+        apply(&endpoint.settings, &other, &mut res);
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "no-store");
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
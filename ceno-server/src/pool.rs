@@ -1,38 +1,100 @@
+use anyhow::{anyhow, Result};
 use arc_swap::ArcSwap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::thread;
+use std::time::Duration;
 use tokio::sync::oneshot;
-use tracing::{info, instrument, Span};
+use tracing::{error, info, instrument, warn, Span};
 
 use crate::engine::JsWorker;
-use crate::{Req, Res};
+use crate::{JsCode, Req, Res, Services};
+
+/// Extra time a worker spends on `ctx.waitUntil` promises after responding
+const WAIT_UNTIL_TIMEOUT: Duration = Duration::from_secs(5);
 
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
 }
 
+/// Load of a pool, exposed as metrics
+#[derive(Debug)]
+pub struct PoolStats {
+    /// requests waiting for a worker
+    pub queued: AtomicUsize,
+    /// workers running a request or its `waitUntil` work
+    pub busy: AtomicUsize,
+    /// bytes allocated by the runtime of each worker
+    pub heap: Vec<AtomicU64>,
+}
+
 impl Worker {
     /// Initialize and run worker in a background thread, get request via mpsc channel
     /// once the request is processed, the response will send back
     /// through an oneshot channel
-    fn new(id: usize, code: &str, receiver: Arc<Mutex<Receiver<Message>>>) -> Worker {
-        let code = code.to_string();
+    ///
+    /// The exported `onInit` runs before the first request, and `onShutdown`
+    /// once the worker is told to terminate. Whether the code loaded and
+    /// `onInit` succeeded is sent on `ready`, the worker stops if not.
+    fn new(
+        id: usize,
+        code: Arc<JsCode>,
+        services: Services,
+        receiver: Arc<Mutex<Receiver<Message>>>,
+        stats: Arc<PoolStats>,
+        ready: Sender<Result<()>>,
+    ) -> Worker {
         let thread = thread::spawn(move || {
-            let js = JsWorker::try_new(&code).unwrap();
+            let loaded =
+                JsWorker::try_load(&code, &services).and_then(|js| js.on_init().map(|_| js));
+            let js = match loaded {
+                Ok(js) => {
+                    let _ = ready.send(Ok(()));
+                    js
+                }
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+            // the pool waits on `ready` until every worker is done with it
+            drop(ready);
+            stats.heap[id].store(js.heap_size(), Ordering::Relaxed);
             loop {
-                let message = receiver.lock().unwrap().recv().unwrap();
+                let receiver = receiver.lock().unwrap_or_else(PoisonError::into_inner);
+                let Ok(message) = receiver.recv() else {
+                    break;
+                };
+                drop(receiver);
                 match message {
                     Message::NewRequest(req) => {
+                        stats.queued.fetch_sub(1, Ordering::Relaxed);
+                        stats.busy.fetch_add(1, Ordering::Relaxed);
                         let _span = req.span.enter();
 
                         info!("Worker {} got a job; executing.", id);
-                        let res = js.run(&req.handler, req.req).unwrap();
-                        req.tx.send(res).expect("should send");
+                        match js.run_with(&req.handler, &req.middleware, req.req) {
+                            // the caller may have given up waiting
+                            Ok(res) => {
+                                let _ = req.tx.send(res);
+                            }
+                            // dropping `tx` tells the caller the handler failed
+                            Err(e) => error!("Worker {} run handler failed: {}", id, e),
+                        }
+                        // finish the work of `ctx.waitUntil` before taking the next job
+                        if let Err(e) = js.drain(WAIT_UNTIL_TIMEOUT) {
+                            warn!("Worker {} drain failed: {}", id, e);
+                        }
+                        stats.heap[id].store(js.heap_size(), Ordering::Relaxed);
+                        stats.busy.fetch_sub(1, Ordering::Relaxed);
                     }
                     Message::Terminate => {
                         info!("Worker {} was told to terminate.", id);
+                        if let Err(e) = js.on_shutdown() {
+                            error!("Worker {} shutdown failed: {}", id, e);
+                        }
                         break;
                     }
                 }
@@ -49,6 +111,8 @@ impl Worker {
 pub struct Request {
     req: Req,
     handler: String,
+    /// of the route, run inside the global middleware
+    middleware: Vec<String>,
     tx: oneshot::Sender<Res>,
     span: Span,
 }
@@ -58,6 +122,7 @@ impl Request {
         Self {
             req,
             handler: handler.to_string(),
+            middleware: Vec::new(),
             tx,
             span,
         }
@@ -65,7 +130,7 @@ impl Request {
 }
 
 enum Message {
-    NewRequest(Request),
+    NewRequest(Box<Request>),
     Terminate,
 }
 
@@ -76,29 +141,39 @@ enum Message {
 pub struct ThreadPool {
     workers: Vec<Worker>,
     sender: Sender<Message>,
+    stats: Arc<PoolStats>,
 }
 
 /// `SwappableThreadPool` wraps around a `ThreadPool` using `ArcSwap`
 /// to allow atomic swapping of thread pools at runtime.
 ///
 /// - `inner`: An `Arc`-wrapped `ArcSwap` holding the current `ThreadPool`.
+/// - `services`: Host services shared by the pools swapped in.
 #[derive(Clone)]
 pub struct SwappableThreadPool {
     inner: Arc<ArcSwap<ThreadPool>>,
+    services: Services,
 }
 
 impl SwappableThreadPool {
-    pub fn new(code: &str) -> Self {
-        let inner = ThreadPool::new(4, code);
-        Self {
+    pub fn new(code: impl Into<JsCode>) -> Result<Self> {
+        Self::with_services(code, Services::default())
+    }
+
+    pub fn with_services(code: impl Into<JsCode>, services: Services) -> Result<Self> {
+        let inner = ThreadPool::with_services(4, code, services.clone())?;
+        Ok(Self {
             inner: Arc::new(ArcSwap::from_pointee(inner)),
-        }
+            services,
+        })
     }
 
-    /// Swaps the current `ThreadPool` with a new one.
-    pub fn swap(&self, code: &str) {
-        let inner = ThreadPool::new(4, code);
-        self.inner.store(Arc::new(inner))
+    /// Swaps the current `ThreadPool` with a new one, the current one keeps
+    /// serving if the new code fails to load.
+    pub fn swap(&self, code: impl Into<JsCode>) -> Result<()> {
+        let inner = ThreadPool::with_services(4, code, self.services.clone())?;
+        self.inner.store(Arc::new(inner));
+        Ok(())
     }
 
     /// Loads the current `ThreadPool` being used.
@@ -111,31 +186,90 @@ impl ThreadPool {
     /// Initialize thread pool
     ///
     /// `size` is the background threads count
-    pub fn new(size: usize, code: &str) -> ThreadPool {
+    pub fn new(size: usize, code: impl Into<JsCode>) -> Result<ThreadPool> {
+        Self::with_services(size, code, Services::default())
+    }
+
+    /// Initialize thread pool whose workers expose `services` to handlers,
+    /// failing if the code doesn't load or its `onInit` throws
+    pub fn with_services(
+        size: usize,
+        code: impl Into<JsCode>,
+        services: Services,
+    ) -> Result<ThreadPool> {
         assert!(size > 0);
 
+        // shared by all workers as the bundle might be large
+        let code = Arc::new(code.into());
         let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
+        let stats = Arc::new(PoolStats {
+            queued: AtomicUsize::new(0),
+            busy: AtomicUsize::new(0),
+            heap: (0..size).map(|_| AtomicU64::new(0)).collect(),
+        });
 
         let mut workers = Vec::with_capacity(size);
+        let (ready, loaded) = mpsc::channel();
 
         for id in 0..size {
-            workers.push(Worker::new(id, code, Arc::clone(&receiver)));
+            workers.push(Worker::new(
+                id,
+                Arc::clone(&code),
+                services.clone(),
+                Arc::clone(&receiver),
+                Arc::clone(&stats),
+                ready.clone(),
+            ));
         }
+        drop(ready);
 
-        ThreadPool { workers, sender }
+        // dropped on failure, which stops the workers which did load
+        let pool = ThreadPool {
+            workers,
+            sender,
+            stats,
+        };
+        let mut ready = 0;
+        // ends once every worker sent its result, or panicked
+        for loaded in loaded {
+            loaded?;
+            ready += 1;
+        }
+        if ready < size {
+            return Err(anyhow!("{} workers failed to start", size - ready));
+        }
+        Ok(pool)
+    }
+
+    pub fn stats(&self) -> &PoolStats {
+        &self.stats
     }
 
     /// Execute task asynchronously
     ///
     /// Return `oneshot::Receiver` for receiving execution result
     /// Caller decides whether to `blocking_recv` or `await` the return value
-    #[instrument(skip(self))]
     pub fn execute(&self, handler: &str, req: Req) -> oneshot::Receiver<Res> {
+        self.execute_with(handler, &[], req)
+    }
+
+    /// Execute task through the middleware of its route, see [`Self::execute`]
+    #[instrument(name = "execute", skip(self))]
+    pub fn execute_with(
+        &self,
+        handler: &str,
+        middleware: &[String],
+        req: Req,
+    ) -> oneshot::Receiver<Res> {
         let (tx, rx) = oneshot::channel();
 
-        let request = Request::new(req, handler, tx, tracing::Span::current());
-        self.sender.send(Message::NewRequest(request)).unwrap();
+        let mut request = Request::new(req, handler, tx, tracing::Span::current());
+        request.middleware = middleware.to_vec();
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        self.sender
+            .send(Message::NewRequest(Box::new(request)))
+            .unwrap();
         rx
     }
 }
@@ -145,7 +279,8 @@ impl Drop for ThreadPool {
         info!("Sending terminate message to all workers.");
 
         for _ in &self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+            // fails once no worker is left to receive it
+            let _ = self.sender.send(Message::Terminate);
         }
 
         info!("Shutting down all workers.");
@@ -154,7 +289,9 @@ impl Drop for ThreadPool {
             info!("Shutting down worker {}", worker.id);
 
             if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+                if thread.join().is_err() {
+                    error!("Worker {} panicked", worker.id);
+                }
             }
         }
     }
@@ -177,7 +314,7 @@ fn thread_pool_works() {
     })();
     "#;
 
-    let pool = ThreadPool::new(4, code);
+    let pool = ThreadPool::new(4, code).unwrap();
 
     let rx = pool.execute(
         "hello",
@@ -190,3 +327,41 @@ fn thread_pool_works() {
     let result = rx.blocking_recv();
     println!("The result is: {:?}", result.unwrap());
 }
+
+#[test]
+fn thread_pool_should_call_lifecycle_hooks() {
+    let code = r#"
+    let started = false;
+    export function onInit() { started = true; }
+    export async function onShutdown() { await Promise.resolve(); }
+    export async function hello(req) {
+        return { status: 200, headers: {}, body: String(started) };
+    }
+    "#;
+
+    let pool = ThreadPool::new(2, JsCode::new(code).with_format(crate::JsFormat::Es)).unwrap();
+    let req = Req::builder().method("GET").url("/hello").build();
+    let res = pool.execute("hello", req).blocking_recv().unwrap();
+    assert_eq!(res.body.as_deref(), Some("true"));
+    // joins the workers after `onShutdown` settles
+    drop(pool);
+}
+
+#[test]
+fn swappable_thread_pool_should_keep_serving_if_the_code_fails() {
+    let code = |init: &str| {
+        let code = format!(
+            "export function onInit() {{ {init} }}\nexport async function hello(req) {{ return {{ status: 200, headers: {{}}, body: \"ok\" }}; }}"
+        );
+        JsCode::new(code).with_format(crate::JsFormat::Es)
+    };
+
+    let pool = SwappableThreadPool::new(code("")).unwrap();
+    let err = pool.swap(code("throw new Error('boom');")).unwrap_err();
+    assert!(format!("{err:#}").contains("boom"), "{err:#}");
+    assert!(pool.swap(JsCode::new("syntax error (")).is_err());
+
+    let req = Req::builder().method("GET").url("/hello").build();
+    let res = pool.load().execute("hello", req).blocking_recv().unwrap();
+    assert_eq!(res.body.as_deref(), Some("ok"));
+}
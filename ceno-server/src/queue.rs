@@ -0,0 +1,401 @@
+use crate::{ProjectQueue, ProjectQueues, Req, SwappableThreadPool};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::Notify, task::JoinHandle};
+use tracing::{info, info_span, warn, Instrument};
+
+/// Method of the request passed to a queue consumer
+pub const QUEUE_METHOD: &str = "QUEUE";
+
+/// How long the dispatcher sleeps when no job is due
+const IDLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A job enqueued by `ceno.queue.enqueue`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub queue: String,
+    /// JSON encoded payload, passed as the request body
+    pub payload: String,
+    /// failed attempts so far
+    pub attempts: u32,
+    /// overrides the `retries` of the consumer
+    pub retries: Option<u32>,
+    pub run_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EnqueueOptions {
+    /// milliseconds to wait before the first attempt
+    pub delay: u64,
+    pub retries: Option<u32>,
+}
+
+/// `JobQueue` persists the jobs of a tenant on local disk, one JSON file per
+/// job under `pending/`, or under `dead/` once it ran out of retries.
+///
+/// Jobs are delivered at least once, the ones in flight when the process
+/// exits are run again on the next start.
+pub struct JobQueue {
+    dir: PathBuf,
+    /// pending jobs which aren't taken by the dispatcher
+    pending: Mutex<Vec<Job>>,
+    notify: Notify,
+}
+
+impl JobQueue {
+    /// Open the queue stored in `dir`, creating it if necessary
+    pub fn open(dir: impl AsRef<Path>) -> Result<Arc<Self>> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join("pending"))?;
+        fs::create_dir_all(dir.join("dead"))?;
+
+        let pending = read_jobs(&dir.join("pending"))?;
+        Ok(Arc::new(Self {
+            dir,
+            pending: Mutex::new(pending),
+            notify: Notify::new(),
+        }))
+    }
+
+    /// Persist a job for the consumer of `queue`, returning its id
+    pub fn enqueue(&self, queue: &str, payload: String, options: EnqueueOptions) -> Result<String> {
+        let now = Utc::now();
+        let delay = TimeDelta::try_milliseconds(options.delay as i64)
+            .ok_or_else(|| anyhow!("delay is too large"))?;
+        let job = Job {
+            id: uuid::Uuid::new_v4().to_string(),
+            queue: queue.to_string(),
+            payload,
+            attempts: 0,
+            retries: options.retries,
+            run_at: now + delay,
+            created_at: now,
+            last_error: None,
+        };
+        self.write(&job)?;
+
+        let id = job.id.clone();
+        self.pending.lock().unwrap().push(job);
+        self.notify.notify_one();
+        Ok(id)
+    }
+
+    /// Jobs which ran out of retries, oldest first
+    pub fn dead_letters(&self) -> Result<Vec<Job>> {
+        read_jobs(&self.dir.join("dead"))
+    }
+
+    /// Take the due jobs having a consumer, along with when the next one is due
+    fn take_due(
+        &self,
+        now: DateTime<Utc>,
+        consumers: &ProjectQueues,
+    ) -> (Vec<Job>, Option<DateTime<Utc>>) {
+        let mut pending = self.pending.lock().unwrap();
+        let (due, rest) = pending
+            .drain(..)
+            .partition(|job| job.run_at <= now && consumers.contains_key(&job.queue));
+        *pending = rest;
+
+        let next = pending
+            .iter()
+            .filter(|job| consumers.contains_key(&job.queue))
+            .map(|job| job.run_at)
+            .min();
+        (due, next)
+    }
+
+    fn complete(&self, job: &Job) -> Result<()> {
+        fs::remove_file(self.path("pending", &job.id))?;
+        Ok(())
+    }
+
+    /// Schedule another attempt of a failed job
+    fn retry(&self, job: Job) -> Result<()> {
+        self.write(&job)?;
+        self.pending.lock().unwrap().push(job);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Move a job which ran out of retries to the dead-letter list
+    fn bury(&self, job: &Job) -> Result<()> {
+        let dead = self.path("dead", &job.id);
+        fs::write(&dead, serde_json::to_vec_pretty(job)?)?;
+        fs::remove_file(self.path("pending", &job.id))?;
+        Ok(())
+    }
+
+    fn write(&self, job: &Job) -> Result<()> {
+        // write then rename, so that a crash never leaves a partial job behind
+        let tmp = self.dir.join(format!("{}.tmp", job.id));
+        fs::write(&tmp, serde_json::to_vec_pretty(job)?)?;
+        fs::rename(tmp, self.path("pending", &job.id))?;
+        Ok(())
+    }
+
+    fn path(&self, kind: &str, id: &str) -> PathBuf {
+        self.dir.join(kind).join(format!("{id}.json"))
+    }
+}
+
+fn read_jobs(dir: &Path) -> Result<Vec<Job>> {
+    let mut jobs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            jobs.push(serde_json::from_slice::<Job>(&fs::read(path)?)?);
+        }
+    }
+    jobs.sort_by_key(|job| job.created_at);
+    Ok(jobs)
+}
+
+/// `QueueDispatcher` runs the consumers of `queues:` for the jobs of a
+/// `JobQueue` through the tenant's `SwappableThreadPool`.
+///
+/// A consumer gets a request whose method is [`QUEUE_METHOD`], url is the
+/// queue name and body is the JSON payload, along with the `x-ceno-job-id`
+/// and `x-ceno-job-attempt` headers. Any response other than 2xx, or an
+/// exception, is retried with exponential backoff.
+#[derive(Clone)]
+pub struct QueueDispatcher {
+    pool: SwappableThreadPool,
+    queue: Arc<JobQueue>,
+    inner: Arc<Mutex<DispatcherInner>>,
+}
+
+struct DispatcherInner {
+    consumers: Arc<ProjectQueues>,
+    /// `None` until `start` is called
+    task: Option<JoinHandle<()>>,
+}
+
+impl QueueDispatcher {
+    pub fn new(pool: SwappableThreadPool, queue: Arc<JobQueue>, consumers: ProjectQueues) -> Self {
+        let inner = DispatcherInner {
+            consumers: Arc::new(consumers),
+            task: None,
+        };
+        Self {
+            pool,
+            queue,
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// Start dispatching jobs, must be called within a tokio runtime
+    pub fn start(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        self.restart(&mut inner);
+    }
+
+    /// Replace the consumers, the dispatcher is restarted if it is running
+    pub fn swap(&self, consumers: ProjectQueues) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consumers = Arc::new(consumers);
+        if inner.task.is_some() {
+            self.restart(&mut inner);
+        }
+    }
+
+    fn restart(&self, inner: &mut DispatcherInner) {
+        if let Some(task) = inner.task.take() {
+            task.abort();
+        }
+        let (pool, queue) = (self.pool.clone(), Arc::clone(&self.queue));
+        let consumers = Arc::clone(&inner.consumers);
+        inner.task = Some(tokio::spawn(dispatch(pool, queue, consumers)));
+    }
+}
+
+impl Drop for DispatcherInner {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+async fn dispatch(pool: SwappableThreadPool, queue: Arc<JobQueue>, consumers: Arc<ProjectQueues>) {
+    loop {
+        let now = Utc::now();
+        let (jobs, next) = queue.take_due(now, &consumers);
+        for job in jobs {
+            let consumer = consumers[&job.queue].clone();
+            let (pool, queue) = (pool.clone(), Arc::clone(&queue));
+            tokio::spawn(async move {
+                if let Err(e) = run_job(&pool, &queue, &consumer, job).await {
+                    warn!("update job failed: {}", e);
+                }
+            });
+        }
+
+        let wait = next
+            .and_then(|next| (next - now).to_std().ok())
+            .unwrap_or(IDLE_INTERVAL)
+            .min(IDLE_INTERVAL);
+        tokio::select! {
+            _ = queue.notify.notified() => {}
+            _ = tokio::time::sleep(wait) => {}
+        }
+    }
+}
+
+async fn run_job(
+    pool: &SwappableThreadPool,
+    queue: &JobQueue,
+    consumer: &ProjectQueue,
+    mut job: Job,
+) -> Result<()> {
+    let attempt = job.attempts + 1;
+    let span = info_span!("queue", name = %job.queue, id = %job.id, attempt);
+    let ret = async {
+        let headers = HashMap::from([
+            ("x-ceno-job-id".to_string(), job.id.clone()),
+            ("x-ceno-job-attempt".to_string(), attempt.to_string()),
+        ]);
+        let req = Req::builder()
+            .method(QUEUE_METHOD)
+            .url(&job.queue)
+            .headers(headers)
+            .body(Some(job.payload.clone()))
+            .build();
+        let rx = pool.load().execute(&consumer.handler, req);
+        match rx.await {
+            Ok(res) if (200..300).contains(&res.status) => Ok(()),
+            Ok(res) => Err(format!("consumer responded {}", res.status)),
+            Err(_) => Err(format!("consumer {} failed", consumer.handler)),
+        }
+    }
+    .instrument(span.clone())
+    .await;
+
+    let _enter = span.enter();
+    let Err(error) = ret else {
+        info!("job finished");
+        return queue.complete(&job);
+    };
+
+    job.attempts = attempt;
+    job.last_error = Some(error.clone());
+    if job.attempts > job.retries.unwrap_or(consumer.retries) {
+        warn!(error, "job ran out of retries");
+        return queue.bury(&job);
+    }
+    let backoff = consumer
+        .backoff
+        .saturating_mul(1 << (job.attempts - 1).min(16));
+    job.run_at = Utc::now() + TimeDelta::milliseconds(backoff.min(i64::MAX as u64) as i64);
+    warn!(error, run_at = %job.run_at, "job failed, will retry");
+    queue.retry(job)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{JobQueue, JsCode, JsFormat, Services};
+
+    fn consumers(handler: &str, retries: u32) -> ProjectQueues {
+        let consumer = ProjectQueue {
+            handler: handler.to_string(),
+            retries,
+            backoff: 10,
+        };
+        HashMap::from([("emails".to_string(), consumer)])
+    }
+
+    #[test]
+    fn job_queue_should_persist_jobs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path();
+        let queue = JobQueue::open(dir)?;
+        let options = EnqueueOptions {
+            delay: 60_000,
+            retries: Some(1),
+        };
+        let delayed = queue.enqueue("emails", "1".into(), options)?;
+        let due = queue.enqueue("emails", "2".into(), Default::default())?;
+        queue.enqueue("unknown", "3".into(), Default::default())?;
+
+        // reopening loads the pending jobs back
+        let queue = JobQueue::open(dir)?;
+        let (jobs, next) = queue.take_due(Utc::now(), &consumers("send", 0));
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(
+            (jobs[0].id.as_str(), jobs[0].payload.as_str()),
+            (due.as_str(), "2")
+        );
+        assert!(next.unwrap() > Utc::now() + TimeDelta::seconds(50));
+        assert_eq!(queue.pending.lock().unwrap().len(), 2);
+
+        let (jobs, _) = queue.take_due(next.unwrap(), &consumers("send", 0));
+        assert_eq!(
+            (jobs[0].id.as_str(), jobs[0].retries),
+            (delayed.as_str(), Some(1))
+        );
+        queue.bury(&jobs[0])?;
+        assert_eq!(queue.dead_letters()?.len(), 1);
+        assert_eq!(JobQueue::open(dir)?.pending.lock().unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queue_dispatcher_should_retry_and_bury() -> Result<()> {
+        let code = r#"
+    export async function send(req) {
+        const { to } = JSON.parse(req.body);
+        if (to === "throw") throw new Error("boom");
+        const status = to === "bad" && req.headers["x-ceno-job-attempt"] !== "2" ? 500 : 200;
+        return { status, headers: {}, body: null };
+    }
+    export async function enqueue(req) {
+        const id = await ceno.queue.enqueue("emails", { to: req.url }, { retries: 1 });
+        return { status: 202, headers: {}, body: id };
+    }
+    "#;
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path();
+        let queue = JobQueue::open(dir)?;
+        let services = Services {
+            queue: Some(Arc::clone(&queue)),
+        };
+        let code = JsCode::new(code).with_format(JsFormat::Es);
+        let pool = SwappableThreadPool::with_services(code, services).unwrap();
+        let dispatcher =
+            QueueDispatcher::new(pool.clone(), Arc::clone(&queue), consumers("send", 3));
+        dispatcher.start();
+
+        for to in ["ok", "bad", "throw"] {
+            let req = Req::builder().method("POST").url(to).build();
+            let res = pool.load().execute("enqueue", req).await?;
+            assert_eq!(res.status, 202);
+        }
+
+        // "bad" succeeds on the retry, "throw" is buried after 2 attempts
+        for _ in 0..100 {
+            let pending = fs::read_dir(dir.join("pending"))?.count();
+            if pending == 0 && !queue.dead_letters()?.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let dead = queue.dead_letters()?;
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].payload, r#"{"to":"throw"}"#);
+        assert_eq!(dead[0].attempts, 2);
+        assert_eq!(fs::read_dir(dir.join("pending"))?.count(), 0);
+        Ok(())
+    }
+}
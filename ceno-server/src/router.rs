@@ -1,9 +1,25 @@
-use crate::{config::ProjectRoutes, AppError};
-use anyhow::Result;
+use crate::{
+    config::{
+        CompressionConfig, ProjectConfig, ProjectRateLimit, ProjectRoute, ProjectRoutes,
+        RouteAction, RouteMethods, RouteSettings,
+    },
+    forward,
+    middleware::AccessLog,
+    openapi::openapi,
+    params::{self, PathParams, RoutePath},
+    policy::RateLimiter,
+    schema::RouteValidator,
+    AppError, ParamValue, ResponseCache,
+};
+use anyhow::{Context as _, Result};
 use arc_swap::ArcSwap;
-use axum::http::Method;
-use matchit::{Match, Router};
-use std::{ops::Deref, sync::Arc};
+use axum::{
+    http::{header, request::Parts, HeaderValue, Method},
+    response::{IntoResponse, Response},
+};
+use matchit::Router;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, ops::Deref, sync::Arc};
 use tracing::instrument;
 
 #[derive(Clone)]
@@ -14,6 +30,21 @@ pub struct SwappableAppRouter {
 pub struct AppRouterInner {
     pub code: String,
     pub router: Router<MethodRoute>,
+    /// the OpenAPI document, if served
+    pub docs: Option<ApiDocs>,
+    /// checked by the rate limit layer before routing
+    pub rate_limits: Vec<ProjectRateLimit>,
+    pub compression: Option<CompressionConfig>,
+    /// dropped along with the router, so a hot swap purges it
+    pub cache: Option<ResponseCache>,
+    /// wraps every handler, as the global `middleware` of the config
+    pub middleware: Vec<String>,
+    pub access_log: Option<Arc<AccessLog>>,
+}
+
+pub struct ApiDocs {
+    pub path: String,
+    pub body: String,
 }
 
 #[derive(Clone)]
@@ -21,29 +52,42 @@ pub struct AppRouter(Arc<AppRouterInner>);
 
 #[derive(Debug, Default, Clone)]
 pub struct MethodRoute {
-    get: Option<String>,
-    head: Option<String>,
-    delete: Option<String>,
-    options: Option<String>,
-    patch: Option<String>,
-    post: Option<String>,
-    put: Option<String>,
-    trace: Option<String>,
-    connect: Option<String>,
+    /// as declared in the config
+    pub(crate) path: String,
+    /// types of the path params, strings if not listed
+    params: Arc<PathParams>,
+    methods: HashMap<Method, Endpoint>,
+    /// answers the methods without an endpoint of their own
+    any: Option<Endpoint>,
+}
+
+/// What answers a route and the policies to enforce before it does
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub action: RouteAction,
+    pub settings: RouteSettings,
+    /// shared by the clones of the route
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+    pub(crate) validator: Option<Arc<RouteValidator>>,
+}
+
+/// The endpoint matched by a request, and its converted path params
+#[derive(Debug)]
+pub struct RouteMatch<'m> {
+    pub value: &'m Endpoint,
+    pub params: HashMap<String, ParamValue>,
 }
 
 impl SwappableAppRouter {
-    pub fn try_new(code: impl Into<String>, routes: ProjectRoutes) -> Result<Self> {
-        let router = Self::get_router(routes)?;
-        let inner = AppRouterInner::new(code, router);
+    pub fn try_new(code: impl Into<String>, config: &ProjectConfig) -> Result<Self> {
+        let inner = AppRouterInner::try_from_config(code, config)?;
         Ok(Self {
             inner: Arc::new(ArcSwap::from_pointee(inner)),
         })
     }
 
-    pub fn swap(&self, code: impl Into<String>, routes: ProjectRoutes) -> Result<()> {
-        let router = Self::get_router(routes)?;
-        let inner = AppRouterInner::new(code, router);
+    pub fn swap(&self, code: impl Into<String>, config: &ProjectConfig) -> Result<()> {
+        let inner = AppRouterInner::try_from_config(code, config)?;
         self.inner.store(Arc::new(inner));
         Ok(())
     }
@@ -55,22 +99,25 @@ impl SwappableAppRouter {
     fn get_router(routes: ProjectRoutes) -> Result<Router<MethodRoute>> {
         let mut router = Router::new();
         for (path, methods) in routes {
-            let mut method_route = MethodRoute::default();
-            for method in methods {
-                match method.method {
-                    Method::GET => method_route.get = Some(method.handler),
-                    Method::HEAD => method_route.head = Some(method.handler),
-                    Method::DELETE => method_route.delete = Some(method.handler),
-                    Method::OPTIONS => method_route.options = Some(method.handler),
-                    Method::PATCH => method_route.patch = Some(method.handler),
-                    Method::POST => method_route.post = Some(method.handler),
-                    Method::PUT => method_route.put = Some(method.handler),
-                    Method::TRACE => method_route.trace = Some(method.handler),
-                    Method::CONNECT => method_route.connect = Some(method.handler),
-                    v => unreachable!("unsupported method {v}"),
-                }
+            let route_path = RoutePath::parse(&path)?;
+            // the first path has all the params
+            let names = route_path.paths[0]
+                .split('/')
+                .filter_map(|segment| segment.strip_prefix([':', '*']))
+                .collect::<Vec<_>>();
+            let mut method_route = MethodRoute {
+                path: path.clone(),
+                ..Default::default()
+            };
+            for route in methods {
+                forward::validate(&route.action, &route.settings, &names)
+                    .with_context(|| format!("invalid route {path}"))?;
+                method_route.insert(route)?;
+            }
+            method_route.params = Arc::new(route_path.params);
+            for path in route_path.paths {
+                router.insert(path, method_route.clone())?;
             }
-            router.insert(path, method_route)?;
         }
         Ok(router)
     }
@@ -78,38 +125,119 @@ impl SwappableAppRouter {
 
 impl AppRouter {
     #[instrument(skip(self))]
-    pub fn match_it<'m, 'p>(
-        &'m self,
-        method: Method,
-        path: &'p str,
-    ) -> Result<Match<'m, 'p, &'m str>, AppError>
-    where
-        'p: 'm,
-    {
+    pub fn match_it(&self, method: Method, path: &str) -> Result<RouteMatch<'_>, AppError> {
         let Ok(ret) = self.router.at(path) else {
             return Err(AppError::RoutePathNotFound(path.to_string()));
         };
 
-        let s = match method {
-            Method::GET => ret.value.get.as_deref(),
-            Method::HEAD => ret.value.head.as_deref(),
-            Method::DELETE => ret.value.delete.as_deref(),
-            Method::OPTIONS => ret.value.options.as_deref(),
-            Method::PATCH => ret.value.patch.as_deref(),
-            Method::POST => ret.value.post.as_deref(),
-            Method::PUT => ret.value.put.as_deref(),
-            Method::TRACE => ret.value.trace.as_deref(),
-            Method::CONNECT => ret.value.connect.as_deref(),
-            _ => unreachable!(),
+        let s = ret
+            .value
+            .get(&method)
+            .ok_or_else(|| AppError::RouteMethodNotAllowed(method, ret.value.allow()))?;
+        let params = params::coerce(&ret.value.params, path, ret.params.iter())?;
+        Ok(RouteMatch { value: s, params })
+    }
+}
+
+impl AppRouter {
+    /// Respond with the OpenAPI document if it's requested
+    pub(crate) fn serve_docs(&self, parts: &Parts) -> Option<Response> {
+        let docs = self.docs.as_ref()?;
+        if docs.path != parts.uri.path() || !matches!(parts.method, Method::GET | Method::HEAD) {
+            return None;
+        }
+        let content_type = [(header::CONTENT_TYPE, "application/json")];
+        Some((content_type, docs.body.clone()).into_response())
+    }
+}
+
+impl MethodRoute {
+    fn insert(&mut self, route: ProjectRoute) -> Result<()> {
+        let methods = route.method.clone();
+        let mut endpoint = Endpoint::try_from(route)?;
+        if let Some(limit) = &endpoint.settings.rate_limit {
+            let route = match &methods {
+                RouteMethods::Any => format!("ANY {}", self.path),
+                RouteMethods::List(methods) => {
+                    let methods = methods.iter().map(Method::as_str).collect::<Vec<_>>();
+                    format!("{} {}", methods.join(","), self.path)
+                }
+            };
+            endpoint.rate_limiter = Some(Arc::new(RateLimiter::new(limit, route)));
+        }
+        match methods {
+            RouteMethods::Any => self.any = Some(endpoint),
+            RouteMethods::List(methods) => {
+                for method in methods {
+                    self.methods.insert(method, endpoint.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The endpoint of `method`, HEAD falls back to GET
+    fn get(&self, method: &Method) -> Option<&Endpoint> {
+        self.methods
+            .get(method)
+            .or_else(|| match *method {
+                Method::HEAD => self.methods.get(&Method::GET),
+                _ => None,
+            })
+            .or(self.any.as_ref())
+    }
+
+    /// Methods answered by the route, including the automatic HEAD and OPTIONS
+    fn allow(&self) -> Vec<Method> {
+        let mut methods = self.methods.keys().cloned().collect::<Vec<_>>();
+        if self.methods.contains_key(&Method::GET) && !self.methods.contains_key(&Method::HEAD) {
+            methods.push(Method::HEAD);
         }
-        .ok_or_else(|| AppError::RouteMethodNotAllowed(method))?;
-        Ok(Match {
-            value: s,
-            params: ret.params,
+        if !self.methods.contains_key(&Method::OPTIONS) {
+            methods.push(Method::OPTIONS);
+        }
+        methods.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        methods
+    }
+}
+
+/// The `Allow` header listing `methods`
+pub(crate) fn allow_header(methods: &[Method]) -> HeaderValue {
+    let methods = methods.iter().map(Method::as_str).collect::<Vec<_>>();
+    HeaderValue::from_str(&methods.join(", ")).expect("methods are valid header values")
+}
+
+impl TryFrom<ProjectRoute> for Endpoint {
+    type Error = anyhow::Error;
+
+    fn try_from(route: ProjectRoute) -> Result<Self> {
+        let validator = route
+            .settings
+            .schema
+            .as_ref()
+            .map(|schemas| {
+                RouteValidator::try_new(schemas)
+                    .with_context(|| format!("invalid schema of {}", route.action))
+            })
+            .transpose()?
+            .map(Arc::new);
+        Ok(Self {
+            action: route.action,
+            settings: route.settings,
+            // set once the path of the route is known
+            rate_limiter: None,
+            validator,
         })
     }
 }
 
+impl Endpoint {
+    /// The name of the handler, if a handler answers the route
+    pub fn handler(&self) -> Option<&str> {
+        self.action.handler()
+    }
+}
+
 impl Deref for AppRouter {
     type Target = AppRouterInner;
 
@@ -123,8 +251,52 @@ impl AppRouterInner {
         Self {
             code: code.into(),
             router,
+            docs: None,
+            rate_limits: Vec::new(),
+            compression: None,
+            cache: None,
+            middleware: Vec::new(),
+            access_log: None,
         }
     }
+
+    fn try_from_config(code: impl Into<String>, config: &ProjectConfig) -> Result<Self> {
+        let code = code.into();
+        let router = SwappableAppRouter::get_router(config.routes.clone())?;
+        let docs = match &config.openapi.path {
+            Some(path) => Some(ApiDocs {
+                path: path.clone(),
+                body: serde_json::to_string_pretty(&openapi(config)?)?,
+            }),
+            None => None,
+        };
+        let cache = match &config.response_cache {
+            Some(cache) => {
+                // another code or config gets a cache of its own
+                let generation = Sha256::new()
+                    .chain_update(&code)
+                    .chain_update(serde_json::to_vec(config)?)
+                    .finalize()[..8]
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect();
+                Some(ResponseCache::new(cache.clone(), generation)?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            docs,
+            rate_limits: config.rate_limits.clone(),
+            compression: config.compression.clone(),
+            cache,
+            middleware: config.middleware.clone(),
+            access_log: config
+                .access_log
+                .clone()
+                .map(|log| Arc::new(AccessLog::new(log))),
+            ..Self::new(code, router)
+        })
+    }
 }
 
 #[cfg(test)]
@@ -136,15 +308,132 @@ mod tests {
     fn router_match_should_work() {
         let config = include_str!("../assets/config.yaml");
         let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
-        let router = SwappableAppRouter::try_new("", config.routes).unwrap();
+        let router = SwappableAppRouter::try_new("", &config).unwrap();
         let app_router = router.load();
         let m = app_router.match_it(Method::GET, "/api/hello/1").unwrap();
-        assert_eq!(m.value, "hello1");
-        assert_eq!(m.params.get("id"), Some("1"));
+        assert_eq!(m.value.handler(), Some("hello1"));
+        assert_eq!(m.params["id"], ParamValue::String("1".into()));
 
         let m = app_router.match_it(Method::POST, "/api/world/2").unwrap();
-        assert_eq!(m.value, "hello4");
-        assert_eq!(m.params.get("id"), Some("2"));
-        assert_eq!(m.params.get("name"), Some("world"));
+        assert_eq!(m.value.handler(), Some("hello4"));
+        assert_eq!(m.params["id"], ParamValue::String("2".into()));
+        assert_eq!(m.params["name"], ParamValue::String("world".into()));
+    }
+
+    #[test]
+    fn router_should_match_method_lists_and_any() {
+        let config = r#"
+name: test
+routes:
+  /items:
+    - method: [GET, POST]
+      handler: items
+    - method: PURGE
+      handler: purge
+  /proxy/*rest:
+    - method: ANY
+      handler: proxy
+    - method: delete
+      handler: remove
+"#;
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        let router = SwappableAppRouter::try_new("", &config).unwrap();
+        let app_router = router.load();
+        let handler = |method: &str, path| {
+            let method = Method::from_bytes(method.as_bytes()).unwrap();
+            app_router
+                .match_it(method, path)
+                .map(|m| m.value.handler().unwrap())
+        };
+
+        assert_eq!(handler("POST", "/items").unwrap(), "items");
+        assert_eq!(handler("HEAD", "/items").unwrap(), "items");
+        assert_eq!(handler("PURGE", "/items").unwrap(), "purge");
+        match handler("BREW", "/items") {
+            Err(AppError::RouteMethodNotAllowed(method, allow)) => {
+                assert_eq!(method.as_str(), "BREW");
+                assert_eq!(allow_header(&allow), "GET, HEAD, OPTIONS, POST, PURGE");
+            }
+            _ => panic!("expected method not allowed"),
+        }
+
+        assert_eq!(handler("PATCH", "/proxy/a/b").unwrap(), "proxy");
+        assert_eq!(handler("BREW", "/proxy/a/b").unwrap(), "proxy");
+        assert_eq!(handler("DELETE", "/proxy/a").unwrap(), "remove");
+        assert!(serde_yaml::from_str::<ProjectConfig>(
+            "name: t\nroutes: {/: [{method: [], handler: a}]}"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn router_should_match_typed_params() {
+        let config = r#"
+name: test
+routes:
+  /users/{id:int}/posts/{page?:int}:
+    - method: GET
+      handler: posts
+"#;
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        let router = SwappableAppRouter::try_new("", &config).unwrap();
+        let app_router = router.load();
+
+        let m = app_router
+            .match_it(Method::GET, "/users/7/posts/2")
+            .unwrap();
+        assert_eq!(m.params["id"], ParamValue::Int(7));
+        assert_eq!(m.params["page"], ParamValue::Int(2));
+        let m = app_router.match_it(Method::GET, "/users/7/posts").unwrap();
+        assert_eq!(m.value.handler(), Some("posts"));
+        assert!(!m.params.contains_key("page"));
+        assert!(matches!(
+            app_router.match_it(Method::GET, "/users/me/posts"),
+            Err(AppError::RouteParamInvalid(..))
+        ));
+    }
+
+    #[test]
+    fn router_should_load_redirects_rewrites_and_proxies() {
+        let config = r#"
+name: test
+routes:
+  /old/{id:int}:
+    - method: GET
+      redirect: { to: "/users/{id}", status: 301 }
+  /me:
+    - method: GET
+      rewrite: /users/0
+  /api/{*rest}:
+    - method: ANY
+      proxy:
+        url: http://backend:8080/{rest}
+        headers: { set: { x-from: ceno }, remove: [cookie] }
+"#;
+        let config: ProjectConfig = serde_yaml::from_str(config).unwrap();
+        let router = SwappableAppRouter::try_new("", &config).unwrap();
+        let app_router = router.load();
+        let m = app_router.match_it(Method::GET, "/old/7").unwrap();
+        assert!(matches!(&m.value.action, RouteAction::Redirect(r) if r.status == 301));
+        let m = app_router.match_it(Method::GET, "/me").unwrap();
+        assert!(matches!(&m.value.action, RouteAction::Rewrite(to) if to == "/users/0"));
+        let m = app_router.match_it(Method::PUT, "/api/a/b").unwrap();
+        assert_eq!(
+            m.value.action.to_string(),
+            "proxy to http://backend:8080/{rest}"
+        );
+        assert_eq!(m.value.handler(), None);
+
+        let invalid = |route: &str| {
+            let config =
+                format!("name: t\nroutes:\n  /a/{{id}}:\n    - method: GET\n      {route}");
+            let config: ProjectConfig = serde_yaml::from_str(&config).unwrap();
+            SwappableAppRouter::try_new("", &config).is_err()
+        };
+        assert!(invalid("redirect: /b/{name}"));
+        assert!(invalid("redirect: { to: /b, status: 200 }"));
+        assert!(invalid("rewrite: b"));
+        assert!(invalid("proxy: ftp://backend"));
+        assert!(!invalid("proxy: http://backend/{id}"));
     }
 }
@@ -0,0 +1,261 @@
+use crate::{OverlapPolicy, ProjectSchedule, ProjectSchedules, Req, Res, SwappableThreadPool};
+use anyhow::{anyhow, Context as _, Result};
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::task::JoinHandle;
+use tracing::{info, info_span, warn, Instrument};
+
+/// Method of the request passed to a scheduled handler
+pub const SCHEDULED_METHOD: &str = "SCHEDULED";
+
+/// `Scheduler` runs the handlers of `schedules:` through the tenant's
+/// `SwappableThreadPool`, so every run picks up the latest swapped code.
+///
+/// A scheduled handler gets a request whose method is [`SCHEDULED_METHOD`],
+/// url is the schedule name, and `x-ceno-scheduled-time` header is the
+/// RFC 3339 time it was due.
+#[derive(Clone)]
+pub struct Scheduler {
+    pool: SwappableThreadPool,
+    inner: Arc<Mutex<SchedulerInner>>,
+}
+
+#[derive(Default)]
+struct SchedulerInner {
+    jobs: HashMap<String, Arc<Job>>,
+    /// `None` until `start` is called
+    tasks: Option<Vec<JoinHandle<()>>>,
+}
+
+struct Job {
+    name: String,
+    schedule: Schedule,
+    config: ProjectSchedule,
+    running: AtomicBool,
+}
+
+impl Scheduler {
+    pub fn try_new(pool: SwappableThreadPool, schedules: ProjectSchedules) -> Result<Self> {
+        let inner = SchedulerInner {
+            jobs: parse_jobs(schedules)?,
+            tasks: None,
+        };
+        Ok(Self {
+            pool,
+            inner: Arc::new(Mutex::new(inner)),
+        })
+    }
+
+    /// Start firing the schedules, must be called within a tokio runtime
+    pub fn start(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.restart(&self.pool);
+    }
+
+    /// Replace the schedules, which are restarted if the scheduler is running.
+    /// The current ones are kept if any of `schedules` is invalid.
+    pub fn swap(&self, schedules: ProjectSchedules) -> Result<()> {
+        let jobs = parse_jobs(schedules)?;
+        let mut inner = self.inner.lock().unwrap();
+        inner.jobs = jobs;
+        if inner.tasks.is_some() {
+            inner.restart(&self.pool);
+        }
+        Ok(())
+    }
+
+    /// Run the schedule `name` once right away, regardless of its overlap policy
+    pub async fn trigger(&self, name: &str) -> Result<Res> {
+        let job = self.inner.lock().unwrap().jobs.get(name).cloned();
+        let job = job.ok_or_else(|| anyhow!("schedule {name} not found"))?;
+        job.run(&self.pool, Utc::now()).await
+    }
+}
+
+impl SchedulerInner {
+    fn restart(&mut self, pool: &SwappableThreadPool) {
+        for task in self.tasks.take().into_iter().flatten() {
+            task.abort();
+        }
+        let tasks = self
+            .jobs
+            .values()
+            .map(|job| tokio::spawn(Arc::clone(job).fire(pool.clone())))
+            .collect();
+        self.tasks = Some(tasks);
+    }
+}
+
+impl Drop for SchedulerInner {
+    fn drop(&mut self) {
+        for task in self.tasks.take().into_iter().flatten() {
+            task.abort();
+        }
+    }
+}
+
+impl Job {
+    /// Run the job whenever it is due, until the task is aborted
+    async fn fire(self: Arc<Self>, pool: SwappableThreadPool) {
+        let mut last = Utc::now();
+        // the timer may wake up slightly early, never fire the same time twice
+        while let Some(next) = self.schedule.after(&last.max(Utc::now())).next() {
+            let delay = (next - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(delay).await;
+            last = next;
+
+            if !self.begin() {
+                warn!(schedule = %self.name, "previous run is still going, skipped");
+                continue;
+            }
+            let (job, pool) = (Arc::clone(&self), pool.clone());
+            tokio::spawn(async move {
+                if let Err(e) = job.run(&pool, next).await {
+                    warn!(schedule = %job.name, "schedule failed: {}", e);
+                }
+                job.running.store(false, Ordering::Release);
+            });
+        }
+    }
+
+    /// Mark the job as running, `false` means this run should be skipped
+    fn begin(&self) -> bool {
+        let running = self.running.swap(true, Ordering::AcqRel);
+        !running || self.config.overlap == OverlapPolicy::Allow
+    }
+
+    async fn run(&self, pool: &SwappableThreadPool, time: DateTime<Utc>) -> Result<Res> {
+        let span = info_span!(
+            "schedule",
+            name = %self.name,
+            handler = %self.config.handler,
+            time = %time.to_rfc3339()
+        );
+        async {
+            let req = Req::builder()
+                .method(SCHEDULED_METHOD)
+                .url(&self.name)
+                .headers(HashMap::from([(
+                    "x-ceno-scheduled-time".to_string(),
+                    time.to_rfc3339(),
+                )]))
+                .build();
+            let rx = pool.load().execute(&self.config.handler, req);
+            let res = rx
+                .await
+                .map_err(|_| anyhow!("handler {} failed", self.config.handler))?;
+            info!(status = res.status, "schedule finished");
+            Ok(res)
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+fn parse_jobs(schedules: ProjectSchedules) -> Result<HashMap<String, Arc<Job>>> {
+    schedules
+        .into_iter()
+        .map(|(name, config)| {
+            let schedule = parse_cron(&config.cron)
+                .with_context(|| format!("invalid cron of schedule {name}"))?;
+            let job = Job {
+                name: name.clone(),
+                schedule,
+                config,
+                running: AtomicBool::new(false),
+            };
+            Ok((name, Arc::new(job)))
+        })
+        .collect()
+}
+
+/// Parse a cron expression, the seconds field is optional
+fn parse_cron(expr: &str) -> Result<Schedule> {
+    let expr = if expr.split_whitespace().count() == 5 {
+        format!("0 {expr}")
+    } else {
+        expr.to_string()
+    };
+    Ok(Schedule::from_str(&expr)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{JsCode, JsFormat};
+
+    fn schedule(cron: &str, handler: &str, overlap: OverlapPolicy) -> ProjectSchedule {
+        ProjectSchedule {
+            cron: cron.to_string(),
+            handler: handler.to_string(),
+            overlap,
+        }
+    }
+
+    #[test]
+    fn parse_cron_should_work() {
+        let time = "2024-01-01T00:00:30Z".parse::<DateTime<Utc>>().unwrap();
+        let next = |expr| parse_cron(expr).unwrap().after(&time).next().unwrap();
+        assert_eq!(
+            next("*/5 * * * *").to_rfc3339(),
+            "2024-01-01T00:05:00+00:00"
+        );
+        assert_eq!(
+            next("45 * * * * *").to_rfc3339(),
+            "2024-01-01T00:00:45+00:00"
+        );
+        assert!(parse_cron("* * *").is_err());
+    }
+
+    #[test]
+    fn job_should_follow_overlap_policy() {
+        let jobs = parse_jobs(HashMap::from([
+            (
+                "skip".into(),
+                schedule("* * * * *", "a", OverlapPolicy::Skip),
+            ),
+            (
+                "allow".into(),
+                schedule("* * * * *", "a", OverlapPolicy::Allow),
+            ),
+        ]))
+        .unwrap();
+        assert!(jobs["skip"].begin());
+        assert!(!jobs["skip"].begin());
+        assert!(jobs["allow"].begin());
+        assert!(jobs["allow"].begin());
+    }
+
+    #[tokio::test]
+    async fn scheduler_trigger_should_work() {
+        let code = r#"
+    export async function cleanup(req) {
+        const time = req.headers["x-ceno-scheduled-time"];
+        return { status: 200, headers: {}, body: `${req.method} ${req.url} ${!isNaN(Date.parse(time))}` };
+    }
+    "#;
+        let pool = SwappableThreadPool::new(JsCode::new(code).with_format(JsFormat::Es)).unwrap();
+        let schedules = HashMap::from([(
+            "nightly".into(),
+            schedule("0 3 * * *", "cleanup", OverlapPolicy::Skip),
+        )]);
+        let scheduler = Scheduler::try_new(pool, schedules).unwrap();
+
+        let res = scheduler.trigger("nightly").await.unwrap();
+        assert_eq!(res.body.as_deref(), Some("SCHEDULED nightly true"));
+        assert!(scheduler.trigger("missing").await.is_err());
+
+        // invalid schedules are rejected and the current ones kept
+        let invalid = HashMap::from([("bad".into(), schedule("bad", "x", OverlapPolicy::Skip))]);
+        assert!(scheduler.swap(invalid).is_err());
+        assert!(scheduler.trigger("nightly").await.is_ok());
+    }
+}
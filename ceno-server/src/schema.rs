@@ -0,0 +1,190 @@
+use crate::{
+    config::{RouteSchemas, SchemaSource},
+    AppError, ParamValue, Res,
+};
+use anyhow::{anyhow, Result};
+use jsonschema::Validator;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// The compiled schemas of a route, see [`RouteSchemas`]
+#[derive(Debug, Default)]
+pub struct RouteValidator {
+    query: Option<Validator>,
+    params: Option<Validator>,
+    body: Option<Validator>,
+    response: Option<Validator>,
+}
+
+/// A value which doesn't satisfy its schema
+#[derive(Debug, Serialize)]
+pub struct SchemaViolation {
+    /// JSON pointer to the value
+    pub path: String,
+    pub message: String,
+}
+
+impl RouteValidator {
+    pub fn try_new(schemas: &RouteSchemas) -> Result<Self> {
+        Ok(Self {
+            query: compile("query", &schemas.query)?,
+            params: compile("params", &schemas.params)?,
+            body: compile("body", &schemas.body)?,
+            response: compile("response", &schemas.response)?,
+        })
+    }
+
+    /// Check the query, params and body of a request. Query values are
+    /// strings, while typed params are converted already.
+    pub fn validate_request(
+        &self,
+        query: &HashMap<String, String>,
+        params: &HashMap<String, ParamValue>,
+        body: &[u8],
+    ) -> Result<(), AppError> {
+        if let Some(validator) = &self.query {
+            check(validator, &serde_json::to_value(query)?)
+                .map_err(|e| AppError::RequestInvalid("query", e))?;
+        }
+        if let Some(validator) = &self.params {
+            check(validator, &serde_json::to_value(params)?)
+                .map_err(|e| AppError::RequestInvalid("params", e))?;
+        }
+        if let Some(validator) = &self.body {
+            parse_json(body)
+                .and_then(|body| check(validator, &body))
+                .map_err(|e| AppError::RequestInvalid("body", e))?;
+        }
+        Ok(())
+    }
+
+    /// Check the body of a successful response
+    pub fn validate_response(&self, res: &Res) -> Result<(), AppError> {
+        let Some(validator) = &self.response else {
+            return Ok(());
+        };
+        if !(200..300).contains(&res.status) {
+            return Ok(());
+        }
+        let body = res.body.as_deref().unwrap_or_default();
+        parse_json(body.as_bytes())
+            .and_then(|body| check(validator, &body))
+            .map_err(AppError::ResponseInvalid)
+    }
+}
+
+fn compile(name: &str, source: &Option<SchemaSource>) -> Result<Option<Validator>> {
+    let Some(source) = source else {
+        return Ok(None);
+    };
+    let schema = source.load()?;
+    let validator =
+        jsonschema::validator_for(&schema).map_err(|e| anyhow!("invalid {name} schema: {e}"))?;
+    Ok(Some(validator))
+}
+
+/// An empty body is `null`
+fn parse_json(body: &[u8]) -> Result<Value, Vec<SchemaViolation>> {
+    if body.is_empty() {
+        return Ok(Value::Null);
+    }
+    serde_json::from_slice(body).map_err(|e| {
+        vec![SchemaViolation {
+            path: String::new(),
+            message: format!("invalid JSON: {e}"),
+        }]
+    })
+}
+
+fn check(validator: &Validator, instance: &Value) -> Result<(), Vec<SchemaViolation>> {
+    let violations = validator
+        .iter_errors(instance)
+        .map(|e| SchemaViolation {
+            path: e.instance_path().to_string(),
+            message: e.to_string(),
+        })
+        .collect::<Vec<_>>();
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn validator() -> RouteValidator {
+        let schemas = RouteSchemas {
+            query: Some(SchemaSource::Inline(json!({
+                "type": "object",
+                "properties": { "sort": { "enum": ["asc", "desc"] } }
+            }))),
+            params: Some(SchemaSource::Inline(json!({
+                "type": "object",
+                "properties": { "id": { "type": "integer", "minimum": 1 } }
+            }))),
+            body: Some(SchemaSource::Inline(json!({
+                "type": "object",
+                "required": ["name"],
+                "properties": { "name": { "type": "string" } }
+            }))),
+            response: Some(SchemaSource::Inline(json!({ "type": "array" }))),
+        };
+        RouteValidator::try_new(&schemas).unwrap()
+    }
+
+    #[test]
+    fn validate_request_should_report_violations() {
+        let validator = validator();
+        let query = HashMap::from([("sort".to_string(), "asc".to_string())]);
+        let params = HashMap::from([("id".to_string(), ParamValue::Int(1))]);
+        assert!(validator
+            .validate_request(&query, &params, br#"{"name":"a"}"#)
+            .is_ok());
+
+        let bad_query = HashMap::from([("sort".to_string(), "up".to_string())]);
+        assert!(matches!(
+            validator.validate_request(&bad_query, &params, br#"{"name":"a"}"#),
+            Err(AppError::RequestInvalid("query", _))
+        ));
+        let bad_params = HashMap::from([("id".to_string(), ParamValue::Int(0))]);
+        assert!(matches!(
+            validator.validate_request(&query, &bad_params, br#"{"name":"a"}"#),
+            Err(AppError::RequestInvalid("params", _))
+        ));
+        match validator.validate_request(&query, &params, br#"{"name":1}"#) {
+            Err(AppError::RequestInvalid("body", violations)) => {
+                assert_eq!(violations[0].path, "/name");
+            }
+            _ => panic!("expected invalid body"),
+        }
+        assert!(validator.validate_request(&query, &params, b"").is_err());
+        assert!(validator.validate_request(&query, &params, b"{").is_err());
+    }
+
+    #[test]
+    fn validate_response_should_only_check_success() {
+        let validator = validator();
+        let res = |status, body: &str| Res {
+            status,
+            headers: HashMap::new(),
+            body: Some(body.to_string()),
+        };
+        assert!(validator.validate_response(&res(200, "[]")).is_ok());
+        assert!(validator.validate_response(&res(200, "{}")).is_err());
+        assert!(validator.validate_response(&res(404, "{}")).is_ok());
+    }
+
+    #[test]
+    fn invalid_schema_should_be_rejected() {
+        let schemas = RouteSchemas {
+            body: Some(SchemaSource::Inline(json!({ "type": "nope" }))),
+            ..Default::default()
+        };
+        assert!(RouteValidator::try_new(&schemas).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+// The `ceno` namespace of host services, see `services.rs`
+(function (native) {
+  const queue = {
+    async enqueue(name, payload, options = {}) {
+      if (typeof name !== "string" || name === "") {
+        throw new TypeError("queue name must be a non-empty string");
+      }
+      const body = JSON.stringify(payload);
+      if (body === undefined) {
+        throw new TypeError("payload must be serializable to JSON");
+      }
+      const { delay = 0, retries } = options;
+      if (!Number.isSafeInteger(delay) || delay < 0) {
+        throw new RangeError("delay must be a non-negative integer of milliseconds");
+      }
+      if (retries !== undefined && (!Number.isInteger(retries) || retries < 0 || retries > 0xffffffff)) {
+        throw new RangeError("retries must be a non-negative integer");
+      }
+      return native.enqueue(name, body, delay, retries);
+    },
+  };
+
+  // id of the span whose callback is running, spans opened synchronously
+  // inside it are its children. After an `await` the span passed to the
+  // callback has to be used instead.
+  let current;
+
+  function span(name, fn, parent) {
+    if (typeof name !== "string" || name === "") {
+      throw new TypeError("span name must be a non-empty string");
+    }
+    if (typeof fn !== "function") {
+      throw new TypeError("span callback must be a function");
+    }
+    const id = native.spanStart(name, parent);
+    const handle = Object.freeze({
+      span: (name, fn) => span(name, fn, id),
+      traceparent: () => native.traceparent(id),
+    });
+    const outer = current;
+    current = id;
+    let result;
+    try {
+      result = fn(handle);
+    } catch (e) {
+      native.spanEnd(id, String(e));
+      throw e;
+    } finally {
+      current = outer;
+    }
+    if (typeof result?.then !== "function") {
+      native.spanEnd(id);
+      return result;
+    }
+    return result.then(
+      (value) => {
+        native.spanEnd(id);
+        return value;
+      },
+      (e) => {
+        native.spanEnd(id, String(e));
+        throw e;
+      },
+    );
+  }
+
+  const trace = {
+    span: (name, fn) => span(name, fn, current),
+    traceparent: () => native.traceparent(current),
+  };
+
+  globalThis.ceno = Object.freeze({
+    queue: Object.freeze(queue),
+    trace: Object.freeze(trace),
+  });
+})
@@ -0,0 +1,38 @@
+use crate::{queue::EnqueueOptions, trace, JobQueue};
+use rquickjs::{Ctx, Exception, Function, Object, Result};
+use std::sync::Arc;
+
+/// Host services of a tenant, exposed to handlers under the `ceno` global
+#[derive(Clone, Default)]
+pub struct Services {
+    /// backs `ceno.queue.enqueue`, which throws if it is `None`
+    pub queue: Option<Arc<JobQueue>>,
+}
+
+/// Install the `ceno` global into a context
+pub(crate) fn init(ctx: &Ctx<'_>, services: &Services) -> Result<()> {
+    let native = Object::new(ctx.clone())?;
+    let queue = services.queue.clone();
+    let enqueue = move |ctx: Ctx<'_>,
+                        name: String,
+                        payload: String,
+                        delay: f64,
+                        retries: Option<u32>|
+          -> Result<String> {
+        let Some(queue) = &queue else {
+            return Err(Exception::throw_message(&ctx, "queue is not enabled"));
+        };
+        let options = EnqueueOptions {
+            delay: delay as u64,
+            retries,
+        };
+        queue
+            .enqueue(&name, payload, options)
+            .map_err(|e| Exception::throw_message(&ctx, &format!("enqueue failed: {e}")))
+    };
+    native.set("enqueue", Function::new(ctx.clone(), enqueue)?)?;
+    trace::init(ctx, &native)?;
+
+    let setup: Function = ctx.eval(include_str!("services.js"))?;
+    setup.call((native,))
+}
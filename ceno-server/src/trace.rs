@@ -0,0 +1,213 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::{
+    propagation::{Extractor, Injector, TextMapPropagator as _},
+    trace::TraceContextExt as _,
+    Context,
+};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use rquickjs::{function::Opt, Ctx, Function, Object, Result};
+use tracing::{field, info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+
+const TRACEPARENT: &str = "traceparent";
+
+thread_local! {
+    /// spans opened by `ceno.trace.span` on the worker of this thread
+    static SPANS: RefCell<Spans> = RefCell::default();
+}
+
+#[derive(Default)]
+struct Spans {
+    next: u32,
+    open: HashMap<u32, Span>,
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Parent `span` to the W3C trace context sent in `headers`, if valid
+pub(crate) fn set_parent(span: &Span, headers: &HeaderMap) {
+    if !headers.contains_key(TRACEPARENT) {
+        return;
+    }
+    let cx = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+    if cx.span().span_context().is_valid() {
+        span.set_parent(cx);
+    }
+}
+
+/// Set the `traceparent` and `tracestate` of `span` on `headers`, keeping
+/// the ones of the client when spans aren't exported. Only proxied
+/// requests go through it, the runtime has no `fetch` to hook into
+pub(crate) fn inject(span: &Span, headers: &mut HeaderMap) {
+    let cx = span.context();
+    if cx.span().span_context().is_valid() {
+        TraceContextPropagator::new().inject_context(&cx, &mut HeaderInjector(headers));
+    }
+}
+
+/// The `traceparent` header of `span`, `None` when spans aren't exported
+fn traceparent(span: &Span) -> Option<String> {
+    let cx: Context = span.context();
+    if !cx.span().span_context().is_valid() {
+        return None;
+    }
+    let mut fields = HashMap::new();
+    TraceContextPropagator::new().inject_context(&cx, &mut fields);
+    fields.remove(TRACEPARENT)
+}
+
+/// Close the spans the last request left open, e.g. on promises which
+/// never settled
+pub(crate) fn reset() {
+    SPANS.take();
+}
+
+/// Add the functions backing `ceno.trace` to `native`
+pub(crate) fn init<'js>(ctx: &Ctx<'js>, native: &Object<'js>) -> Result<()> {
+    // a parent which has ended already is replaced by the current span
+    let start = |name: String, parent: Option<u32>| -> u32 {
+        SPANS.with_borrow_mut(|spans| {
+            let parent = parent
+                .and_then(|id| spans.open.get(&id).cloned())
+                .unwrap_or_else(Span::current);
+            let span = info_span!(
+                parent: &parent,
+                "js",
+                otel.name = %name,
+                otel.status_code = field::Empty,
+                otel.status_message = field::Empty,
+            );
+            spans.next = spans.next.wrapping_add(1);
+            spans.open.insert(spans.next, span);
+            spans.next
+        })
+    };
+    let end = |id: u32, Opt(error): Opt<String>| {
+        let span = SPANS.with_borrow_mut(|spans| spans.open.remove(&id));
+        if let (Some(span), Some(error)) = (span, error) {
+            span.record("otel.status_code", "ERROR");
+            span.record("otel.status_message", error);
+        }
+    };
+    let current = |id: Option<u32>| -> Option<String> {
+        let span = SPANS.with_borrow(|spans| id.and_then(|id| spans.open.get(&id).cloned()));
+        traceparent(&span.unwrap_or_else(Span::current))
+    };
+    native.set("spanStart", Function::new(ctx.clone(), start)?)?;
+    native.set("spanEnd", Function::new(ctx.clone(), end)?)?;
+    native.set("traceparent", Function::new(ctx.clone(), current)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{JsWorker, Req};
+    use futures_util::future::BoxFuture;
+    use opentelemetry::trace::{SpanId, Status, TraceId, TracerProvider as _};
+    use opentelemetry_sdk::{
+        export::trace::{ExportResult, SpanData, SpanExporter},
+        trace::TracerProvider,
+    };
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    /// Stands in for an OTLP collector, keeping the spans in memory
+    #[derive(Debug, Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Collector {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[test]
+    fn js_spans_should_join_the_incoming_trace() {
+        let collector = Collector::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(collector.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        let code = r#"
+    (function(){
+        async function hello(req){
+            const parent = ceno.trace.traceparent();
+            const body = await ceno.trace.span("outer", async (span) => {
+                await null;
+                span.span("inner", () => {});
+                return span.traceparent();
+            });
+            try {
+                ceno.trace.span("failing", () => { throw new Error("boom"); });
+            } catch (e) {}
+            return { status: 200, headers: {}, body: parent + " " + body };
+        }
+        return { hello };
+    })();
+    "#;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            TRACEPARENT,
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+                .parse()
+                .unwrap(),
+        );
+        let body = tracing::subscriber::with_default(subscriber, || {
+            let request = info_span!("request");
+            set_parent(&request, &headers);
+            let _entered = request.enter();
+            let worker = JsWorker::try_new(code).unwrap();
+            let req = Req::builder()
+                .method("GET")
+                .url("/")
+                .headers(Default::default())
+                .build();
+            worker.run("hello", req).unwrap().body.unwrap()
+        });
+
+        let spans = collector.0.lock().unwrap().clone();
+        let span = |name: &str| spans.iter().find(|s| s.name == name).unwrap().clone();
+        let trace_id = TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap();
+        assert!(spans.iter().all(|s| s.span_context.trace_id() == trace_id));
+        let (request, run) = (span("request"), span("run"));
+        let (outer, inner) = (span("outer"), span("inner"));
+        assert_eq!(
+            request.parent_span_id,
+            SpanId::from_hex("b7ad6b7169203331").unwrap()
+        );
+        assert_eq!(outer.parent_span_id, run.span_context.span_id());
+        assert_eq!(inner.parent_span_id, outer.span_context.span_id());
+        assert!(matches!(span("failing").status, Status::Error { .. }));
+
+        let (parent, from_outer) = body.split_once(' ').unwrap();
+        let id = |span: &SpanData| format!("{}-{}", trace_id, span.span_context.span_id());
+        assert_eq!(parent, format!("00-{}-01", id(&run)));
+        assert_eq!(from_outer, format!("00-{}-01", id(&outer)));
+    }
+}
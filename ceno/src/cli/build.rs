@@ -1,25 +1,32 @@
+use crate::fs_routes::{discover, entry_source, merge};
 use crate::utils::calc_project_hash;
 use crate::{CmdExector, BUILD_DIR};
-use bundler::run_bundle;
+use anyhow::Context as _;
+use bundler::{run_bundle, ModuleType, Options};
+use ceno_server::{
+    compile_bytecode, JsCode, JsFormat, ProjectConfig, SwappableAppRouter, CONFIG_FILES,
+};
 use clap::Parser;
-use std::fs::File;
-use std::path::Path;
-use std::{env, fs, io};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
 
 #[derive(Debug, Parser)]
-pub struct BuildOpts {}
+pub struct BuildOpts {
+    #[arg(long, help = "Check the config merged with config.<ENV>.yml")]
+    pub env: Option<String>,
+}
 
 impl CmdExector for BuildOpts {
     async fn execute(self) -> anyhow::Result<()> {
         let cur_dir = env::current_dir()?.display().to_string();
-        let filename = build_project(&cur_dir, true)?;
+        let filename = build_project(&cur_dir, true, self.env.as_deref())?;
         eprintln!("Build success: {}", filename);
 
         Ok(())
     }
 }
 
-pub(crate) fn build_project(dir: &str, recrate: bool) -> anyhow::Result<String> {
+pub(crate) fn build_project(dir: &str, recrate: bool, env: Option<&str>) -> anyhow::Result<String> {
     let hash = calc_project_hash(dir)?;
 
     if recrate {
@@ -27,8 +34,9 @@ pub(crate) fn build_project(dir: &str, recrate: bool) -> anyhow::Result<String>
     }
     fs::create_dir_all(BUILD_DIR)?;
 
-    let filename = format!("{}/{}.js", BUILD_DIR, hash);
-    let config = format!("{}/{}.yml", BUILD_DIR, hash);
+    let filename = format!("{}/{}.mjs", BUILD_DIR, hash);
+    let config_file = ProjectConfig::find(".")?;
+    let config = config_path(&filename, &config_file);
     let dst = Path::new(&filename);
     // if the file already exists, skip building
     if dst.exists() {
@@ -36,11 +44,80 @@ pub(crate) fn build_project(dir: &str, recrate: bool) -> anyhow::Result<String>
     }
 
     // build the project
-    let content = run_bundle("main.ts", &Default::default())?;
-    fs::write(dst, content)?;
-    let mut dst = File::create(config)?;
-    let mut src = File::open("config.yml")?;
-    io::copy(&mut src, &mut dst)?;
+    let options = Options {
+        module_type: ModuleType::Es,
+        ..Default::default()
+    };
+    let entry = match ProjectConfig::load_env(&config_file, env)? {
+        config if config.fs_routes => {
+            let routes = discover(dir)?;
+            let mut merged = config;
+            merge(&mut merged, &routes)?;
+            // overlapping paths, like `/users/{id}` and `/users/new`, are
+            // fine, but the router rejects the ones it can't tell apart
+            SwappableAppRouter::try_new("", &merged).context("conflicting routes")?;
+            fs::write(
+                routes_path(&filename),
+                serde_json::to_string_pretty(&routes)?,
+            )?;
+            let entry = Path::new(BUILD_DIR).join("entry.ts");
+            fs::write(&entry, entry_source(&routes, Path::new("main.ts").exists()))?;
+            entry.display().to_string()
+        }
+        _ => "main.ts".to_string(),
+    };
+    let content = run_bundle(&entry, &options)?;
+    let code = JsCode::new(content).with_format(JsFormat::Es);
+    // precompile so that workers don't need to parse the bundle on startup
+    fs::write(bytecode_path(&filename), compile_bytecode(&code)?)?;
+    fs::write(dst, code.source)?;
+    fs::copy(&config_file, &config)?;
+    // overlays are picked by `--env` when the build runs
+    let (stem, ext) = file_name(&config_file)
+        .split_once('.')
+        .context("config without extension")?;
+    for overlay in fs::read_dir(".")? {
+        let overlay = overlay?.file_name();
+        let Some(env) = overlay
+            .to_str()
+            .and_then(|name| name.strip_prefix(stem)?.strip_prefix('.'))
+            .and_then(|name| name.strip_suffix(ext)?.strip_suffix('.'))
+        else {
+            continue;
+        };
+        fs::copy(&overlay, config.with_extension(format!("{env}.{ext}")))?;
+    }
 
     Ok(filename)
 }
+
+/// bytecode of a bundle is stored next to it
+pub(crate) fn bytecode_path(filename: &str) -> PathBuf {
+    Path::new(filename).with_extension("qjsc")
+}
+
+/// so is the config it was built with, keeping its format
+pub(crate) fn config_path(filename: &str, config: &Path) -> PathBuf {
+    let ext = config.extension().unwrap_or_default();
+    Path::new(filename).with_extension(ext)
+}
+
+/// The config a bundle was built with
+pub(crate) fn built_config_path(filename: &str) -> anyhow::Result<PathBuf> {
+    CONFIG_FILES
+        .iter()
+        .map(|name| config_path(filename, Path::new(name)))
+        .find(|path| path.exists())
+        .with_context(|| format!("no config built with {filename}"))
+}
+
+fn file_name(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+}
+
+/// and so are the routes discovered under `routes/`
+pub(crate) fn routes_path(filename: &str) -> PathBuf {
+    Path::new(filename).with_extension("routes.json")
+}
@@ -0,0 +1,58 @@
+use crate::{fs_routes::load_config, CmdExector};
+use anyhow::Result;
+use ceno_server::CONFIG_SCHEMA;
+use clap::{Parser, Subcommand};
+use serde_yaml::Value;
+
+#[derive(Debug, Parser)]
+pub struct ConfigOpts {
+    #[command(subcommand)]
+    pub cmd: ConfigCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    #[command(
+        name = "print",
+        about = "Print the config with its overlay, variables, groups and includes resolved"
+    )]
+    Print {
+        #[arg(long, help = "Merge config.<ENV>.yml into the config")]
+        env: Option<String>,
+    },
+    #[command(name = "schema", about = "Print the JSON Schema of the config")]
+    Schema,
+}
+
+impl CmdExector for ConfigOpts {
+    async fn execute(self) -> Result<()> {
+        match self.cmd {
+            ConfigCommand::Print { env } => {
+                let config = load_config(env.as_deref())?;
+                let mut value = serde_yaml::to_value(&config)?;
+                // settings left unset are noise
+                remove_unset(&mut value);
+                print!("{}", serde_yaml::to_string(&value)?);
+            }
+            ConfigCommand::Schema => print!("{CONFIG_SCHEMA}"),
+        }
+        Ok(())
+    }
+}
+
+/// Remove nulls and empty collections from the mappings of `value`
+fn remove_unset(value: &mut Value) {
+    match value {
+        Value::Mapping(map) => {
+            map.values_mut().for_each(remove_unset);
+            map.retain(|_, v| match v {
+                Value::Null => false,
+                Value::Mapping(map) => !map.is_empty(),
+                Value::Sequence(seq) => !seq.is_empty(),
+                _ => true,
+            });
+        }
+        Value::Sequence(seq) => seq.iter_mut().for_each(remove_unset),
+        _ => {}
+    }
+}
@@ -1,9 +1,9 @@
 use crate::CmdExector;
 use anyhow::Result;
 use askama::Template;
-use ceno_server::{Req, Res};
+use ceno_server::{Req, Res, CONFIG_FILES, CONFIG_SCHEMA};
 use clap::Parser;
-use dialoguer::Input;
+use dialoguer::{Input, Select};
 use git2::Repository;
 use std::{fs, path::Path};
 use ts_rs::TS as _;
@@ -11,12 +11,43 @@ use ts_rs::TS as _;
 #[derive(Debug, Parser)]
 pub struct InitOpts {}
 
+/// declaration of the `ceno` global, see `ceno_server::Services`
+const CENO_DECL: &str = r#"declare global {
+  const ceno: {
+    queue: {
+      enqueue(name: string, payload: unknown, options?: { delay?: number; retries?: number }): Promise<string>;
+    };
+    trace: {
+      span<T>(name: string, fn: (span: CenoSpan) => T): T;
+      traceparent(): string | null;
+    };
+  };
+  interface CenoSpan {
+    span<T>(name: string, fn: (span: CenoSpan) => T): T;
+    traceparent(): string | null;
+  }
+}
+"#;
+
+/// `name` is a quoted string in every format
 #[derive(Template)]
-#[template(path = "config.yml.j2")]
+#[template(path = "config.yml.j2", escape = "none")]
 struct ConfigFile {
     name: String,
 }
 
+#[derive(Template)]
+#[template(path = "config.json.j2", escape = "none")]
+struct JsonConfigFile {
+    name: String,
+}
+
+#[derive(Template)]
+#[template(path = "config.toml.j2", escape = "none")]
+struct TomlConfigFile {
+    name: String,
+}
+
 #[derive(Template)]
 #[template(path = "main.ts.j2")]
 struct MainTsFile {}
@@ -28,28 +59,38 @@ struct GitIgnoreFile {}
 impl CmdExector for InitOpts {
     async fn execute(self) -> Result<()> {
         let name: String = Input::new().with_prompt("Project name").interact_text()?;
+        let config = Select::new()
+            .with_prompt("Config format")
+            .items(&CONFIG_FILES)
+            .default(0)
+            .interact()?;
+        let config = CONFIG_FILES[config];
 
         // if current dir is empty then init project, otherwise create new dir and init project
         let cur = Path::new(".");
         if fs::read_dir(cur)?.next().is_none() {
-            init_project(&name, cur)?;
+            init_project(&name, config, cur)?;
         } else {
             let path = cur.join(&name);
-            init_project(&name, &path)?;
+            init_project(&name, config, &path)?;
         }
 
         Ok(())
     }
 }
 
-fn init_project(name: &str, path: &Path) -> Result<()> {
+fn init_project(name: &str, config: &str, path: &Path) -> Result<()> {
     // init git repository
     Repository::init(path)?;
-    // init config file
-    let config = ConfigFile {
-        name: name.to_string(),
+    // init config file, along with its schema for editors
+    let quoted = serde_json::to_string(name)?;
+    let content = match config.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("json") => JsonConfigFile { name: quoted }.render()?,
+        Some("toml") => TomlConfigFile { name: quoted }.render()?,
+        _ => ConfigFile { name: quoted }.render()?,
     };
-    fs::write(path.join("config.yml"), config.render()?)?;
+    fs::write(path.join(config), content)?;
+    fs::write(path.join("config.schema.json"), CONFIG_SCHEMA)?;
     // init main.ts file
     fs::write(path.join("main.ts"), MainTsFile {}.render()?)?;
     // init .gitignore file
@@ -61,9 +102,35 @@ fn init_project(name: &str, path: &Path) -> Result<()> {
     s.push('\n');
     s.push_str(&Res::decl());
     s.push('\n');
+    s.push_str("export type Next = (req?: Req) => Promise<Res>;\n");
+    s.push_str("export type Ctx = { waitUntil(promise: Promise<unknown>): void };\n");
     s.push_str("export function rust_print(msg: string): void;\n");
+    s.push_str(CENO_DECL);
     s.push_str("export {Req, Res}\n");
     fs::write(path.join("types.d.ts"), s)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ceno_server::ProjectConfig;
+
+    #[test]
+    fn init_project_should_write_every_config_format() -> Result<()> {
+        for config in CONFIG_FILES {
+            let dir = tempfile::tempdir()?;
+            init_project("my \"app\"", config, dir.path())?;
+            let file = ProjectConfig::find(dir.path())?;
+            assert!(file.ends_with(config));
+            let loaded = ProjectConfig::load(file)?;
+            assert_eq!(loaded.name, "my \"app\"");
+            assert_eq!(
+                loaded.routes["/api/hello"][0].action.to_string(),
+                "handler hello"
+            );
+        }
+        Ok(())
+    }
+}
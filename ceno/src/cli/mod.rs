@@ -1,11 +1,19 @@
 mod build;
+mod config;
 mod init;
+mod openapi;
+mod queue;
 mod run;
+mod telemetry;
+mod types;
 
 use clap::Parser;
 use enum_dispatch::enum_dispatch;
 
-pub use self::{build::BuildOpts, init::InitOpts, run::RunOpts};
+pub use self::{
+    build::BuildOpts, config::ConfigOpts, init::InitOpts, openapi::OpenApiOpts, queue::QueueOpts,
+    run::RunOpts, types::TypesOpts,
+};
 
 #[derive(Debug, Parser)]
 #[command(name = "ceno", version, author, about, long_about = None)]
@@ -23,4 +31,15 @@ pub enum SubCommand {
     Build(BuildOpts),
     #[command(name = "run", about = "Run user's ceno project")]
     Run(RunOpts),
+    #[command(name = "queue", about = "Inspect jobs of ceno.queue")]
+    Queue(QueueOpts),
+    #[command(name = "types", about = "Generate TypeScript types from route schemas")]
+    Types(TypesOpts),
+    #[command(
+        name = "openapi",
+        about = "Generate the OpenAPI document of the routes"
+    )]
+    OpenApi(OpenApiOpts),
+    #[command(name = "config", about = "Inspect the config of ceno project")]
+    Config(ConfigOpts),
 }
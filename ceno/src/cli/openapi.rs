@@ -0,0 +1,32 @@
+use crate::{fs_routes::load_config, CmdExector};
+use anyhow::Result;
+use ceno_server::openapi;
+use clap::Parser;
+use std::fs;
+
+#[derive(Debug, Parser)]
+pub struct OpenApiOpts {
+    #[arg(
+        short,
+        long,
+        default_value = "openapi.json",
+        help = "File to write the document to, `-` for stdout"
+    )]
+    pub output: String,
+    #[arg(long, help = "Merge config.<ENV>.yml into the config")]
+    pub env: Option<String>,
+}
+
+impl CmdExector for OpenApiOpts {
+    async fn execute(self) -> Result<()> {
+        let config = load_config(self.env.as_deref())?;
+        let doc = serde_json::to_string_pretty(&openapi(&config)?)?;
+        if self.output == "-" {
+            println!("{doc}");
+        } else {
+            fs::write(&self.output, doc)?;
+            println!("OpenAPI document written to {}", self.output);
+        }
+        Ok(())
+    }
+}
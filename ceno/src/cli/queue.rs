@@ -0,0 +1,49 @@
+use crate::{CmdExector, QUEUE_DIR};
+use ceno_server::{Job, JobQueue};
+use clap::{Parser, Subcommand};
+use std::path::Path;
+
+#[derive(Debug, Parser)]
+pub struct QueueOpts {
+    #[command(subcommand)]
+    pub cmd: QueueCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum QueueCommand {
+    #[command(name = "dead", about = "List jobs which ran out of retries")]
+    Dead {
+        #[arg(short, long, help = "Only list jobs of this queue")]
+        queue: Option<String>,
+    },
+}
+
+impl CmdExector for QueueOpts {
+    async fn execute(self) -> anyhow::Result<()> {
+        match self.cmd {
+            QueueCommand::Dead { queue } => {
+                let store = JobQueue::open(Path::new(QUEUE_DIR).join("localhost"))?;
+                let jobs = store
+                    .dead_letters()?
+                    .into_iter()
+                    .filter(|job| queue.as_ref().is_none_or(|name| &job.queue == name));
+                for job in jobs {
+                    println!("{}", format_job(&job));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn format_job(job: &Job) -> String {
+    format!(
+        "{}\t{}\tattempts={}\tcreated_at={}\terror={}\tpayload={}",
+        job.id,
+        job.queue,
+        job.attempts,
+        job.created_at.to_rfc3339(),
+        job.last_error.as_deref().unwrap_or_default(),
+        job.payload
+    )
+}
@@ -1,23 +1,24 @@
-use super::build::build_project;
-use crate::{CmdExector, BUILD_DIR};
+use super::build::{build_project, built_config_path, bytecode_path, routes_path};
+use super::telemetry::{TraceOpts, TracingGuard};
+use crate::fs_routes::merge;
+use crate::{CmdExector, BUILD_DIR, QUEUE_DIR};
 use ceno_server::{
-    start_server, ProjectConfig, SwappableAppRouter, SwappableThreadPool, TenentRouter,
+    start_server, JobQueue, JsCode, JsFormat, Metrics, ProjectConfig, QueueDispatcher, Scheduler,
+    ServerOptions, Services, SwappableAppRouter, SwappableThreadPool, TenentRouter,
 };
 use clap::Parser;
 use notify::{RecommendedWatcher, RecursiveMode};
 use notify_debouncer_mini::{new_debouncer, DebounceEventResult, DebouncedEvent, Debouncer};
 use opentelemetry::trace::TracerProvider as _;
-use opentelemetry::KeyValue;
-use opentelemetry_sdk::trace::Config;
-use opentelemetry_sdk::Resource;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::{channel, Receiver};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::{Stream, StreamExt};
 use tracing::level_filters::LevelFilter;
-use tracing::{info, Level};
+use tracing::{error, info, Level};
 use tracing_subscriber::filter::Targets;
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer as _};
 
@@ -111,74 +112,137 @@ impl SwapWatcher for FsWatcher {
 pub struct RunOpts {
     #[arg(short, long, default_value = "5000", help = "Port to listen")]
     pub port: u16,
-    #[arg(long, default_value_t = false, help = "Enable opentelemetry")]
-    pub otlp: bool,
+    #[arg(long, value_name = "SCHEDULE", help = "Run a schedule once and exit")]
+    pub trigger: Option<String>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Validate responses against the schemas of their routes"
+    )]
+    pub dev: bool,
+    #[arg(long, help = "Merge config.<ENV>.yml into the config")]
+    pub env: Option<String>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Serve Prometheus metrics on /metrics of port 9464"
+    )]
+    pub metrics: bool,
+    #[arg(long, help = "Serve /metrics on this port instead [implies --metrics]")]
+    pub metrics_port: Option<u16>,
+    #[command(flatten)]
+    pub trace: TraceOpts,
 }
 
 impl CmdExector for RunOpts {
     async fn execute(self) -> anyhow::Result<()> {
         let fmt_layer = tracing_subscriber::fmt::Layer::new().with_filter(LevelFilter::INFO);
 
-        let provider = opentelemetry_otlp::new_pipeline()
-            .tracing()
-            .with_exporter(opentelemetry_otlp::new_exporter().tonic())
-            .with_trace_config(
-                Config::default()
-                    .with_resource(Resource::new(vec![KeyValue::new("service.name", "ceno")])),
-            )
-            .install_batch(opentelemetry_sdk::runtime::Tokio)
-            .expect("Couldn't create OTLP tracer");
+        let provider = self.trace.provider()?;
+        let telemetry_layer = provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer("ceno"))
+                .with_filter(Targets::new().with_target("ceno", Level::INFO))
+        });
+        tracing_subscriber::registry()
+            .with(fmt_layer)
+            .with(telemetry_layer)
+            .init();
+        // spans still batched are exported when `execute` returns
+        let _tracing = provider.map(TracingGuard::new);
 
-        let tracer = provider.tracer("ceno");
+        let (code, config) = get_code_and_config(self.env.as_deref())?;
 
-        let telemetry_layer = tracing_opentelemetry::layer()
-            .with_tracer(tracer)
-            .with_filter(Targets::new().with_target("ceno", Level::INFO));
+        let router = SwappableAppRouter::try_new(&code.source, &config)?;
+        let routers = vec![TenentRouter::new("localhost", router.clone())];
 
-        let r = tracing_subscriber::registry().with(fmt_layer);
+        let queue = JobQueue::open(Path::new(QUEUE_DIR).join("localhost"))?;
+        let services = Services {
+            queue: Some(queue.clone()),
+        };
+        let pool = SwappableThreadPool::with_services(code, services)?;
+        let pools = vec![("localhost".to_string(), pool.clone())];
 
-        if self.otlp {
-            r.with(telemetry_layer).init();
-        } else {
-            r.init();
+        let scheduler = Scheduler::try_new(pool.clone(), config.schedules)?;
+        if let Some(name) = self.trigger {
+            let res = scheduler.trigger(&name).await?;
+            println!("{}", res.status);
+            if let Some(body) = res.body {
+                println!("{body}");
+            }
+            return Ok(());
         }
-
-        let (code, config) = get_code_and_config()?;
-
-        let router = SwappableAppRouter::try_new(&code, config.routes)?;
-        let routers = vec![TenentRouter::new("localhost", router.clone())];
-
+        scheduler.start();
+        let dispatcher = QueueDispatcher::new(pool.clone(), queue, config.queues);
+        dispatcher.start();
+
+        let metrics = (self.metrics || self.metrics_port.is_some()).then(Arc::<Metrics>::default);
+        let swap = Swap {
+            router,
+            pool,
+            scheduler,
+            dispatcher,
+            env: self.env.clone(),
+            metrics: metrics.clone(),
+        };
         let mut notifier = FsWatcher::try_new(format!("./{}", BUILD_DIR))?;
 
-        let pool = SwappableThreadPool::new(&code);
-        let pools = vec![("localhost".to_string(), pool.clone())];
-
         tokio::spawn(async move {
             // take debouncer and drop it to stop watching files in the end of the async block
             let _debouncer = notifier.debouncer.take();
             let stream = notifier.recv()?;
 
-            handle_swap(router, pool, stream).await
+            handle_swap(swap, stream).await
         });
 
-        start_server(self.port, routers, pools).await?;
+        let mut options = ServerOptions::builder()
+            .validate_responses(self.dev)
+            .build();
+        options.metrics = metrics;
+        options.metrics_port = self.metrics_port;
+        start_server(self.port, routers, pools, options).await?;
 
         Ok(())
     }
 }
 
-async fn handle_swap(
+/// What's swapped when the build changes
+struct Swap {
     router: SwappableAppRouter,
     pool: SwappableThreadPool,
+    scheduler: Scheduler,
+    dispatcher: QueueDispatcher,
+    env: Option<String>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Swap {
+    fn reload(&self) -> anyhow::Result<()> {
+        let (code, config) = get_code_and_config(self.env.as_deref())?;
+        let previous = self.router.inner.load_full();
+        self.router.swap(&code.source, &config)?;
+        if let Err(e) = self.pool.swap(code) {
+            // the last good build keeps serving
+            self.router.inner.store(previous);
+            return Err(e);
+        }
+        self.scheduler.swap(config.schedules)?;
+        self.dispatcher.swap(config.queues);
+        Ok(())
+    }
+}
+
+async fn handle_swap(
+    swap: Swap,
     mut stream: impl Stream<Item = FileChangedEvent> + Unpin,
 ) -> anyhow::Result<()> {
     while let Some(event) = stream.next().await {
         let mut need_swap = false;
-        // config.yml change, or any ".ts" / ".js" file change
+        // config.yml change, or any ".ts" / ".js" / ".mjs" file change
         for path in event.files {
             info!("path: {:?}", path);
             let ext = path.extension().unwrap_or_default();
-            if path.ends_with("config.yml") || ext == "ts" || ext == "js" {
+            if path.ends_with("config.yml") || ext == "ts" || ext == "js" || ext == "mjs" {
                 info!("File changed: {}", path.display());
                 need_swap = true;
                 break;
@@ -186,19 +250,32 @@ async fn handle_swap(
         }
 
         if need_swap {
-            let (code, config) = get_code_and_config()?;
-            pool.swap(&code);
-            router.swap(code, config.routes)?;
+            // the last good build keeps serving until the next change
+            let reloaded = swap.reload();
+            if let Err(e) = &reloaded {
+                error!("reload failed: {e:#}");
+            }
+            if let Some(metrics) = &swap.metrics {
+                metrics.reloaded("localhost", reloaded.is_ok());
+            }
         }
     }
     Ok(())
 }
 
-fn get_code_and_config() -> anyhow::Result<(String, ProjectConfig)> {
-    let filename = build_project(".", false)?;
-    let config = filename.replace(".js", ".yml");
-    let code = fs::read_to_string(filename)?;
-    let config = ProjectConfig::load(config)?;
+fn get_code_and_config(env: Option<&str>) -> anyhow::Result<(JsCode, ProjectConfig)> {
+    let filename = build_project(".", false, env)?;
+    let config = built_config_path(&filename)?;
+    let mut code = JsCode::new(fs::read_to_string(&filename)?).with_format(JsFormat::Es);
+    // builds made by an older ceno may come without bytecode
+    if let Ok(bytecode) = fs::read(bytecode_path(&filename)) {
+        code = code.with_bytecode(bytecode);
+    }
+    let mut config = ProjectConfig::load_env(config, env)?;
+    if let Ok(routes) = fs::read_to_string(routes_path(&filename)) {
+        merge(&mut config, &serde_json::from_str::<Vec<_>>(&routes)?)?;
+    }
+    let code = code.with_middleware(config.middleware.clone());
     Ok((code, config))
 }
 
@@ -245,7 +322,7 @@ mod tests {
 
         let _debouncer = composited_notify.fs.debouncer.take();
 
-        let mut stream = composited_notify.recv()?;
+        let stream = composited_notify.recv()?;
 
         let mut stream = pin!(stream);
 
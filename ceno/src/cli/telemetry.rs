@@ -0,0 +1,346 @@
+use anyhow::{anyhow, Context as _, Result};
+use async_trait::async_trait;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Uri};
+use clap::{Args, ValueEnum};
+use opentelemetry::KeyValue;
+use opentelemetry_http::{Bytes, HttpClient, HttpError, Request, Response};
+use opentelemetry_otlp::{SpanExporterBuilder, OTEL_EXPORTER_OTLP_TIMEOUT_DEFAULT};
+use opentelemetry_sdk::{
+    runtime,
+    trace::{Config, Sampler, TracerProvider},
+    Resource,
+};
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tonic::{metadata::MetadataMap, transport::Channel};
+use tracing::warn;
+
+/// Path of the OTLP/HTTP endpoint receiving spans
+const HTTP_TRACES_PATH: &str = "/v1/traces";
+
+/// Time the collector has to take a batch of spans
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(OTEL_EXPORTER_OTLP_TIMEOUT_DEFAULT);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OtlpProtocol {
+    #[default]
+    Grpc,
+    Http,
+}
+
+/// Where the spans of `ceno run` are exported, tracing is off unless one
+/// of the exporters is chosen
+#[derive(Debug, Args)]
+pub struct TraceOpts {
+    #[arg(long, default_value_t = false, help = "Export traces over OTLP")]
+    pub otlp: bool,
+    #[arg(
+        long,
+        value_name = "URL",
+        help = "OTLP collector endpoint, /v1/traces is added for http [implies --otlp]"
+    )]
+    pub otlp_endpoint: Option<String>,
+    #[arg(long, value_enum, default_value_t, help = "OTLP protocol")]
+    pub otlp_protocol: OtlpProtocol,
+    #[arg(
+        long = "otlp-header",
+        value_name = "KEY=VALUE",
+        value_parser = parse_header,
+        help = "Header sent to the OTLP collector, may be repeated"
+    )]
+    pub otlp_headers: Vec<(String, String)>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Print spans to stdout as JSON lines"
+    )]
+    pub trace_stdout: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Append spans to a file as JSON lines"
+    )]
+    pub trace_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "RATIO",
+        default_value_t = 1.0,
+        value_parser = parse_ratio,
+        help = "Share of the traces started by ceno which are sampled, from 0 to 1"
+    )]
+    pub trace_sample_ratio: f64,
+    #[arg(
+        long,
+        default_value = "ceno",
+        help = "service.name of the exported spans"
+    )]
+    pub service_name: String,
+}
+
+/// The same as the defaults of the flags
+impl Default for TraceOpts {
+    fn default() -> Self {
+        Self {
+            otlp: false,
+            otlp_endpoint: None,
+            otlp_protocol: OtlpProtocol::default(),
+            otlp_headers: Vec::new(),
+            trace_stdout: false,
+            trace_file: None,
+            trace_sample_ratio: 1.0,
+            service_name: "ceno".to_string(),
+        }
+    }
+}
+
+/// Flushes and stops the exporters when dropped
+pub struct TracingGuard(TracerProvider);
+
+impl TraceOpts {
+    fn otlp_enabled(&self) -> bool {
+        self.otlp || self.otlp_endpoint.is_some() || !self.otlp_headers.is_empty()
+    }
+
+    /// The provider of the tracer exporting spans, `None` if tracing is off
+    pub fn provider(&self) -> Result<Option<TracerProvider>> {
+        if !self.otlp_enabled() && !self.trace_stdout && self.trace_file.is_none() {
+            return Ok(None);
+        }
+        // traces sent with a sampled `traceparent` are kept either way
+        let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            self.trace_sample_ratio,
+        )));
+        let resource = Resource::new(vec![KeyValue::new(
+            "service.name",
+            self.service_name.clone(),
+        )]);
+        let mut builder = TracerProvider::builder().with_config(
+            Config::default()
+                .with_sampler(sampler)
+                .with_resource(resource),
+        );
+        if self.otlp_enabled() {
+            let exporter = self
+                .otlp_exporter()?
+                .build_span_exporter()
+                .context("create OTLP exporter")?;
+            builder = builder.with_batch_exporter(exporter, runtime::Tokio);
+        }
+        // spans are written one per line as they end, as the stdout exporter
+        // recurses forever when comparing the resources of a batch
+        if self.trace_stdout {
+            builder = builder.with_simple_exporter(opentelemetry_stdout::SpanExporter::default());
+        }
+        if let Some(path) = &self.trace_file {
+            let exporter = opentelemetry_stdout::SpanExporter::builder()
+                .with_writer(open(path)?)
+                .build();
+            builder = builder.with_simple_exporter(exporter);
+        }
+        Ok(Some(builder.build()))
+    }
+
+    /// The exporter reads the endpoint from the environment before the one
+    /// it's given, so a channel or client of our own makes the flag win
+    fn otlp_exporter(&self) -> Result<SpanExporterBuilder> {
+        let exporter = match self.otlp_protocol {
+            OtlpProtocol::Grpc => {
+                let mut headers = HeaderMap::new();
+                for (key, value) in &self.otlp_headers {
+                    headers.insert(HeaderName::try_from(key)?, HeaderValue::try_from(value)?);
+                }
+                let mut exporter = opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_metadata(MetadataMap::from_headers(headers));
+                if let Some(endpoint) = &self.otlp_endpoint {
+                    let channel = Channel::from_shared(endpoint.clone())
+                        .with_context(|| format!("invalid OTLP endpoint {endpoint}"))?
+                        .timeout(EXPORT_TIMEOUT)
+                        .connect_lazy();
+                    exporter = exporter.with_channel(channel);
+                }
+                exporter.into()
+            }
+            OtlpProtocol::Http => {
+                let headers: HashMap<_, _> = self.otlp_headers.iter().cloned().collect();
+                let mut exporter = opentelemetry_otlp::new_exporter()
+                    .http()
+                    .with_headers(headers);
+                if let Some(endpoint) = &self.otlp_endpoint {
+                    exporter = exporter.with_http_client(FixedUrl {
+                        client: reqwest::Client::new(),
+                        url: http_traces_url(endpoint)?.parse()?,
+                    });
+                }
+                exporter.into()
+            }
+        };
+        Ok(exporter)
+    }
+}
+
+/// Sends the requests of the exporter to `url`, whatever it was built with
+#[derive(Debug)]
+struct FixedUrl<C> {
+    client: C,
+    url: Uri,
+}
+
+#[async_trait]
+impl<C: HttpClient> HttpClient for FixedUrl<C> {
+    async fn send(&self, mut request: Request<Vec<u8>>) -> Result<Response<Bytes>, HttpError> {
+        *request.uri_mut() = self.url.clone();
+        self.client.send(request).await
+    }
+}
+
+impl TracingGuard {
+    pub fn new(provider: TracerProvider) -> Self {
+        Self(provider)
+    }
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            warn!("spans not exported on shutdown: {e}");
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<fs::File> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open trace file {}", path.display()))
+}
+
+/// The endpoint is taken as is if it has a path, as the exporter expects
+/// the full url of the traces
+fn http_traces_url(endpoint: &str) -> Result<String> {
+    let uri: Uri = endpoint
+        .parse()
+        .with_context(|| format!("invalid OTLP endpoint {endpoint}"))?;
+    if uri.path() != "/" {
+        return Ok(endpoint.to_string());
+    }
+    Ok(format!(
+        "{}{HTTP_TRACES_PATH}",
+        endpoint.trim_end_matches('/')
+    ))
+}
+
+fn parse_header(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected KEY=VALUE, got {s}"))?;
+    Ok((key.trim().to_string(), value.trim().to_string()))
+}
+
+fn parse_ratio(s: &str) -> Result<f64> {
+    let ratio: f64 = s.parse()?;
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(anyhow!("sampling ratio must be between 0 and 1"));
+    }
+    Ok(ratio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{Tracer as _, TracerProvider as _};
+
+    #[test]
+    fn args_should_parse() {
+        assert_eq!(
+            parse_header("x-api-key = secret=1").unwrap(),
+            ("x-api-key".to_string(), "secret=1".to_string())
+        );
+        assert!(parse_header("x-api-key").is_err());
+        assert_eq!(parse_ratio("0.25").unwrap(), 0.25);
+        assert!(parse_ratio("1.5").is_err());
+        assert_eq!(
+            http_traces_url("http://collector:4318/").unwrap(),
+            "http://collector:4318/v1/traces"
+        );
+        assert_eq!(
+            http_traces_url("http://collector:4318/otlp/traces").unwrap(),
+            "http://collector:4318/otlp/traces"
+        );
+    }
+
+    /// Keeps the uri of the last request
+    #[derive(Debug, Default)]
+    struct Recorder(std::sync::Mutex<Option<Uri>>);
+
+    #[async_trait]
+    impl HttpClient for Recorder {
+        async fn send(&self, request: Request<Vec<u8>>) -> Result<Response<Bytes>, HttpError> {
+            *self.0.lock().unwrap() = Some(request.uri().clone());
+            Ok(Response::new(Bytes::new()))
+        }
+    }
+
+    #[tokio::test]
+    async fn otlp_endpoint_should_win_over_the_environment() {
+        let client = FixedUrl {
+            client: Recorder::default(),
+            url: http_traces_url("http://from-flag:4318")
+                .unwrap()
+                .parse()
+                .unwrap(),
+        };
+        // the url the exporter resolved, e.g. from OTEL_EXPORTER_OTLP_ENDPOINT
+        let request = Request::post("http://from-env:4318/v1/traces")
+            .body(Vec::new())
+            .unwrap();
+        client.send(request).await.unwrap();
+        let uri = client.client.0.lock().unwrap().clone().unwrap();
+        assert_eq!(uri, "http://from-flag:4318/v1/traces");
+    }
+
+    #[test]
+    fn default_should_match_the_flags() {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            trace: TraceOpts,
+        }
+        let parsed = <Cli as clap::Parser>::parse_from(["ceno"]).trace;
+        assert_eq!(format!("{parsed:?}"), format!("{:?}", TraceOpts::default()));
+    }
+
+    #[test]
+    fn provider_should_be_off_by_default() {
+        assert!(TraceOpts::default().provider().unwrap().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn trace_file_should_get_spans() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("logs/traces.jsonl");
+        let opts = TraceOpts {
+            trace_file: Some(path.clone()),
+            service_name: "my-app".to_string(),
+            ..Default::default()
+        };
+        let provider = opts.provider()?.unwrap();
+        let tracer = provider.tracer("test");
+        tracer.in_span("hello", |_| tracer.in_span("world", |_| {}));
+        drop(TracingGuard::new(provider));
+
+        let content = fs::read_to_string(path)?;
+        assert_eq!(content.lines().count(), 2, "{content}");
+        assert!(content.contains("\"hello\"") && content.contains("\"world\""));
+        assert!(content.contains("my-app"));
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use crate::{fs_routes::load_config, CmdExector};
+use anyhow::Result;
+use ceno_server::{ProjectConfig, RouteSchemas, SchemaSource};
+use clap::Parser;
+use serde_json::{Map, Value};
+use std::{collections::BTreeMap, fs};
+
+#[derive(Debug, Parser)]
+pub struct TypesOpts {
+    #[arg(
+        short,
+        long,
+        default_value = "schemas.d.ts",
+        help = "File to write the types to"
+    )]
+    pub output: String,
+    #[arg(long, help = "Merge config.<ENV>.yml into the config")]
+    pub env: Option<String>,
+}
+
+impl CmdExector for TypesOpts {
+    async fn execute(self) -> Result<()> {
+        let config = load_config(self.env.as_deref())?;
+        let types = generate_types(&config)?;
+        fs::write(&self.output, types)?;
+        println!("types written to {}", self.output);
+        Ok(())
+    }
+}
+
+/// TypeScript types for the schemas of every route, named after the handler
+/// and the part, e.g. `CreateUserBody`
+pub(crate) fn generate_types(config: &ProjectConfig) -> Result<String> {
+    // sorted by name, so the output is stable
+    let mut types = BTreeMap::new();
+    for route in config.routes.values().flatten() {
+        let (Some(schemas), Some(handler)) = (&route.settings.schema, route.action.handler())
+        else {
+            continue;
+        };
+        let name = pascal_case(handler);
+        for (part, source) in parts(schemas) {
+            let Some(source) = source else {
+                continue;
+            };
+            let ts = schema_to_ts(&source.load()?);
+            types.entry(format!("{name}{part}")).or_insert(ts);
+        }
+    }
+
+    let mut s = String::from("// generated by `ceno types` from the schemas of config.yml\n");
+    for (name, ts) in types {
+        s.push_str(&format!("export type {name} = {ts};\n"));
+    }
+    Ok(s)
+}
+
+fn parts(schemas: &RouteSchemas) -> [(&str, &Option<SchemaSource>); 4] {
+    [
+        ("Query", &schemas.query),
+        ("Params", &schemas.params),
+        ("Body", &schemas.body),
+        ("Response", &schemas.response),
+    ]
+}
+
+/// Convert a JSON Schema to a TypeScript type, keywords which can't be
+/// expressed, such as `$ref` or `pattern`, are left out
+pub(crate) fn schema_to_ts(schema: &Value) -> String {
+    let schema = match schema {
+        Value::Bool(true) => return "unknown".into(),
+        Value::Bool(false) => return "never".into(),
+        Value::Object(schema) => schema,
+        _ => return "unknown".into(),
+    };
+
+    if let Some(value) = schema.get("const") {
+        return value.to_string();
+    }
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        return union(values.iter().map(Value::to_string));
+    }
+    if let Some(Value::Array(schemas)) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+        return union(schemas.iter().map(schema_to_ts));
+    }
+    if let Some(Value::Array(schemas)) = schema.get("allOf") {
+        let types = schemas.iter().map(|s| wrap(schema_to_ts(s)));
+        return types.collect::<Vec<_>>().join(" & ");
+    }
+
+    match schema.get("type") {
+        Some(Value::String(ty)) => type_to_ts(ty, schema),
+        Some(Value::Array(types)) => union(
+            types
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|ty| type_to_ts(ty, schema)),
+        ),
+        _ if schema.contains_key("properties") => type_to_ts("object", schema),
+        _ => "unknown".into(),
+    }
+}
+
+fn type_to_ts(ty: &str, schema: &Map<String, Value>) -> String {
+    match ty {
+        "string" => "string".into(),
+        "number" | "integer" => "number".into(),
+        "boolean" => "boolean".into(),
+        "null" => "null".into(),
+        "array" => match (schema.get("prefixItems"), schema.get("items")) {
+            (Some(Value::Array(items)), _) => {
+                let items = items.iter().map(schema_to_ts).collect::<Vec<_>>();
+                format!("[{}]", items.join(", "))
+            }
+            (_, Some(items)) => format!("{}[]", wrap(schema_to_ts(items))),
+            _ => "unknown[]".into(),
+        },
+        "object" => object_to_ts(schema),
+        _ => "unknown".into(),
+    }
+}
+
+fn object_to_ts(schema: &Map<String, Value>) -> String {
+    let required = schema
+        .get("required")
+        .and_then(Value::as_array)
+        .map(|names| names.iter().filter_map(Value::as_str).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let empty = Map::new();
+    let properties = match schema.get("properties") {
+        Some(Value::Object(properties)) => properties,
+        _ => &empty,
+    };
+    // required names without a schema of their own can hold anything
+    let mut fields = required
+        .iter()
+        .filter(|name| !properties.contains_key(**name))
+        .map(|name| format!("{}: unknown", property_name(name)))
+        .collect::<Vec<_>>();
+    for (name, schema) in properties {
+        let optional = if required.contains(&name.as_str()) {
+            ""
+        } else {
+            "?"
+        };
+        fields.push(format!(
+            "{}{optional}: {}",
+            property_name(name),
+            schema_to_ts(schema)
+        ));
+    }
+    match schema.get("additionalProperties") {
+        Some(Value::Bool(false)) => {}
+        Some(extra @ Value::Object(_)) => {
+            fields.push(format!("[key: string]: {}", schema_to_ts(extra)));
+        }
+        _ if fields.is_empty() => return "Record<string, unknown>".into(),
+        _ => {}
+    }
+    if fields.is_empty() {
+        "{}".into()
+    } else {
+        format!("{{ {} }}", fields.join("; "))
+    }
+}
+
+fn union(types: impl Iterator<Item = String>) -> String {
+    let mut types = types.collect::<Vec<_>>();
+    types.dedup();
+    match types.len() {
+        0 => "never".into(),
+        _ => types.join(" | "),
+    }
+}
+
+/// Parenthesize unions and intersections where they'd bind wrongly
+fn wrap(ts: String) -> String {
+    if ts.contains(" | ") || ts.contains(" & ") {
+        format!("({ts})")
+    } else {
+        ts
+    }
+}
+
+fn property_name(name: &str) -> String {
+    let identifier = name.chars().enumerate().all(|(i, c)| {
+        c == '_' || c == '$' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit())
+    });
+    if identifier && !name.is_empty() {
+        name.to_string()
+    } else {
+        Value::from(name).to_string()
+    }
+}
+
+fn pascal_case(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            std::iter::once(first).chain(chars).collect::<String>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn schema_to_ts_should_work() {
+        let schema = json!({
+            "type": "object",
+            "required": ["id", "tags"],
+            "properties": {
+                "id": { "type": "integer" },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] } },
+                "x-rate": { "type": ["number", "null"] },
+                "meta": { "type": "object", "additionalProperties": { "type": "string" } }
+            }
+        });
+        assert_eq!(
+            schema_to_ts(&schema),
+            r#"{ id: number; meta?: { [key: string]: string }; tags: ("a" | "b")[]; "x-rate"?: number | null }"#
+        );
+        assert_eq!(
+            schema_to_ts(&json!({ "type": "object" })),
+            "Record<string, unknown>"
+        );
+        assert_eq!(
+            schema_to_ts(&json!({ "type": "object", "required": ["ok"] })),
+            "{ ok: unknown }"
+        );
+        assert_eq!(schema_to_ts(&json!(true)), "unknown");
+    }
+
+    #[test]
+    fn generate_types_should_name_by_handler() {
+        let config: ProjectConfig = serde_yaml::from_str(
+            r#"
+name: test
+routes:
+  /users/{id:int}:
+    - method: PUT
+      handler: update_user
+      schema:
+        body: { type: object, properties: { name: { type: string } } }
+        response: { type: boolean }
+"#,
+        )
+        .unwrap();
+        let types = generate_types(&config).unwrap();
+        assert!(types.contains("export type UpdateUserBody = { name?: string };\n"));
+        assert!(types.contains("export type UpdateUserResponse = boolean;\n"));
+    }
+}
@@ -0,0 +1,276 @@
+use crate::utils::get_files_with_exts;
+use anyhow::{anyhow, bail, Context as _, Result};
+use bundler::module_exports;
+use ceno_server::{ProjectConfig, ProjectRoute};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    path::{Component, Path, PathBuf},
+};
+
+/// Directory of the handlers routed by their file path
+pub(crate) const ROUTES_DIR: &str = "routes";
+
+/// Exports of a route file answering a method, `ANY` answers the others
+const METHODS: [&str; 8] = [
+    "GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS", "ANY",
+];
+
+/// A method exported by a file under `routes/`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FsRoute {
+    /// relative to the project, e.g. `routes/users/[id].ts`
+    pub file: String,
+    /// e.g. `/users/{id}`
+    pub path: String,
+    pub method: String,
+    /// name the entry exports the method as
+    pub handler: String,
+}
+
+/// Discover the routes of the files under `routes/` in `dir`. `index` files
+/// route their directory, `[id]` is a param and `[...rest]` the rest of the
+/// path. Files and directories starting with `_` are left out, so helpers can
+/// live next to the routes.
+pub(crate) fn discover(dir: &str) -> Result<Vec<FsRoute>> {
+    // glob leaves out `.`, so it can't be stripped from the files otherwise
+    let root = Path::new(dir)
+        .join(ROUTES_DIR)
+        .components()
+        .filter(|c| c != &Component::CurDir)
+        .collect::<PathBuf>();
+    let mut routes = Vec::new();
+    let mut paths = HashMap::<String, String>::new();
+    let mut handlers = HashMap::<String, String>::new();
+
+    for file in get_files_with_exts(&root.display().to_string(), &["ts", "js"])? {
+        let relative = file.strip_prefix(&root)?;
+        let mut names = relative
+            .iter()
+            .map(|name| {
+                name.to_str()
+                    .ok_or_else(|| anyhow!("invalid file name {relative:?}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let name = format!("{ROUTES_DIR}/{}", names.join("/"));
+        if name.ends_with(".d.ts") || names.iter().any(|name| name.starts_with('_')) {
+            continue;
+        }
+
+        // the extension is left out of the route
+        let last = names.len() - 1;
+        names[last] = names[last]
+            .rsplit_once('.')
+            .map_or(names[last], |(stem, _)| stem);
+        if names[last] == "index" {
+            names.pop();
+        }
+        let path = route_path(&names).with_context(|| format!("invalid route file {name}"))?;
+        if let Some(other) = paths.insert(path.clone(), name.clone()) {
+            bail!("{other} and {name} both route {path}");
+        }
+
+        let exports = module_exports(&file.display().to_string())?;
+        let methods = exports
+            .iter()
+            .filter(|export| METHODS.contains(&export.as_str()))
+            .collect::<Vec<_>>();
+        if methods.is_empty() {
+            bail!(
+                "{name} exports none of {}, prefix its name with `_` if it isn't a route",
+                METHODS.join(", ")
+            );
+        }
+        for method in methods {
+            let handler = handler_name(&names, method);
+            if let Some(other) = handlers.insert(handler.clone(), name.clone()) {
+                bail!("{other} and {name} both export {method} as {handler}, rename one of them");
+            }
+            routes.push(FsRoute {
+                file: name.clone(),
+                path: path.clone(),
+                method: method.clone(),
+                handler,
+            });
+        }
+    }
+    Ok(routes)
+}
+
+fn route_path(names: &[&str]) -> Result<String> {
+    let segments = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let Some(param) = name.strip_prefix('[').and_then(|s| s.strip_suffix(']')) else {
+                if name.contains(['[', ']', '{', '}']) {
+                    bail!("a param must take a whole segment");
+                }
+                return Ok(name.to_string());
+            };
+            match param.strip_prefix("...") {
+                Some(_) if i != names.len() - 1 => bail!("only the last param may be a rest"),
+                Some(rest) => Ok(format!("{{*{rest}}}")),
+                None => Ok(format!("{{{param}}}")),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(format!("/{}", segments.join("/")))
+}
+
+/// `routes/users/[id].ts` exporting `GET` is `routes$users$id$GET`
+fn handler_name(names: &[&str], method: &str) -> String {
+    let mut handler = ROUTES_DIR.to_string();
+    for name in names {
+        let name = name
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+            .collect::<String>();
+        write!(handler, "${name}").unwrap();
+    }
+    write!(handler, "${method}").unwrap();
+    handler
+}
+
+/// Source of the entry bundled in place of `main.ts`, which is written to
+/// the build directory and re-exports the routes under their handler names
+pub(crate) fn entry_source(routes: &[FsRoute], main: bool) -> String {
+    let mut source = String::new();
+    if main {
+        source.push_str("export * from \"../main.ts\";\n");
+    }
+    for routes in routes.chunk_by(|a, b| a.file == b.file) {
+        let names = routes
+            .iter()
+            .map(|route| format!("{} as {}", route.method, route.handler))
+            .collect::<Vec<_>>();
+        writeln!(
+            source,
+            "export {{ {} }} from \"../{}\";",
+            names.join(", "),
+            routes[0].file
+        )
+        .unwrap();
+    }
+    source
+}
+
+/// Add the routes to the ones of `config`, a path may only be routed by one
+/// of them
+pub(crate) fn merge(config: &mut ProjectConfig, routes: &[FsRoute]) -> Result<()> {
+    if let Some(route) = routes.iter().find(|r| config.routes.contains_key(&r.path)) {
+        bail!(
+            "{} routes {}, which the config routes too",
+            route.file,
+            route.path
+        );
+    }
+    for route in routes {
+        let project_route: ProjectRoute =
+            serde_json::from_value(json!({ "method": route.method, "handler": route.handler }))?;
+        config
+            .routes
+            .entry(route.path.clone())
+            .or_default()
+            .push(project_route);
+    }
+    Ok(())
+}
+
+/// The config of the project in the current directory, merged with the
+/// overlay of `env`, along with the routes of `routes/` if enabled
+pub(crate) fn load_config(env: Option<&str>) -> Result<ProjectConfig> {
+    let mut config = ProjectConfig::load_env(ProjectConfig::find(".")?, env)?;
+    if config.fs_routes {
+        merge(&mut config, &discover(".")?)?;
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn project(files: &[(&str, &str)]) -> Result<tempfile::TempDir> {
+        let dir = tempfile::tempdir()?;
+        for (name, source) in files {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, source)?;
+        }
+        Ok(dir)
+    }
+
+    #[test]
+    fn discover_should_route_files() -> Result<()> {
+        let dir = project(&[
+            ("routes/index.ts", "export function GET() {}"),
+            (
+                "routes/api/users/[id].ts",
+                "export function GET() {}\nexport const POST = () => {};\nexport const x = 1;",
+            ),
+            ("routes/files/[...path].js", "export function ANY() {}"),
+            ("routes/_lib.ts", "export const helper = 1;"),
+        ])?;
+        let routes = discover(&dir.path().display().to_string())?;
+        let routes = routes
+            .iter()
+            .map(|r| (r.path.as_str(), r.method.as_str(), r.handler.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            routes,
+            [
+                ("/api/users/{id}", "GET", "routes$api$users$id$GET"),
+                ("/api/users/{id}", "POST", "routes$api$users$id$POST"),
+                ("/files/{*path}", "ANY", "routes$files$path$ANY"),
+                ("/", "GET", "routes$GET"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn discover_should_report_conflicts() -> Result<()> {
+        let dir = project(&[
+            ("routes/users.ts", "export function GET() {}"),
+            ("routes/users/index.ts", "export function GET() {}"),
+        ])?;
+        let err = discover(&dir.path().display().to_string()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "routes/users/index.ts and routes/users.ts both route /users"
+        );
+
+        let dir = project(&[("routes/users.ts", "export const users = [];")])?;
+        assert!(discover(&dir.path().display().to_string()).is_err());
+
+        let mut config: ProjectConfig = serde_yaml::from_str(
+            "name: test\nroutes:\n  /users:\n    - method: GET\n      handler: users",
+        )?;
+        let route = FsRoute {
+            file: "routes/users.ts".into(),
+            path: "/users".into(),
+            method: "POST".into(),
+            handler: "routes$users$POST".into(),
+        };
+        assert!(merge(&mut config, &[route]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn entry_source_should_reexport_routes() {
+        let route = |method: &str| FsRoute {
+            file: "routes/users/[id].ts".into(),
+            path: "/users/{id}".into(),
+            method: method.into(),
+            handler: format!("routes$users$id${method}"),
+        };
+        assert_eq!(
+            entry_source(&[route("GET"), route("PUT")], true),
+            "export * from \"../main.ts\";\nexport { GET as routes$users$id$GET, PUT as routes$users$id$PUT } from \"../routes/users/[id].ts\";\n"
+        );
+    }
+}
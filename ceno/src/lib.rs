@@ -1,10 +1,13 @@
 mod cli;
+mod fs_routes;
 mod utils;
 
 pub use cli::*;
 use enum_dispatch::enum_dispatch;
 
 pub const BUILD_DIR: &str = ".build";
+/// jobs of `ceno.queue`, kept across builds
+pub const QUEUE_DIR: &str = ".ceno/queue";
 
 #[allow(async_fn_in_trait)]
 #[enum_dispatch]
@@ -1,22 +1,26 @@
 use anyhow::Result;
 use glob::glob;
-use std::{collections::BTreeSet, fs::File, path::PathBuf};
-
-use crate::BUILD_DIR;
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    path::{Component, Path, PathBuf},
+};
 
 /// get all files with certain extension in a directory
 pub(crate) fn get_files_with_exts(dir: &str, exts: &[&str]) -> Result<BTreeSet<PathBuf>> {
-    // glob all targets files and exclude BUILD_DIR
+    // dot files and directories are skipped, such as BUILD_DIR and the jobs
+    // of QUEUE_DIR, which change without the project changing
+    let hidden = |path: &Path| {
+        path.strip_prefix(dir).unwrap_or(path).components().any(
+            |c| matches!(c, Component::Normal(name) if name.to_string_lossy().starts_with('.')),
+        )
+    };
     let mut files = BTreeSet::new();
     for ext in exts {
         let rule = format!("{}/**/*.{}", dir, ext);
         let paths = glob(&rule)?
             .filter_map(|p| p.ok())
-            .filter(|p| {
-                p.parent()
-                    .and_then(|parent| parent.to_str())
-                    .map_or(false, |parent_str| !parent_str.contains(BUILD_DIR))
-            })
+            .filter(|p| !hidden(p))
             .collect::<BTreeSet<PathBuf>>();
         files.extend(paths);
     }
@@ -25,7 +29,7 @@ pub(crate) fn get_files_with_exts(dir: &str, exts: &[&str]) -> Result<BTreeSet<P
 
 /// calculate target files hash via blake3
 pub(crate) fn calc_project_hash(dir: &str) -> Result<String> {
-    calc_hash_for_files(dir, &["ts", "js", "json"], 16)
+    calc_hash_for_files(dir, &["ts", "js", "json", "yml", "yaml", "toml"], 16)
 }
 
 pub(crate) fn calc_hash_for_files(dir: &str, exts: &[&str], len: usize) -> Result<String> {
@@ -64,4 +68,24 @@ mod tests {
         assert_eq!(hash, "af1349b9f5f9");
         Ok(())
     }
+
+    #[test]
+    fn calc_project_hash_should_skip_dot_dirs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = |file: &str| {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            path
+        };
+        std::fs::write(path("main.ts"), "export {}")?;
+        let root = dir.path().to_str().unwrap();
+        let hash = calc_project_hash(root)?;
+
+        std::fs::write(path(".ceno/queue/localhost/pending/1.json"), "{}")?;
+        std::fs::write(path(".build/main.js"), "")?;
+        assert_eq!(calc_project_hash(root)?, hash);
+        std::fs::write(path("routes/users.ts"), "export {}")?;
+        assert_ne!(calc_project_hash(root)?, hash);
+        Ok(())
+    }
 }